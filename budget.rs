@@ -0,0 +1,89 @@
+//! Bound on the output of a process, set by `--max-total-bytes`.
+//!
+//! The display counts the bytes it prints. Once the budget is spent, the
+//! expansions still matching are counted instead of shown, in a single notice:
+//! on a terminal it is redrawn in place with the count, otherwise it is
+//! printed once, and the final count when rustc is done expanding.
+
+use std::cell::Cell;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+static PRINTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Expansions matched since the budget was spent.
+static SUPPRESSED: Mutex<usize> = Mutex::new(0);
+
+/// Adds `bytes` printed by the display.
+pub(crate) fn count(bytes: usize) {
+    PRINTED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Returns whether more than `max` bytes were printed, 0 being unlimited.
+pub(crate) fn exceeded(max: usize) -> bool {
+    max != 0 && PRINTED.load(Ordering::Relaxed) > max
+}
+
+fn notice(suppressed: usize) -> String {
+    format!(
+        "proc-debug: output budget exceeded, suppressing further expansions \
+         ({suppressed} more matched)"
+    )
+}
+
+fn final_notice(suppressed: usize) -> String {
+    format!("proc-debug: output budget exceeded, suppressed {suppressed} expansions in total")
+}
+
+#[test]
+fn test_notice() {
+    assert_eq!(
+        notice(1),
+        "proc-debug: output budget exceeded, suppressing further expansions (1 more matched)"
+    );
+    assert_eq!(
+        final_notice(3),
+        "proc-debug: output budget exceeded, suppressed 3 expansions in total"
+    );
+    assert!(!exceeded(0));
+}
+
+/// Prints the final count when dropped, if armed, for output which is not a
+/// terminal.
+struct FinalCount(Cell<bool>);
+
+impl Drop for FinalCount {
+    fn drop(&mut self) {
+        if !self.0.get() {
+            return;
+        }
+        let suppressed = *SUPPRESSED.lock().unwrap_or_else(|e| e.into_inner());
+        if suppressed > 1 {
+            let _ = writeln!(std::io::stdout(), "{}", final_notice(suppressed));
+        }
+    }
+}
+
+thread_local! {
+    /// Armed by the first suppressed expansion, and dropped when its thread
+    /// ends, which is when rustc is done expanding.
+    static FINAL_COUNT: FinalCount = const { FinalCount(Cell::new(false)) };
+}
+
+/// Counts an expansion which is not shown, and updates the notice.
+pub(crate) fn suppress() {
+    let mut suppressed = SUPPRESSED.lock().unwrap_or_else(|e| e.into_inner());
+    *suppressed += 1;
+    let mut stdout = std::io::stdout().lock();
+    if stdout.is_terminal() {
+        if *suppressed > 1 {
+            let _ = write!(stdout, "\x1b[1A\r");
+        }
+        let _ = writeln!(stdout, "{}\x1b[K", notice(*suppressed));
+    } else if *suppressed == 1 {
+        let _ = writeln!(stdout, "{}", notice(1));
+        // Gone if suppressed while the thread exits
+        let _ = FINAL_COUNT.try_with(|final_count| final_count.0.set(true));
+    }
+}
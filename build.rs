@@ -7,12 +7,17 @@ fn random() -> u64 {
 }
 
 fn main() {
-    if let Ok(_) = std::env::var("PROC_DEBUG_FLAGS") {
+    if std::env::var("PROC_DEBUG_FLAGS").is_ok() {
         // Force to rerun all times, to show print
-        let mut out_file = PathBuf::from(std::env::var("OUT_DIR").unwrap());
-        out_file.push("out.txt");
-        std::fs::write(&out_file, format!("{}", random())).unwrap();
-        println!("cargo::rerun-if-changed={}", out_file.display());
+        if let Some(out_dir) = std::env::var_os("OUT_DIR") {
+            let mut out_file = PathBuf::from(out_dir);
+            out_file.push("out.txt");
+            // Sandboxed builds may deny the write; we only lose the forced rerun.
+            match std::fs::write(&out_file, format!("{}", random())) {
+                Ok(()) => println!("cargo::rerun-if-changed={}", out_file.display()),
+                Err(e) => println!("cargo::warning=proc-debug cannot force rerun: {e}"),
+            }
+        }
     }
     println!("cargo::rerun-if-env-changed=PROC_DEBUG_FLAGS");
 }
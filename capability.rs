@@ -0,0 +1,92 @@
+//! Optional filesystem capabilities.
+//!
+//! Proc-macros may run inside sandboxes (Bazel, Buck, distro builders) which
+//! deny filesystem access. Features writing files go through this module: the
+//! target directory is probed once per process, a single notice is printed
+//! when it is unavailable, and the feature is silently disabled afterwards so
+//! that only the terminal output remains.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+static PROBED: Mutex<Option<HashMap<PathBuf, bool>>> = Mutex::new(None);
+
+fn notice(path: &Path, err: &std::io::Error) {
+    let _ = writeln!(
+        std::io::stderr(),
+        "proc-debug: {} is unavailable ({}), continuing with terminal output only",
+        path.display(),
+        err
+    );
+}
+
+fn probe(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!(".proc-debug-probe-{}", std::process::id()));
+    File::create(&path)?;
+    std::fs::remove_file(&path)
+}
+
+fn with_probed<R>(f: impl FnOnce(&mut HashMap<PathBuf, bool>) -> R) -> R {
+    let mut probed = PROBED.lock().unwrap_or_else(|e| e.into_inner());
+    f(probed.get_or_insert_with(HashMap::new))
+}
+
+#[test]
+fn test_unwritable_dir() {
+    let mut dir = std::env::temp_dir();
+    dir.push(format!("proc-debug-capability-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // A regular file cannot contain entries, even for the superuser.
+    let blocker = dir.join("blocker");
+    std::fs::write(&blocker, "").unwrap();
+    let denied = blocker.join("out");
+    assert!(!writable_dir(&denied));
+    assert!(open_append(&denied.join("log.txt")).is_none());
+    // The result is cached; removing the blocker does not trigger a retry.
+    std::fs::remove_file(&blocker).unwrap();
+    assert!(!writable_dir(&denied));
+    assert!(writable_dir(&dir));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Returns whether files can be created in `dir`, creating it if missing.
+///
+/// The result is cached, so a denied directory is never retried within this
+/// process.
+pub(crate) fn writable_dir(dir: &Path) -> bool {
+    with_probed(|probed| {
+        *probed
+            .entry(dir.to_owned())
+            .or_insert_with(|| match probe(dir) {
+                Ok(()) => true,
+                Err(e) => {
+                    notice(dir, &e);
+                    false
+                }
+            })
+    })
+}
+
+/// Opens `path` for appending, or returns `None` when its directory is not
+/// writable.
+pub(crate) fn open_append(path: &Path) -> Option<File> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if !writable_dir(dir) {
+        return None;
+    }
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            notice(path, &e);
+            with_probed(|probed| probed.insert(dir.to_owned(), false));
+            None
+        }
+    }
+}
@@ -1,9 +1,19 @@
-use anyhow::Result;
-use cargo::core::{compiler, resolver, Package, PackageId, PackageIdSpec, PackageSet, Resolve};
+mod progress;
+mod stats;
+
+use anyhow::{Context, Result};
+use cargo::core::{compiler, resolver, PackageId, PackageIdSpec, PackageSet, Resolve};
 use cargo::ops::WorkspaceResolve;
 use cargo::{CargoResult, GlobalContext};
 use clap::Parser;
+use proc_debug_instrument::{
+    apply_package, backup_path, cfg_enabled, instrument_package, plan_package, restore_package,
+    InstrumentOptions, InstrumentReport, Plan, RestoreOutcome, ANNOTATION,
+};
+use progress::{Phase, Progress, ProgressFormat};
 use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
@@ -89,14 +99,156 @@ struct Arguments {
     #[arg(long)]
     verbose: bool,
 
+    /// print only the outputs, warnings and errors, without the `PKG` lines
+    /// and the progress of cargo
+    #[arg(long, short = 'q')]
+    quiet: bool,
+
+    /// also instrument proc-macros outside the current workspace
+    #[arg(long, conflicts_with = "workspace_only")]
+    include_deps: bool,
+
+    /// instrument only proc-macros in the current workspace (default)
+    #[arg(long)]
+    workspace_only: bool,
+
+    /// instrument proc-macros in the registry cache, modifying shared sources
+    #[arg(long)]
+    allow_registry: bool,
+
+    /// run cargo and rustc of this rustup toolchain, like `+<TOOLCHAIN>`
+    #[arg(long, value_name = "TOOLCHAIN")]
+    toolchain: Option<String>,
+
+    /// fail unless the macro (`crate::fn_name` or `fn_name`) is invoked
+    #[arg(long, value_name = "MACRO")]
+    expect: Vec<String>,
+
+    /// leave the instrumented sources in place after the run, to run cargo
+    /// with PROC_DEBUG_FLAGS directly
+    #[arg(long)]
+    keep: bool,
+
+    /// restore the sources left instrumented by --keep, and exit
+    #[arg(long, conflicts_with = "keep")]
+    restore: bool,
+
+    /// inject this version of the proc-debug library instead of the one
+    /// matching cargo-proc-debug, such as a pre-release
+    #[arg(long, value_name = "X.Y.Z", value_parser = parse_lib_version)]
+    lib_version: Option<String>,
+
+    /// pass PROC_DEBUG_FLAGS only to the rustc runs of the selected packages,
+    /// through a rustc wrapper, and not to build scripts or other crates
+    #[arg(long, visible_alias = "only-consumer")]
+    target_only: bool,
+
+    /// shell command to run after instrumenting, before the build, aborting
+    /// the run if it fails
+    #[arg(long, value_name = "CMD")]
+    before: Option<String>,
+
+    /// shell command to run after the build, before restoring the sources,
+    /// failing the run if it fails
+    #[arg(long, value_name = "CMD")]
+    after: Option<String>,
+
+    /// write the packages and files instrumented, with the number of
+    /// annotations in each, and the PROC_DEBUG_FLAGS to this JSON file
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// report the phases of the run on stderr: human (nothing beyond the usual
+    /// messages) or json (one event per line)
+    #[arg(long, value_name = "FORMAT", default_value = "human")]
+    progress_format: ProgressFormat,
+
     /// keywords to filter debugging proc-macros
     #[arg(value_name = "KEYWORD")]
     keywords: Vec<String>,
+
+    #[command(subcommand)]
+    action: Option<Action>,
+}
+
+#[derive(clap::Subcommand)]
+enum Action {
+    /// check that expansions parse and do not invoke compile_error!, failing
+    /// otherwise (usable as a pre-commit hook)
+    CheckExpansions {
+        /// keywords to filter checked proc-macros
+        #[arg(value_name = "KEYWORD")]
+        keywords: Vec<String>,
+    },
+    /// write the modifications of the instrumented packages, as diffs, to a
+    /// JSON plan without touching any file
+    Plan {
+        /// file to write the plan to
+        #[arg(long, value_name = "PATH")]
+        out: PathBuf,
+    },
+    /// instrument as recorded in a plan, refusing files which changed since
+    Apply {
+        /// plan written by `cargo proc-debug plan`
+        #[arg(long, value_name = "PATH")]
+        plan: PathBuf,
+    },
+    /// compare the expansions of two runs recorded with
+    /// PROC_DEBUG_FLAGS="-a --format json", failing when a macro regressed
+    StatsDiff {
+        /// records of the baseline run
+        baseline: PathBuf,
+        /// records of the run to check
+        current: PathBuf,
+        /// largest increase allowed of the count, bytes or time of a macro
+        #[arg(long, value_name = "PERCENT", default_value_t = 20.0)]
+        fail_threshold_pct: f64,
+        /// macro label allowed to regress
+        #[arg(long, value_name = "MACRO")]
+        ignore: Vec<String>,
+    },
+}
+
+#[test]
+fn test_extend_args() {
+    let args = |argv: &[&str]| {
+        let Subcommand::ProcDebug(args) =
+            Subcommand::parse_from(["cargo", "proc-debug"].iter().chain(argv));
+        let mut command = Command::new("cargo");
+        args.extend_args(&mut command);
+        command
+            .get_args()
+            .map(|a| a.to_str().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(args(&["-p", "app", "-q"]), ["--package", "app", "--quiet"]);
+    assert_eq!(args(&["--lib", "my_macro"]), ["--lib"]);
+}
+
+#[test]
+fn test_get_env() {
+    let args = |argv: &[&str]| {
+        let Subcommand::ProcDebug(args) =
+            Subcommand::parse_from(["cargo", "proc-debug"].iter().chain(argv));
+        args
+    };
+    assert_eq!(args(&[]).get_env(false), "-a");
+    assert_eq!(args(&["--verbose"]).get_env(true), "-v");
+    assert_eq!(
+        args(&["--path", "::my_macros", "my_macro"]).get_env(false),
+        "--path \"::my_macros\" \"my_macro\""
+    );
 }
 
 impl Arguments {
-    fn get_env(&self) -> String {
-        let mut ret = format!("-a");
+    /// Returns the `PROC_DEBUG_FLAGS` of the build. Every macro is shown with
+    /// `-a` unless the keywords, `--path` or the configuration file
+    /// (`config_selects`) select some, which `-a` would override.
+    fn get_env(&self, config_selects: bool) -> String {
+        let mut ret = String::new();
+        if self.path.is_empty() && self.keywords.is_empty() && !config_selects {
+            ret += "-a";
+        }
         for p in &self.path {
             ret += &format!(" --path \"{}\"", p);
         }
@@ -106,7 +258,7 @@ impl Arguments {
         for k in &self.keywords {
             ret += &format!(" \"{}\"", k);
         }
-        ret
+        ret.trim_start().to_owned()
     }
 
     fn extend_args(&self, args: &mut Command) {
@@ -160,6 +312,9 @@ impl Arguments {
             args.arg("--target");
             args.arg(t);
         }
+        if self.quiet {
+            args.arg("--quiet");
+        }
     }
 }
 
@@ -183,12 +338,97 @@ fn find_manifest_path() -> std::io::Result<PathBuf> {
     }
 }
 
+#[test]
+fn test_find_config() {
+    let root = std::env::temp_dir().join(format!("proc-debug-config-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("member")).unwrap();
+    let manifest = root.join("member/Cargo.toml");
+    assert_eq!(find_config(&root, &manifest), None);
+    std::fs::write(root.join("proc-debug.toml"), "").unwrap();
+    // Found in the workspace root from a member, whatever the directory
+    assert_eq!(
+        find_config(Path::new("/nonexistent"), &manifest),
+        Some(root.join("proc-debug.toml"))
+    );
+    std::fs::write(root.join("member/proc-debug.toml"), "").unwrap();
+    assert_eq!(
+        find_config(&root.join("member"), &root.join("Cargo.toml")),
+        Some(root.join("member/proc-debug.toml"))
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_config_selects() {
+    let path = std::env::temp_dir().join(format!("proc-debug-selects-{}.toml", std::process::id()));
+    let selects = |content: &str| {
+        std::fs::write(&path, content).unwrap();
+        config_selects(&path)
+    };
+    assert!(!selects("depth = 2\nnot = \"test\"\n"));
+    assert!(selects("path = [\"serde_derive::Serialize\"]\n"));
+    assert!(selects("queries = \"my_macro\"\n"));
+    assert!(selects("filter_file = \"filters.txt\"\n"));
+    assert!(!selects("queries = []\n"));
+    std::fs::remove_file(&path).unwrap();
+    assert!(!config_selects(&path));
+}
+
+/// Whether the configuration file at `path` selects macros with `path`,
+/// `queries`, `label`, `label-prefix` or `filter-file`. A file which cannot be
+/// read selects none, the build reporting the error.
+fn config_selects(path: &Path) -> bool {
+    let Ok(table) = std::fs::read_to_string(path).map(|content| content.parse::<toml::Table>())
+    else {
+        return false;
+    };
+    table.into_iter().flatten().any(|(key, value)| {
+        ["path", "queries", "label", "label-prefix", "filter-file"]
+            .contains(&key.replace('_', "-").as_str())
+            && !matches!(&value, toml::Value::Array(values) if values.is_empty())
+            && value != toml::Value::Boolean(false)
+    })
+}
+
+/// Finds `proc-debug.toml` in `dir`, or in the directory of `manifest_path`
+/// or one of its ancestors, such as the root of its workspace. The path is
+/// passed on to the build as `PROC_DEBUG_CONFIG`, since rustc runs in other
+/// directories for some crates.
+fn find_config(dir: &Path, manifest_path: &Path) -> Option<PathBuf> {
+    std::iter::once(dir)
+        .chain(manifest_path.parent().into_iter().flat_map(Path::ancestors))
+        .map(|dir| dir.join("proc-debug.toml"))
+        .find(|path| path.is_file())
+}
+
+#[test]
+fn test_parse_lib_version() {
+    assert_eq!(parse_lib_version("0.1.5").as_deref(), Ok("0.1.5"));
+    assert_eq!(parse_lib_version("0.2.0-rc.1").as_deref(), Ok("0.2.0-rc.1"));
+    for invalid in ["v0.1.5", "0.1", "0.1.5/../x", "0.1.5+build", ""] {
+        assert!(parse_lib_version(invalid).is_err(), "{invalid}");
+    }
+}
+
+/// Checks that `--lib-version` is a version such as `0.1.5` or `0.2.0-rc.1`,
+/// which names a release tag and a directory.
+fn parse_lib_version(version: &str) -> std::result::Result<String, String> {
+    let expected = || format!("expected a version like 0.1.5 or 0.2.0-rc.1, found `{version}`");
+    let parsed = semver::Version::parse(version).map_err(|_| expected())?;
+    if !parsed.build.is_empty() {
+        return Err(expected());
+    }
+    Ok(parsed.to_string())
+}
+
 fn ensure_proc_debug_crate(sysroot: &Path, version: &str) -> Result<PathBuf> {
     let url = format!("https://github.com/yasuo-ozu/proc-debug/archive/refs/tags/v{version}.zip");
     let mut path = PathBuf::from(sysroot);
     path.push(format!("proc-debug-{version}"));
     if !path.exists() {
-        let data = reqwest::blocking::get(url)?
+        let data = reqwest::blocking::get(&url)?
+            .error_for_status()
+            .with_context(|| format!("cannot download proc-debug {version} from {url}"))?
             .bytes()?
             .iter()
             .cloned()
@@ -199,24 +439,93 @@ fn ensure_proc_debug_crate(sysroot: &Path, version: &str) -> Result<PathBuf> {
     Ok(path)
 }
 
+#[test]
+fn test_check_package_selection() {
+    let root = std::env::temp_dir().join(format!("proc-debug-virtual-{}", std::process::id()));
+    for name in ["app", "macros"] {
+        std::fs::create_dir_all(root.join(name).join("src")).unwrap();
+        std::fs::write(
+            root.join(name).join("Cargo.toml"),
+            format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n"),
+        )
+        .unwrap();
+        std::fs::write(root.join(name).join("src/lib.rs"), "").unwrap();
+    }
+    std::fs::write(
+        root.join("Cargo.toml"),
+        "[workspace]\nmembers = [\"app\", \"macros\"]\nresolver = \"2\"\n",
+    )
+    .unwrap();
+    let gctx = GlobalContext::default().unwrap();
+    let workspace = cargo::core::Workspace::new(&root.join("Cargo.toml"), &gctx).unwrap();
+    let err = check_package_selection(&workspace, &[]).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "{} is a virtual manifest, select the packages whose macro calls are debugged \
+             with -p/--package, among: app, macros",
+            root.join("Cargo.toml").display()
+        )
+    );
+    assert!(check_package_selection(&workspace, &["app".to_owned()]).is_ok());
+    // A member is a package of its own
+    let workspace = cargo::core::Workspace::new(&root.join("app/Cargo.toml"), &gctx).unwrap();
+    assert!(check_package_selection(&workspace, &[]).is_ok());
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+/// Checks that packages are selected with `packages` when the manifest is
+/// virtual, as it has no package of its own whose macro calls are debugged.
+fn check_package_selection(
+    workspace: &cargo::core::Workspace<'_>,
+    packages: &[String],
+) -> Result<()> {
+    if workspace.is_virtual() && packages.is_empty() {
+        let members = workspace
+            .members()
+            .map(|pkg| pkg.name().to_string())
+            .collect::<Vec<_>>();
+        anyhow::bail!(
+            "{} is a virtual manifest, select the packages whose macro calls are debugged with \
+             -p/--package, among: {}",
+            workspace.root_manifest().display(),
+            members.join(", ")
+        );
+    }
+    Ok(())
+}
+
 fn resolve_workspace<'gctx>(
     args: &Arguments,
     gctx: &'gctx GlobalContext,
-) -> CargoResult<(PathBuf, WorkspaceResolve<'gctx>)> {
+    progress: &Progress,
+) -> CargoResult<(PathBuf, BTreeSet<PackageId>, WorkspaceResolve<'gctx>)> {
     let manifest_path = args
         .manifest_path
         .clone()
         .map(|p| std::path::absolute(p).unwrap())
         .unwrap_or_else(|| find_manifest_path().unwrap());
-    let mut workspace = cargo::core::Workspace::new(&manifest_path, &gctx)?;
+    let mut workspace = cargo::core::Workspace::new(&manifest_path, gctx)?;
+    check_package_selection(&workspace, &args.package)?;
     workspace.set_ignore_lock(true);
     let target_dir = workspace.target_dir().as_path_unlocked().to_owned();
     let mut sysroot = target_dir.clone();
     sysroot.push("proc-debug-root");
-    let lib_path = ensure_proc_debug_crate(&sysroot, env!("CARGO_PKG_VERSION"))?;
+    let downloading = progress.begin(Phase::Downloading);
+    let lib_path = ensure_proc_debug_crate(
+        &sysroot,
+        args.lib_version
+            .as_deref()
+            .unwrap_or(env!("CARGO_PKG_VERSION")),
+    )?;
+    downloading.finish();
     let mut lib_manifest_path = lib_path.clone();
     lib_manifest_path.push("Cargo.toml");
     workspace.load(&lib_manifest_path)?;
+    let members = workspace
+        .members()
+        .map(|pkg| pkg.package_id())
+        .collect::<BTreeSet<_>>();
 
     let kinds = compiler::CompileKind::from_requested_targets(
         gctx,
@@ -233,7 +542,7 @@ fn resolve_workspace<'gctx>(
         .package
         .iter()
         .cloned()
-        .map(|o| cargo::core::PackageIdSpec::new(o))
+        .map(PackageIdSpec::new)
         .chain(Some(PackageIdSpec::new("proc-debug".to_owned())))
         .collect::<Vec<_>>();
     cargo::ops::resolve_ws_with_opts(
@@ -253,7 +562,7 @@ fn resolve_workspace<'gctx>(
         },
         resolver::ForceAllTargets::No,
     )
-    .map(|o| (lib_path, o))
+    .map(|o| (lib_path, members, o))
 }
 
 fn resolve_deps(
@@ -262,11 +571,10 @@ fn resolve_deps(
 ) -> BTreeSet<PackageId> {
     let mut unresolved_deps: BTreeSet<_> = pids.into_iter().collect();
     let mut resolved_deps = BTreeSet::new();
-    while unresolved_deps.len() > 0 {
+    while !unresolved_deps.is_empty() {
         let ret = unresolved_deps
             .iter()
-            .map(|d| resolve.deps(d.clone()).map(|(a, _)| a))
-            .flatten()
+            .flat_map(|d| resolve.deps(*d).map(|(a, _)| a))
             .collect::<BTreeSet<_>>();
         resolved_deps.extend(&unresolved_deps);
         unresolved_deps = ret.difference(&resolved_deps).cloned().collect();
@@ -274,22 +582,127 @@ fn resolve_deps(
     resolved_deps
 }
 
+/// Returns whether the package `name` is selected by one of `proc_filter`,
+/// globs matched against the package name and its crate name, or `true` if
+/// there is none. An invalid glob only matches itself.
+fn matches_proc_filter(name: &str, proc_filter: &[String]) -> bool {
+    let crate_name = name.replace('-', "_");
+    proc_filter.is_empty()
+        || proc_filter.iter().any(|m| match glob::Pattern::new(m) {
+            Ok(pattern) => pattern.matches(name) || pattern.matches(&crate_name),
+            Err(_) => name == m || crate_name == *m,
+        })
+}
+
+#[test]
+fn test_keyword_crates() {
+    let names = ["serde_derive", "thiserror-impl", "my-macros"].map(str::to_owned);
+    let crates = |keywords: &[&str]| {
+        let keywords = keywords.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        keyword_crates(&keywords, &names)
+    };
+    assert_eq!(crates(&["serde_derive"]), ["serde_derive"]);
+    assert_eq!(
+        crates(&["thiserror_impl", "my-macros"]),
+        ["thiserror-impl", "my-macros"]
+    );
+    // A keyword naming no crate may match macros of any
+    assert!(crates(&["serde_derive", "Serialize"]).is_empty());
+    assert!(crates(&["serde"]).is_empty());
+    assert!(crates(&[]).is_empty());
+}
+
+/// Returns the proc-macro crates among `names` named by `keywords`, when each
+/// keyword is the name of one, since only their macros can then match the
+/// keywords. Otherwise, returns nothing, as a keyword may match a macro of any
+/// crate.
+fn keyword_crates(keywords: &[String], names: &[String]) -> Vec<String> {
+    let normalize = |name: &str| name.replace('-', "_");
+    let crates = keywords
+        .iter()
+        .map(|keyword| {
+            names
+                .iter()
+                .find(|name| normalize(name) == normalize(keyword))
+        })
+        .collect::<Option<Vec<_>>>()
+        .unwrap_or_default();
+    crates.into_iter().cloned().collect()
+}
+
+#[test]
+fn test_resolve_all_packages() {
+    let root = std::env::temp_dir().join(format!("proc-debug-filter-{}", std::process::id()));
+    let names = [
+        "serde_derive",
+        "serde-with-macros",
+        "thiserror-impl",
+        "helper",
+    ];
+    for name in names {
+        let dir = root.join(name);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        let lib = if name == "helper" {
+            ""
+        } else {
+            "\n[lib]\nproc-macro = true\n"
+        };
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n{lib}"),
+        )
+        .unwrap();
+        std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+    }
+    let members = names.map(|n| format!("\"{n}\"")).join(", ");
+    std::fs::write(
+        root.join("Cargo.toml"),
+        format!("[workspace]\nmembers = [{members}]\nresolver = \"2\"\n"),
+    )
+    .unwrap();
+    let gctx = GlobalContext::default().unwrap();
+    let workspace = cargo::core::Workspace::new(&root.join("Cargo.toml"), &gctx).unwrap();
+    let (pkg_set, resolve) = cargo::ops::resolve_ws(&workspace).unwrap();
+    pkg_set.get_many(pkg_set.package_ids()).unwrap();
+    let select = |filter: &[&str]| {
+        let filter = filter.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        resolve_all_packages(&pkg_set, &resolve, &filter, None)
+            .iter()
+            .map(|id| id.name().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        select(&[]),
+        ["serde-with-macros", "serde_derive", "thiserror-impl"]
+    );
+    assert_eq!(select(&["serde_*"]), ["serde-with-macros", "serde_derive"]);
+    assert_eq!(select(&["thiserror_impl"]), ["thiserror-impl"]);
+    assert_eq!(
+        select(&["*-impl", "serde_derive"]),
+        ["serde_derive", "thiserror-impl"]
+    );
+    assert!(select(&["helper"]).is_empty());
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+/// Collects proc-macro packages to instrument. When `members` is given, only
+/// packages in it are selected.
 fn resolve_all_packages(
     package_set: &PackageSet,
     resolve: &Resolve,
     proc_filter: &[String],
+    members: Option<&BTreeSet<PackageId>>,
 ) -> Vec<PackageId> {
     let lib_packages = package_set
         .package_ids()
-        .filter(|pid| pid.clone().name() == "proc-debug")
+        .filter(|pid| pid.name() == "proc-debug")
         .collect::<Vec<_>>();
     let lib_package_deps = resolve_deps(lib_packages, resolve);
     let proc_packages = package_set
         .packages()
         .filter(|pkg| matches!(pkg.library(), Some(targ) if targ.proc_macro()))
-        .filter(|pkg| {
-            proc_filter.len() == 0 || proc_filter.iter().any(|m| pkg.name() == m.as_str())
-        })
+        .filter(|pkg| matches_proc_filter(&pkg.name(), proc_filter))
+        .filter(|pkg| members.is_none_or(|m| m.contains(&pkg.package_id())))
         .map(|pkg| pkg.package_id())
         .collect::<BTreeSet<_>>();
     proc_packages
@@ -298,135 +711,826 @@ fn resolve_all_packages(
         .collect::<Vec<_>>()
 }
 
-fn modify_rust_file(content: String) -> Result<String> {
-    let content =
-        comment::rust::strip(content).map_err(|_| anyhow::Error::msg("Cannot remove comment"))?;
-    let mut modified = Vec::new();
-    for line in content.lines() {
-        let line = line.replace(
-            "#[proc_macro]",
-            "#[::proc_debug::proc_debug]\n#[proc_macro]",
-        );
-        let line = line.replace(
-            "#[proc_macro_attribute]",
-            "#[::proc_debug::proc_debug]\n#[proc_macro_attribute]",
-        );
-        let line = line.replace(
-            "#[proc_macro_derive",
-            "#[::proc_debug::proc_debug]\n#[proc_macro_derive",
+#[test]
+fn test_no_package_hints() {
+    let hints = no_package_hints(&["serde_*".to_owned()], false, 1);
+    assert_eq!(hints.len(), 3);
+    assert!(hints[0].contains("`::serde_*`"));
+    assert!(hints[1].contains("--include-deps"));
+    assert!(hints[2].contains("1 package"));
+    let hints = no_package_hints(&[], true, 0);
+    assert_eq!(hints.len(), 1);
+    assert!(hints[0].contains("no dependency"));
+}
+
+/// Explains why no package was instrumented, given the crates of `--path`
+/// beginning with `::`, `--include-deps` and the number of packages skipped
+/// in the registry cache.
+fn no_package_hints(proc_filter: &[String], include_deps: bool, skipped: usize) -> Vec<String> {
+    let mut hints = Vec::new();
+    if !proc_filter.is_empty() {
+        let paths = proc_filter
+            .iter()
+            .map(|m| format!("`::{m}`"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        hints.push(format!(
+            "--path {paths} matched no proc-macro crate; it is a glob on the crate name, as in `--path ::my_macros` or `--path \"::serde_*\"`"
+        ));
+    }
+    if !include_deps {
+        hints.push(
+            "only proc-macros of the workspace are instrumented, use --include-deps for those of dependencies (-p/--package selects the packages whose macro calls are debugged, not the macros)".to_owned(),
         );
-        modified.push(line);
     }
-    Ok(modified.join("\n"))
+    if skipped > 0 {
+        hints.push(format!(
+            "{skipped} package(s) in the registry cache were skipped, use --allow-registry to instrument them"
+        ));
+    }
+    if hints.is_empty() {
+        hints.push("no dependency of the selected packages is a proc-macro crate".to_owned());
+    }
+    hints
+}
+
+#[test]
+fn test_never_invoked() {
+    let inventory = vec!["a::used".to_owned(), "a::unused".to_owned()];
+    let observed = "a::used\na::used\nb::other\n";
+    assert_eq!(never_invoked(&inventory, observed), vec!["a::unused"]);
+}
+
+/// Returns the macros in `inventory` which never appear in `observed`, the
+/// contents of the results file written by the library.
+fn never_invoked<'a>(inventory: &'a [String], observed: &str) -> Vec<&'a str> {
+    let observed = observed.lines().collect::<BTreeSet<_>>();
+    inventory
+        .iter()
+        .map(|m| m.as_str())
+        .filter(|m| !observed.contains(m))
+        .collect()
+}
+
+#[test]
+fn test_is_in_registry() {
+    let home = Path::new("/home/u/.cargo");
+    assert!(is_in_registry(
+        Path::new("/home/u/.cargo/registry/src/index.crates.io-6f17d22bba15001f/serde_derive-1.0.203/Cargo.toml"),
+        home
+    ));
+    assert!(!is_in_registry(
+        Path::new("/home/u/.cargo/git/checkouts/foo/Cargo.toml"),
+        home
+    ));
+    assert!(!is_in_registry(Path::new("/work/macro/Cargo.toml"), home));
+}
+
+/// Returns whether `manifest_path` lies in the registry cache of `cargo_home`,
+/// which is shared by every project on this machine and often read-only.
+fn is_in_registry(manifest_path: &Path, cargo_home: &Path) -> bool {
+    manifest_path.starts_with(cargo_home.join("registry"))
+}
+
+#[test]
+fn test_failed_expansions() {
+    let header = "index\tlabel\tkind\tmodpath\tmacro_name\tdef_file\tdef_line\t\
+        input_tokens\toutput_tokens\tduration_ms\r\n";
+    assert!(failed_expansions("").is_empty());
+    assert!(failed_expansions(header).is_empty());
+    assert!(failed_expansions(&format!("# {{workspace}} = /home/me/app\r\n{header}")).is_empty());
+    let failing =
+        format!("{header}0\tm::my_attr\tattribute\tm\tmy_attr\tsrc/lib.rs\t3\t10\t4\t0.100\r\n");
+    assert_eq!(
+        failed_expansions(&failing),
+        vec!["m::my_attr (src/lib.rs:3)"]
+    );
+}
+
+/// Lists the expansions recorded with `--errors-only --format tsv` as
+/// `label (def_file:def_line)`.
+fn failed_expansions(tsv: &str) -> Vec<String> {
+    tsv.lines()
+        .filter(|line| !line.starts_with("index\t") && !line.starts_with('#'))
+        .filter_map(|line| {
+            let fields = line.trim_end_matches('\r').split('\t').collect::<Vec<_>>();
+            Some(format!(
+                "{} ({}:{})",
+                fields.get(1)?,
+                fields.get(5)?,
+                fields.get(6)?
+            ))
+        })
+        .collect()
+}
+
+#[test]
+fn test_take_toolchain() {
+    let argv = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+    let (toolchain, rest) =
+        take_toolchain(argv(&["cargo-proc-debug", "proc-debug", "+nightly", "-a"]));
+    assert_eq!(toolchain.as_deref(), Some("nightly"));
+    assert_eq!(rest, argv(&["cargo-proc-debug", "proc-debug", "-a"]));
+    let (toolchain, rest) = take_toolchain(argv(&["cargo-proc-debug", "proc-debug", "my_macro"]));
+    assert_eq!(toolchain, None);
+    assert_eq!(rest, argv(&["cargo-proc-debug", "proc-debug", "my_macro"]));
+}
+
+/// Removes a `+<toolchain>` argument right after the subcommand name, which
+/// clap cannot parse, and returns the toolchain.
+fn take_toolchain(mut argv: Vec<OsString>) -> (Option<String>, Vec<OsString>) {
+    let toolchain = argv
+        .get(2)
+        .and_then(|arg| arg.to_str()?.strip_prefix('+'))
+        .map(str::to_owned);
+    if toolchain.is_some() {
+        argv.remove(2);
+    }
+    (toolchain, argv)
+}
+
+#[cfg(unix)]
+#[test]
+fn test_check_toolchain() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = std::env::temp_dir().join(format!("proc-debug-rustup-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let rustup = dir.join("rustup");
+    std::fs::write(&rustup, "#!/bin/sh\n[ \"$3\" = stable ] || exit 1\n").unwrap();
+    std::fs::set_permissions(&rustup, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let rustup = rustup.to_str().unwrap();
+    assert!(check_toolchain(rustup, "stable").is_ok());
+    let err = check_toolchain(rustup, "nightly-2000-01-01").unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("`nightly-2000-01-01` is not installed"));
+    std::fs::remove_dir_all(&dir).unwrap();
 }
 
-fn modify_toml_file(content: String, lib_path: &Path) -> Result<String> {
-    if content.find("proc-debug").is_some() {
-        Ok(content)
+/// Checks with `rustup which` that `toolchain` has a cargo.
+fn check_toolchain(rustup: &str, toolchain: &str) -> Result<()> {
+    let status = Command::new(rustup)
+        .args(["which", "--toolchain", toolchain, "cargo"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(_) => anyhow::bail!(
+            "toolchain `{toolchain}` is not installed (try `rustup toolchain install {toolchain}`)"
+        ),
+        Err(e) => anyhow::bail!("cannot run rustup to select toolchain `{toolchain}`: {e}"),
+    }
+}
+
+#[test]
+fn test_cargo_command() {
+    let command = cargo_command(Some("nightly"));
+    assert_eq!(command.get_program(), "cargo");
+    assert!(command
+        .get_envs()
+        .any(|(k, v)| k == "RUSTUP_TOOLCHAIN" && v == Some("nightly".as_ref())));
+}
+
+/// Returns the command running cargo, through the rustup proxy with
+/// `toolchain` if given.
+fn cargo_command(toolchain: Option<&str>) -> Command {
+    match toolchain {
+        Some(toolchain) => {
+            let mut command = Command::new("cargo");
+            command.env("RUSTUP_TOOLCHAIN", toolchain);
+            command
+        }
+        None => Command::new(std::env::var("CARGO").unwrap_or("cargo".to_owned())),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_run_hook() {
+    let files = [PathBuf::from("/a/lib.rs"), PathBuf::from("/b/Cargo.toml")];
+    let check = "[ \"$PROC_DEBUG_FLAGS\" = '-a -v' ] && \
+                 [ \"$PROC_DEBUG_MODIFIED_FILES\" = \"$(printf '/a/lib.rs\\n/b/Cargo.toml')\" ]";
+    assert!(run_hook("--after", check, "-a -v", &files).is_ok());
+    let err = run_hook("--before", "exit 3", "-a", &files).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "the --before command `exit 3` failed with exit status: 3"
+    );
+}
+
+/// Runs the shell command `hook` of `option`, `--before` or `--after`, with
+/// the `flags` as `PROC_DEBUG_FLAGS` and the modified `files`, one per line,
+/// as `PROC_DEBUG_MODIFIED_FILES`.
+fn run_hook(option: &str, hook: &str, flags: &str, files: &[PathBuf]) -> Result<()> {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.args(["/C", hook]);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.args(["-c", hook]);
+        command
+    };
+    let files = files
+        .iter()
+        .map(|f| f.display().to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let status = command
+        .env("PROC_DEBUG_FLAGS", flags)
+        .env("PROC_DEBUG_MODIFIED_FILES", files)
+        .status()
+        .with_context(|| format!("cannot run the {option} command `{hook}`"))?;
+    if !status.success() {
+        anyhow::bail!("the {option} command `{hook}` failed with {status}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_report_json() {
+    let dir = std::env::temp_dir().join(format!("proc-debug-report-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let lib = dir.join("lib.rs");
+    std::fs::write(
+        &lib,
+        format!("#[{ANNOTATION}]\n#[proc_macro]\nfn a() {{}}\n#[{ANNOTATION}]\n#[proc_macro]\nfn b() {{}}\n"),
+    )
+    .unwrap();
+    let report = InstrumentReport {
+        modified_files: vec![lib.clone(), dir.join("Cargo.toml")],
+        wrapped_macros: vec!["m::a".to_owned(), "m::b".to_owned()],
+        ..Default::default()
+    };
+    let json = report_json(&[("m 0.1.0".to_owned(), dir.clone(), report)], "-a \"a\"");
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "flags": "-a \"a\"",
+            "packages": [{
+                "package": "m 0.1.0",
+                "root": dir,
+                "files": [
+                    { "path": lib, "injection_points": 2 },
+                    { "path": dir.join("Cargo.toml"), "injection_points": 0 },
+                ],
+                "macros": ["m::a", "m::b"],
+            }],
+        })
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Returns the manifest of `--report` for the instrumented `packages`, as
+/// package id, root and report, run with `flags`.
+fn report_json(packages: &[(String, PathBuf, InstrumentReport)], flags: &str) -> serde_json::Value {
+    let packages = packages
+        .iter()
+        .map(|(id, root, report)| {
+            let files = report
+                .modified_files
+                .iter()
+                .map(|path| {
+                    let content = std::fs::read_to_string(path).unwrap_or_default();
+                    serde_json::json!({
+                        "path": path,
+                        "injection_points": content.matches(ANNOTATION).count(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            serde_json::json!({
+                "package": id,
+                "root": root,
+                "files": files,
+                "macros": report.wrapped_macros,
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({ "flags": flags, "packages": packages })
+}
+
+/// Flags which the rustc wrapper of `--target-only` passes on as
+/// `PROC_DEBUG_FLAGS` to the packages in scope.
+const SCOPED_FLAGS: &str = "PROC_DEBUG_SCOPED_FLAGS";
+
+#[test]
+fn test_in_scope() {
+    let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+    let lib = args(&["--crate-name", "my_crate", "--edition=2021", "src/lib.rs"]);
+    assert!(in_scope(&lib, true));
+    assert!(!in_scope(&lib, false));
+    let build = args(&["--crate-name", "build_script_build", "build.rs"]);
+    assert!(!in_scope(&build, true));
+    assert!(!in_scope(&args(&["-vV"]), false));
+}
+
+/// Whether a rustc run with `args` compiles one of the packages selected for
+/// the build, which cargo marks as primary, rather than its build script or
+/// another crate.
+fn in_scope(args: &[OsString], primary: bool) -> bool {
+    let crate_name = args.iter().skip_while(|arg| *arg != "--crate-name").nth(1);
+    primary
+        && !crate_name
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("build_script_"))
+}
+
+/// Runs rustc as the `RUSTC_WORKSPACE_WRAPPER` set by `--target-only`, as
+/// `<rustc> <args>...`, passing `flags` as `PROC_DEBUG_FLAGS` only to the
+/// packages in scope, and exits with its status.
+fn run_as_wrapper(flags: OsString, argv: &[OsString]) -> ! {
+    let (rustc, args) = argv.split_first().unwrap();
+    let mut command = Command::new(rustc);
+    command.args(args).env_remove(SCOPED_FLAGS);
+    if in_scope(args, std::env::var_os("CARGO_PRIMARY_PACKAGE").is_some()) {
+        command.env("PROC_DEBUG_FLAGS", flags);
     } else {
-        Ok(format!(
-            "{content}\n\n[dependencies.proc-debug]\npath = \"{}\"",
-            lib_path.to_str().unwrap()
-        ))
-    }
-}
-
-fn backup_and_modify(
-    path: PathBuf,
-    f: impl FnOnce(String) -> Result<String>,
-) -> Result<Option<PathBuf>> {
-    let fname = path.file_name().unwrap().to_str().unwrap();
-    let mut bak_path = path.clone();
-    bak_path.set_file_name(format!("{fname}.proc-debug-bak"));
-    if bak_path.exists() {
-        return Ok(None);
-    }
-    let content = String::from_utf8(std::fs::read(&path)?)?;
-    let modified = f(content)?;
-    std::fs::rename(&path, &bak_path)?;
-    if let Err(e) = std::fs::write(&path, modified) {
-        std::fs::rename(&bak_path, &path)?;
-        Err(e)?;
-    }
-    Ok(Some(path))
-}
-
-fn unmodify(path: &Path) -> std::io::Result<()> {
-    let fname = path.file_name().unwrap().to_str().unwrap();
-    let mut bak_path = path.to_owned();
-    bak_path.set_file_name(format!("{fname}.proc-debug-bak"));
-    let _ = std::fs::remove_file(path);
-    std::fs::rename(bak_path, path)
-}
-
-fn modify_files_of_package(pkg: &Package, lib_path: &Path) -> Result<Vec<PathBuf>> {
-    let target = pkg.library().unwrap();
-    let mut src_path = target.src_path().path().unwrap().to_owned();
-    if !src_path.is_absolute() {
-        let mut new_path = pkg.manifest_path().to_owned();
-        new_path.pop();
-        new_path.extend(src_path.into_iter());
-        src_path = new_path;
-    }
-    let mut ret = Vec::new();
-    let src_path = src_path.canonicalize()?;
-    ret.extend(backup_and_modify(src_path, |content| {
-        modify_rust_file(content)
-    })?);
-    ret.extend(backup_and_modify(
-        pkg.manifest_path().to_owned(),
-        |content| modify_toml_file(content, lib_path),
-    )?);
-    Ok(ret)
+        command.env_remove("PROC_DEBUG_FLAGS");
+    }
+    match command.status() {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            eprintln!("error: cannot run {}: {e}", Path::new(rustc).display());
+            std::process::exit(1);
+        }
+    }
 }
 
 fn main() {
-    let Subcommand::ProcDebug(args) = Subcommand::parse();
+    if let Some(flags) = std::env::var_os(SCOPED_FLAGS) {
+        let argv = std::env::args_os().skip(1).collect::<Vec<_>>();
+        if argv.first().is_some_and(|arg| arg != "proc-debug") {
+            run_as_wrapper(flags, &argv);
+        }
+    }
+    let (toolchain, argv) = take_toolchain(std::env::args_os().collect());
+    let Subcommand::ProcDebug(mut args) = Subcommand::parse_from(argv);
+    if let Some(toolchain) = toolchain {
+        args.toolchain.get_or_insert(toolchain);
+    }
+    if let Some(toolchain) = &args.toolchain {
+        if let Err(e) = check_toolchain("rustup", toolchain) {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+        // The rustc run to resolve the workspace is selected the same way.
+        std::env::set_var("RUSTUP_TOOLCHAIN", toolchain);
+    }
+    if args.target_only && std::env::var_os("RUSTC_WORKSPACE_WRAPPER").is_some() {
+        eprintln!("error: --target-only cannot be used with RUSTC_WORKSPACE_WRAPPER set");
+        std::process::exit(1);
+    }
+    let (mut check, mut plan_out, mut plan) = (false, None, None);
+    match args.action.take() {
+        Some(Action::CheckExpansions { keywords }) => {
+            args.keywords.extend(keywords);
+            check = true;
+        }
+        Some(Action::Plan { out }) => plan_out = Some(out),
+        Some(Action::StatsDiff {
+            baseline,
+            current,
+            fail_threshold_pct,
+            ignore,
+        }) => match stats::diff(&baseline, &current, fail_threshold_pct, &ignore) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("error: {e:#}");
+                std::process::exit(1);
+            }
+        },
+        Some(Action::Apply { plan: path }) => {
+            let read = std::fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|json| Plan::from_json(&json));
+            match read {
+                Ok(read) => plan = Some(read),
+                Err(e) => {
+                    eprintln!("error: cannot read the plan {}: {e}", path.display());
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => (),
+    }
     if args.version {
         println!("cargo-proc-debug {}", env!("CARGO_PKG_VERSION"));
         return;
     }
-    let context =
+    let progress = Progress::new(args.progress_format);
+    if let Err(code) = run(&args, check, plan_out, plan, &progress) {
+        std::process::exit(code);
+    }
+}
+
+#[test]
+fn test_run_errors() {
+    let recorder = progress::Recorder::default();
+    let progress = Progress::Json(std::cell::RefCell::new(Box::new(recorder.clone())));
+    let run = |argv: &[&str]| {
+        let Subcommand::ProcDebug(args) =
+            Subcommand::parse_from(["cargo", "proc-debug"].iter().chain(argv));
+        run(&args, false, None, None, &progress)
+    };
+    let root = std::env::temp_dir().join(format!("proc-debug-run-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("app/src")).unwrap();
+    std::fs::write(
+        root.join("app/Cargo.toml"),
+        "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+    )
+    .unwrap();
+    std::fs::write(root.join("app/src/lib.rs"), "").unwrap();
+    std::fs::write(
+        root.join("Cargo.toml"),
+        "[workspace]\nmembers = [\"app\"]\nresolver = \"2\"\n",
+    )
+    .unwrap();
+    let manifest = |name: &str| root.join(name).display().to_string();
+    // Each failure ends the phase it interrupted
+    assert_eq!(
+        run(&["--manifest-path", &manifest("missing/Cargo.toml")]),
+        Err(1)
+    );
+    assert_eq!(run(&["--manifest-path", &manifest("Cargo.toml")]), Err(1));
+    std::fs::remove_dir_all(&root).unwrap();
+    assert_eq!(
+        recorder.text(),
+        "{\"event\":\"begin\",\"phase\":\"resolving\"}\n\
+         {\"event\":\"end\",\"ok\":false,\"phase\":\"resolving\"}\n"
+            .repeat(2)
+    );
+}
+
+/// Instruments the packages selected by `args` and runs cargo on them, or
+/// does the `plan` action instead, announcing the phases to `progress`. An
+/// error gives the status to exit with, once its phases ended and the
+/// sources were restored, which exiting from the middle of them would skip.
+fn run(
+    args: &Arguments,
+    check: bool,
+    plan_out: Option<PathBuf>,
+    plan: Option<Plan>,
+    progress: &Progress,
+) -> std::result::Result<(), i32> {
+    let resolving = progress.begin(Phase::Resolving);
+    let mut context =
         cargo::util::context::GlobalContext::default().unwrap_or_else(|e| panic!("{}", e));
+    // Resolve as the build will, following net.offline or CARGO_NET_OFFLINE
+    if let Err(e) = context.configure(0, args.quiet, None, false, false, false, &None, &[], &[]) {
+        eprintln!("error: {e:#}");
+        return Err(1);
+    }
     let (
         lib_path,
+        members,
         WorkspaceResolve {
             targeted_resolve,
             pkg_set,
             ..
         },
-    ) = resolve_workspace(&args, &context).unwrap_or_else(|e| panic!("{}", e));
+    ) = match resolve_workspace(args, &context, progress) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            return Err(1);
+        }
+    };
     let proc_filter = args
         .path
         .iter()
         .filter_map(|s| {
             if s.starts_with("::") {
-                s.split(":").skip(2).next().map(|s| s.to_owned())
+                s.split(":").nth(2).map(|s| s.to_owned())
             } else {
                 None
             }
         })
         .collect::<Vec<_>>();
-    let pkg_ids = resolve_all_packages(&pkg_set, &targeted_resolve, proc_filter.as_slice());
-    struct Guard(Vec<PathBuf>);
-    impl Drop for Guard {
+    // Keywords naming proc-macro crates also narrow down the crates to
+    // instrument, and are still passed on as queries, without `-a`, to filter
+    // their macros at runtime
+    let proc_names = pkg_set
+        .packages()
+        .filter(|pkg| matches!(pkg.library(), Some(targ) if targ.proc_macro()))
+        .map(|pkg| pkg.name().to_string())
+        .collect::<Vec<_>>();
+    let mut crate_filter = proc_filter.clone();
+    crate_filter.extend(keyword_crates(&args.keywords, &proc_names));
+    let pkg_ids = resolve_all_packages(
+        &pkg_set,
+        &targeted_resolve,
+        crate_filter.as_slice(),
+        (!args.include_deps).then_some(&members),
+    );
+    let cargo_home = context.home().as_path_unlocked().to_owned();
+    let mut skipped = 0;
+    let pkg_ids = pkg_ids
+        .into_iter()
+        .filter(|id| {
+            let pkg = pkg_set.get_one(*id).unwrap();
+            if args.allow_registry
+                || !(id.source_id().is_registry()
+                    || is_in_registry(pkg.manifest_path(), &cargo_home))
+            {
+                return true;
+            }
+            eprintln!(
+                "warning: skipping {id}, which lives in the registry cache (use --allow-registry to instrument it)"
+            );
+            skipped += 1;
+            false
+        })
+        .collect::<Vec<_>>();
+    resolving.finish();
+    struct Guard<'a>(Vec<PathBuf>, &'a Progress);
+    impl Drop for Guard<'_> {
         fn drop(&mut self) {
-            for p in &self.0 {
-                let _ = unmodify(p);
+            if self.0.is_empty() {
+                return;
+            }
+            let restoring = self.1.begin(Phase::Restoring);
+            let mut ok = true;
+            for root in &self.0 {
+                match restore_package(root) {
+                    Ok(restored) => {
+                        let files = restored.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+                        self.1
+                            .package("restored", &root.display().to_string(), &files);
+                        for (path, outcome) in restored {
+                            match outcome {
+                                RestoreOutcome::RestoredMissing => eprintln!(
+                                    "warning: {} was missing, restored it from its backup",
+                                    path.display()
+                                ),
+                                RestoreOutcome::Gone => eprintln!(
+                                    "warning: {} no longer exists, nothing to restore",
+                                    root.display()
+                                ),
+                                RestoreOutcome::Edited => {
+                                    eprintln!(
+                                        "error: {} was edited since it was instrumented, so it \
+                                         is not restored: move {} over it to discard the \
+                                         edits, or remove it to keep them",
+                                        path.display(),
+                                        backup_path(&path).display()
+                                    );
+                                    ok = false;
+                                }
+                                _ => (),
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("error: cannot restore {}: {e}", root.display());
+                        ok = false;
+                    }
+                }
+            }
+            if ok {
+                restoring.finish();
             }
         }
     }
-    let mut modified_packages = Guard(Vec::new());
+    if args.restore {
+        let restored = Guard(
+            pkg_ids
+                .iter()
+                .map(|id| pkg_set.get_one(*id).unwrap().root().to_owned())
+                .collect(),
+            progress,
+        );
+        drop(restored);
+        return Ok(());
+    }
+    let opts = InstrumentOptions {
+        proc_debug_path: lib_path.clone(),
+    };
+    if let Some(out) = plan_out {
+        let planning = progress.begin(Phase::Planning);
+        let mut plan = Plan::default();
+        for id in &pkg_ids {
+            let root = pkg_set.get_one(*id).unwrap().root();
+            let package = match plan_package(root, &opts) {
+                Ok(package) => package,
+                Err(e) => {
+                    eprintln!("error: {e:#}");
+                    return Err(1);
+                }
+            };
+            let files = package
+                .files
+                .iter()
+                .map(|f| f.path.clone())
+                .collect::<Vec<_>>();
+            progress.package("planned", &id.to_string(), &files);
+            for warning in &package.warnings {
+                eprintln!("warning: {warning}");
+            }
+            plan.packages.push(package);
+        }
+        if let Err(e) = std::fs::write(&out, plan.to_json()) {
+            eprintln!("error: cannot write the plan {}: {e}", out.display());
+            return Err(1);
+        }
+        planning.finish();
+        let files = plan.packages.iter().map(|p| p.files.len()).sum::<usize>();
+        eprintln!(
+            "planned {files} file(s) to modify in {} package(s), written to {}",
+            plan.packages.len(),
+            out.display()
+        );
+        return Ok(());
+    }
+    let mut modified_packages = Guard(Vec::new(), progress);
+    let mut inventory = Vec::new();
+    let mut modified_files = Vec::new();
+    let mut instrumented = Vec::new();
+    let instrumenting = progress.begin(Phase::Instrumenting);
     for id in &pkg_ids {
-        modified_packages.0.extend(
-            modify_files_of_package(&pkg_set.get_one(id.clone()).unwrap(), lib_path.as_path())
-                .unwrap_or_else(|e| panic!("{}", e)),
+        let root = pkg_set.get_one(*id).unwrap().root();
+        let report = match &plan {
+            Some(plan) => match plan.packages.iter().find(|p| p.root == root) {
+                Some(package) => apply_package(package),
+                None => Err(anyhow::anyhow!(
+                    "the plan has no entry for {id}, make a new plan"
+                )),
+            },
+            None => instrument_package(root, &opts),
+        };
+        let report = match report {
+            Ok(report) => report,
+            Err(e) => {
+                eprintln!("error: {e}");
+                return Err(1);
+            }
+        };
+        if !report.modified_files.is_empty() {
+            modified_packages.0.push(root.to_owned());
+        }
+        modified_files.extend(report.modified_files.iter().cloned());
+        instrumented.push((id.to_string(), root.to_owned(), report.clone()));
+        progress.package("instrumented", &id.to_string(), &report.modified_files);
+        for warning in &report.warnings {
+            eprintln!("warning: {warning}");
+        }
+        if !args.quiet {
+            println!("PKG {id}");
+        }
+        let features = targeted_resolve
+            .features(*id)
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Vec<_>>();
+        let mut compiled_out = Vec::new();
+        for (m, predicate) in &report.cfg_gated {
+            if !args.quiet {
+                println!("GATED {m} on cfg({predicate})");
+            }
+            if cfg_enabled(predicate, &features) == Some(false) {
+                eprintln!(
+                    "warning: {m} is gated on cfg({predicate}), which the features enabled for this build do not satisfy"
+                );
+                compiled_out.push(m);
+            }
+        }
+        inventory.extend(
+            report
+                .wrapped_macros
+                .iter()
+                .filter(|m| !compiled_out.contains(m))
+                .cloned(),
         );
-        println!("PKG {}", &id);
     }
-    let mut command = Command::new(std::env::var("CARGO").unwrap_or("cargo".to_owned()));
+    instrumenting.finish();
+    if pkg_ids.is_empty() {
+        eprintln!("warning: no proc-macro package was instrumented, so no expansion will be shown");
+        for hint in no_package_hints(&proc_filter, args.include_deps, skipped) {
+            eprintln!("  = note: {hint}");
+        }
+    }
+    let results_path = lib_path.with_file_name(format!("results-{}.txt", std::process::id()));
+    let _ = std::fs::remove_file(&results_path);
+    let errors_path = lib_path.with_file_name(format!("errors-{}.tsv", std::process::id()));
+    let _ = std::fs::remove_file(&errors_path);
+    let mut command = cargo_command(args.toolchain.as_deref());
     command.arg("check");
     args.extend_args(&mut command);
-    command.env("PROC_DEBUG_FLAGS", args.get_env());
-    let _ = command.status().unwrap_or_else(|e| panic!("{e}"));
+    command.env("PROC_DEBUG_RESULTS", &results_path);
+    let config = std::env::var_os("PROC_DEBUG_CONFIG")
+        .map(PathBuf::from)
+        .or_else(|| {
+            let manifest_path = args
+                .manifest_path
+                .clone()
+                .map(|p| std::path::absolute(p).unwrap())
+                .unwrap_or_else(|| find_manifest_path().unwrap());
+            let dir = std::env::current_dir().unwrap_or_default();
+            let config = find_config(&dir, &manifest_path)?;
+            command.env("PROC_DEBUG_CONFIG", &config);
+            Some(config)
+        });
+    let config_selects = config.as_deref().is_some_and(config_selects);
+    let mut flags = args.get_env(config_selects);
+    // cargo captures the output of rustc, so the expansions are colored when
+    // this terminal is, unless CARGO_TERM_COLOR says otherwise
+    if std::env::var_os("CARGO_TERM_COLOR").is_none() && std::io::stdout().is_terminal() {
+        flags += " --color always";
+    }
+    if check {
+        // --quiet already passes it on
+        if !args.quiet {
+            command.arg("--quiet");
+        }
+        flags += " --errors-only --format tsv";
+        command.env("PROC_DEBUG_OUT", &errors_path);
+    }
+    if args.target_only {
+        let wrapper = std::env::current_exe().unwrap_or_else(|e| panic!("{e}"));
+        command.env("RUSTC_WORKSPACE_WRAPPER", wrapper);
+        command.env(SCOPED_FLAGS, &flags);
+        command.env_remove("PROC_DEBUG_FLAGS");
+    } else {
+        command.env("PROC_DEBUG_FLAGS", &flags);
+    }
+    if let Some(path) = &args.report {
+        let json = serde_json::to_string_pretty(&report_json(&instrumented, &flags)).unwrap();
+        if let Err(e) = std::fs::write(path, json) {
+            eprintln!("error: cannot write the report {}: {e}", path.display());
+            return Err(1);
+        }
+    }
+    if let Some(hook) = &args.before {
+        if let Err(e) = run_hook("--before", hook, &flags, &modified_files) {
+            eprintln!("error: {e:#}");
+            return Err(1);
+        }
+    }
+    let building = progress.begin(Phase::Building);
+    let status = command.status().unwrap_or_else(|e| panic!("{e}"));
+    if status.success() {
+        building.finish();
+    } else {
+        drop(building);
+    }
+    let after_failed = args.after.as_ref().is_some_and(|hook| {
+        run_hook("--after", hook, &flags, &modified_files)
+            .inspect_err(|e| eprintln!("error: {e:#}"))
+            .is_err()
+    });
+    if args.keep {
+        modified_packages.0.clear();
+        eprintln!("kept the instrumented sources, run cargo with:");
+        eprintln!("  PROC_DEBUG_FLAGS={:?}", args.get_env(config_selects));
+        eprintln!("and restore them with `cargo proc-debug --restore`");
+    }
+    drop(modified_packages);
+    if after_failed {
+        return Err(1);
+    }
+
+    let observed = std::fs::read_to_string(&results_path).unwrap_or_default();
+    let _ = std::fs::remove_file(&results_path);
+    let missing = never_invoked(&inventory, &observed);
+    for m in &missing {
+        println!("never invoked: {m} (instrumented but 0 expansions)");
+    }
+    let errors = std::fs::read_to_string(&errors_path).unwrap_or_default();
+    let _ = std::fs::remove_file(&errors_path);
+    let failed = failed_expansions(&errors);
+    for f in &failed {
+        eprintln!("error: expansion of {f} does not parse or invokes compile_error!");
+    }
+    if !pkg_ids.is_empty() && !args.quiet {
+        let names = pkg_ids
+            .iter()
+            .map(|id| id.name().to_string())
+            .collect::<Vec<_>>();
+        eprintln!(
+            "instrumented {} package(s): {}",
+            names.len(),
+            names.join(", ")
+        );
+    }
+    let unmet = args
+        .expect
+        .iter()
+        .filter(|e| {
+            !observed
+                .lines()
+                .any(|o| o == e.as_str() || o.ends_with(&format!("::{e}")))
+        })
+        .collect::<Vec<_>>();
+    for e in &unmet {
+        eprintln!("error: expected macro `{e}` was never invoked");
+    }
+    if !unmet.is_empty() || !failed.is_empty() {
+        return Err(1);
+    }
+    // A check fails with the build, even when no expansion is to blame
+    if check && !status.success() {
+        return Err(status.code().unwrap_or(1));
+    }
+    Ok(())
 }
@@ -0,0 +1,169 @@
+//! Announcements of the phases of a run, for wrappers of `cargo proc-debug`.
+//!
+//! With `--progress-format json`, each event is written to stderr as a JSON
+//! object on its own line, leaving stdout to the expansions:
+//!
+//! - `{"event":"begin","phase":PHASE}` when a phase begins, where `PHASE` is
+//!   one of `resolving`, `downloading`, `planning`, `instrumenting`,
+//!   `building` and `restoring`. Phases may be nested.
+//! - `{"event":"end","phase":PHASE,"ok":BOOL}` when it ends, with `ok` false
+//!   if it failed. Every `begin` is paired with an `end`, even on errors.
+//! - `{"event":"package","action":ACTION,"package":NAME,"files":[PATH..]}`
+//!   for each package `planned`, `instrumented` or `restored`, with the files
+//!   concerned.
+//!
+//! The default `human` format announces nothing.
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Selected by `--progress-format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum ProgressFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    Resolving,
+    Downloading,
+    Planning,
+    Instrumenting,
+    Building,
+    Restoring,
+}
+
+impl Phase {
+    fn name(&self) -> &'static str {
+        match self {
+            Phase::Resolving => "resolving",
+            Phase::Downloading => "downloading",
+            Phase::Planning => "planning",
+            Phase::Instrumenting => "instrumenting",
+            Phase::Building => "building",
+            Phase::Restoring => "restoring",
+        }
+    }
+}
+
+/// Reporter of the events of a run.
+pub(crate) enum Progress {
+    Human,
+    Json(RefCell<Box<dyn Write>>),
+}
+
+impl Progress {
+    pub(crate) fn new(format: ProgressFormat) -> Self {
+        match format {
+            ProgressFormat::Human => Progress::Human,
+            ProgressFormat::Json => Progress::Json(RefCell::new(Box::new(std::io::stderr()))),
+        }
+    }
+
+    fn emit(&self, event: serde_json::Value) {
+        if let Progress::Json(out) = self {
+            let mut out = out.borrow_mut();
+            let _ = writeln!(out, "{event}");
+            let _ = out.flush();
+        }
+    }
+
+    /// Begins `phase`, which ends when the returned guard is dropped.
+    pub(crate) fn begin(&self, phase: Phase) -> PhaseGuard<'_> {
+        self.emit(serde_json::json!({"event": "begin", "phase": phase.name()}));
+        PhaseGuard {
+            progress: self,
+            phase,
+            ok: false,
+        }
+    }
+
+    /// Announces that `action` was done to `package`, concerning `files`.
+    pub(crate) fn package(&self, action: &str, package: &str, files: &[PathBuf]) {
+        self.emit(serde_json::json!({
+            "event": "package",
+            "action": action,
+            "package": package,
+            "files": files,
+        }));
+    }
+}
+
+/// Running phase, which ends as failed unless [`PhaseGuard::finish`] is
+/// called.
+pub(crate) struct PhaseGuard<'a> {
+    progress: &'a Progress,
+    phase: Phase,
+    ok: bool,
+}
+
+impl PhaseGuard<'_> {
+    /// Ends the phase successfully.
+    pub(crate) fn finish(mut self) {
+        self.ok = true;
+    }
+}
+
+impl Drop for PhaseGuard<'_> {
+    fn drop(&mut self) {
+        self.progress.emit(serde_json::json!({
+            "event": "end",
+            "phase": self.phase.name(),
+            "ok": self.ok,
+        }));
+    }
+}
+
+#[cfg(test)]
+#[derive(Default, Clone)]
+pub(crate) struct Recorder(std::rc::Rc<RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Recorder {
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for Recorder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_progress() {
+    let recorder = Recorder::default();
+    let progress = Progress::Json(RefCell::new(Box::new(recorder.clone())));
+    let resolving = progress.begin(Phase::Resolving);
+    progress.begin(Phase::Downloading).finish();
+    resolving.finish();
+    progress.package("instrumented", "a 0.1.0", &[PathBuf::from("src/lib.rs")]);
+    // A phase interrupted by an error or a panic still ends, as failed.
+    let failed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _building = progress.begin(Phase::Building);
+        panic!("build failed");
+    }));
+    assert!(failed.is_err());
+    drop(progress.begin(Phase::Restoring));
+    assert_eq!(
+        recorder.text(),
+        "{\"event\":\"begin\",\"phase\":\"resolving\"}\n\
+         {\"event\":\"begin\",\"phase\":\"downloading\"}\n\
+         {\"event\":\"end\",\"ok\":true,\"phase\":\"downloading\"}\n\
+         {\"event\":\"end\",\"ok\":true,\"phase\":\"resolving\"}\n\
+         {\"action\":\"instrumented\",\"event\":\"package\",\"files\":[\"src/lib.rs\"],\"package\":\"a 0.1.0\"}\n\
+         {\"event\":\"begin\",\"phase\":\"building\"}\n\
+         {\"event\":\"end\",\"ok\":false,\"phase\":\"building\"}\n\
+         {\"event\":\"begin\",\"phase\":\"restoring\"}\n\
+         {\"event\":\"end\",\"ok\":false,\"phase\":\"restoring\"}\n"
+    );
+}
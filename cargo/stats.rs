@@ -0,0 +1,308 @@
+//! Comparison of the expansions of two runs, for `cargo proc-debug
+//! stats-diff`.
+//!
+//! Each file holds the records written by `PROC_DEBUG_FLAGS="-a --format
+//! json"`, one JSON object per line, as `PROC_DEBUG_OUT` collects them. They
+//! are summed per macro into the number of expansions, the bytes of their
+//! outputs and the time they took.
+//!
+//! A change is measured in percent of the baseline, but of at least a floor per
+//! metric, so that a macro taking 0.1 ms does not fail the comparison by taking
+//! 0.3 ms, and a macro missing from the baseline is measured against the floors
+//! alone.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Baselines below these are taken as these, in the order of [`Totals::metrics`].
+const FLOORS: [f64; 3] = [10.0, 4096.0, 10.0];
+
+const METRICS: [&str; 3] = ["count", "bytes", "time (ms)"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Totals {
+    count: usize,
+    bytes: usize,
+    duration_ms: f64,
+}
+
+impl Totals {
+    fn metrics(&self) -> [f64; 3] {
+        [self.count as f64, self.bytes as f64, self.duration_ms]
+    }
+}
+
+/// Sums the records of a file written by `--format json`, per macro label.
+pub(crate) fn parse(json_lines: &str) -> Result<BTreeMap<String, Totals>> {
+    let mut totals = BTreeMap::<String, Totals>::new();
+    for (n, line) in json_lines.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str::<serde_json::Value>(line)
+            .with_context(|| format!("line {}", n + 1))?;
+        let (Some(label), Some(output), Some(duration_ms)) = (
+            record["label"].as_str(),
+            record["output"].as_str(),
+            record["duration_ms"].as_f64(),
+        ) else {
+            bail!(
+                "line {}: expected a record of `--format json`, with `label`, `output` and `duration_ms`",
+                n + 1
+            );
+        };
+        let entry = totals.entry(label.to_owned()).or_default();
+        entry.count += 1;
+        entry.bytes += output.len();
+        entry.duration_ms += duration_ms;
+    }
+    Ok(totals)
+}
+
+pub(crate) fn read(path: &Path) -> Result<BTreeMap<String, Totals>> {
+    let content = std::fs::read_to_string(path)?;
+    parse(&content).with_context(|| format!("in {}", path.display()))
+}
+
+/// Comparison of a macro between the two runs.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Row {
+    label: String,
+    baseline: Option<Totals>,
+    current: Option<Totals>,
+    /// Change of each metric in percent, see the module documentation
+    changes: [f64; 3],
+    ignored: bool,
+}
+
+impl Row {
+    /// Largest increase in percent, or 0 when nothing increased.
+    fn regression(&self) -> f64 {
+        self.changes.iter().copied().fold(0.0, f64::max)
+    }
+
+    /// Whether the macro regressed beyond `threshold` percent.
+    pub(crate) fn fails(&self, threshold: f64) -> bool {
+        !self.ignored && self.regression() > threshold
+    }
+}
+
+/// Compares the totals of each macro, the largest regressions first.
+pub(crate) fn compare(
+    baseline: &BTreeMap<String, Totals>,
+    current: &BTreeMap<String, Totals>,
+    ignore: &[String],
+) -> Vec<Row> {
+    let mut labels = baseline.keys().chain(current.keys()).collect::<Vec<_>>();
+    labels.sort();
+    labels.dedup();
+    let mut rows = labels
+        .into_iter()
+        .map(|label| {
+            let (old, new) = (baseline.get(label), current.get(label));
+            let (old_metrics, new_metrics) = (
+                old.copied().unwrap_or_default().metrics(),
+                new.copied().unwrap_or_default().metrics(),
+            );
+            let changes = std::array::from_fn(|n| {
+                (new_metrics[n] - old_metrics[n]) / old_metrics[n].max(FLOORS[n]) * 100.0
+            });
+            Row {
+                label: label.clone(),
+                baseline: old.copied(),
+                current: new.copied(),
+                changes,
+                ignored: ignore.contains(label),
+            }
+        })
+        .collect::<Vec<_>>();
+    rows.sort_by(|a, b| {
+        b.regression()
+            .total_cmp(&a.regression())
+            .then_with(|| a.label.cmp(&b.label))
+    });
+    rows
+}
+
+fn cell(old: Option<f64>, new: Option<f64>, change: f64, decimals: usize) -> String {
+    let value = |v: Option<f64>| match v {
+        Some(v) => format!("{v:.decimals$}"),
+        None => "-".to_owned(),
+    };
+    // Not `-0%` for a slight decrease
+    let change = if change.abs() < 0.5 { 0.0 } else { change };
+    format!("{} → {} ({change:+.0}%)", value(old), value(new))
+}
+
+/// Renders `rows` as a table, marking those beyond `threshold` percent.
+pub(crate) fn render(rows: &[Row], threshold: f64) -> Vec<String> {
+    let table = rows
+        .iter()
+        .map(|row| {
+            let mut cells = vec![row.label.clone()];
+            for n in 0..3 {
+                cells.push(cell(
+                    row.baseline.map(|t| t.metrics()[n]),
+                    row.current.map(|t| t.metrics()[n]),
+                    row.changes[n],
+                    if n == 2 { 3 } else { 0 },
+                ));
+            }
+            cells.push(
+                match (row.baseline, row.current) {
+                    (None, _) => "new",
+                    (_, None) => "removed",
+                    _ if row.fails(threshold) => "REGRESSED",
+                    _ if row.ignored && row.regression() > threshold => "ignored",
+                    _ => "",
+                }
+                .to_owned(),
+            );
+            cells
+        })
+        .collect::<Vec<_>>();
+    let header = ["macro", METRICS[0], METRICS[1], METRICS[2], ""].map(str::to_owned);
+    let widths = std::iter::once(&header.to_vec()).chain(&table).fold(
+        vec![0; header.len()],
+        |widths, cells| {
+            widths
+                .iter()
+                .zip(cells)
+                .map(|(width, cell)| (*width).max(cell.chars().count()))
+                .collect()
+        },
+    );
+    std::iter::once(header.to_vec())
+        .chain(table)
+        .map(|cells| {
+            cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell}{}", " ".repeat(width - cell.chars().count())))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_owned()
+        })
+        .collect()
+}
+
+/// Compares the files `baseline` and `current`, printing the table, and
+/// returns whether no macro regressed beyond `threshold` percent.
+pub(crate) fn diff(
+    baseline: &Path,
+    current: &Path,
+    threshold: f64,
+    ignore: &[String],
+) -> Result<bool> {
+    let rows = compare(&read(baseline)?, &read(current)?, ignore);
+    for line in render(&rows, threshold) {
+        println!("{line}");
+    }
+    let failed = rows.iter().filter(|row| row.fails(threshold)).count();
+    if failed > 0 {
+        eprintln!("{failed} macro(s) regressed by more than {threshold}%");
+    }
+    Ok(failed == 0)
+}
+
+#[cfg(test)]
+fn totals(entries: &[(&str, usize, usize, f64)]) -> BTreeMap<String, Totals> {
+    entries
+        .iter()
+        .map(|&(label, count, bytes, duration_ms)| {
+            (
+                label.to_owned(),
+                Totals {
+                    count,
+                    bytes,
+                    duration_ms,
+                },
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse() {
+    let lines = "{\"label\":\"a::m\",\"output\":\"struct A;\",\"duration_ms\":1.5,\"index\":0}\n\
+                 \n\
+                 {\"label\":\"a::m\",\"output\":\"\",\"duration_ms\":0.5}\n\
+                 {\"label\":\"b::n\",\"output\":\"x\",\"duration_ms\":2}\n";
+    assert_eq!(
+        parse(lines).unwrap(),
+        totals(&[("a::m", 2, 9, 2.0), ("b::n", 1, 1, 2.0)])
+    );
+    let err = parse("{\"label\":\"a::m\"}").unwrap_err();
+    assert!(err.to_string().starts_with("line 1: expected a record"));
+    assert_eq!(
+        format!("{:#}", parse("\n{").unwrap_err()).split(':').next(),
+        Some("line 2")
+    );
+}
+
+#[test]
+fn test_compare() {
+    let baseline = totals(&[
+        ("slower", 100, 100_000, 200.0),
+        ("larger", 100, 100_000, 200.0),
+        ("faster", 100, 100_000, 200.0),
+        ("tiny", 1, 10, 0.1),
+        ("removed", 5, 500, 1.0),
+        ("ignored", 100, 100_000, 200.0),
+    ]);
+    let current = totals(&[
+        ("slower", 100, 100_000, 300.0),
+        ("larger", 100, 125_000, 200.0),
+        ("faster", 90, 50_000, 100.0),
+        ("tiny", 3, 300, 0.5),
+        ("new", 50, 100, 1.0),
+        ("ignored", 100, 100_000, 1000.0),
+    ]);
+    let rows = compare(&baseline, &current, &["ignored".to_owned()]);
+    let order = rows
+        .iter()
+        .map(|row| row.label.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        order,
+        ["new", "ignored", "slower", "larger", "tiny", "faster", "removed"]
+    );
+    let row = |label| rows.iter().find(|row| row.label == label).unwrap();
+    assert_eq!(row("slower").changes, [0.0, 0.0, 50.0]);
+    assert_eq!(row("larger").changes, [0.0, 25.0, 0.0]);
+    assert_eq!(row("faster").changes, [-10.0, -50.0, -50.0]);
+    // Against the floors rather than 1 expansion of 10 bytes in 0.1 ms
+    assert!(row("tiny").regression() <= 20.0);
+    // Only the count of a new macro exceeds its floor
+    assert_eq!(row("new").changes[0], 500.0);
+    assert_eq!(row("removed").regression(), 0.0);
+    let failed = rows
+        .iter()
+        .filter(|row| row.fails(20.0))
+        .map(|row| row.label.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(failed, ["new", "slower", "larger"]);
+    assert!(rows.iter().all(|row| !row.fails(500.0)));
+    assert!(compare(&baseline, &baseline, &[])
+        .iter()
+        .all(|row| row.regression() == 0.0));
+}
+
+#[test]
+fn test_render() {
+    let rows = compare(
+        &totals(&[("a::m", 2, 100, 1.0), ("b::gone", 1, 10, 0.5)]),
+        &totals(&[("a::m", 30, 100, 1.0)]),
+        &[],
+    );
+    assert_eq!(
+        render(&rows, 20.0),
+        [
+            "macro    count           bytes            time (ms)",
+            "a::m     2 → 30 (+280%)  100 → 100 (+0%)  1.000 → 1.000 (+0%)  REGRESSED",
+            "b::gone  1 → - (-10%)    10 → - (+0%)     0.500 → - (-5%)      removed",
+        ]
+    );
+}
@@ -0,0 +1,197 @@
+//! Runs `cargo proc-debug` on small workspaces, each with a proc-macro crate
+//! `macros` and a crate `app` calling its macros, built offline.
+#![cfg(unix)]
+
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+/// Writes the workspace `name`, whose `macros` crate has the source `macros`
+/// and `app` crate the source `app`, and returns its root.
+fn fixture(name: &str, macros: &str, app: &str) -> PathBuf {
+    let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_dir_all(&root);
+    for (package, dependencies, source) in [
+        (
+            "macros",
+            "[lib]\nproc-macro = true\n\n[dependencies]\nproc-macro2 = \"1\"\n",
+            macros,
+        ),
+        (
+            "app",
+            "[dependencies]\nmacros = { path = \"../macros\" }\n",
+            app,
+        ),
+    ] {
+        std::fs::create_dir_all(root.join(package).join("src")).unwrap();
+        std::fs::write(
+            root.join(package).join("Cargo.toml"),
+            format!(
+                "[package]\nname = \"{package}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+                 {dependencies}"
+            ),
+        )
+        .unwrap();
+        std::fs::write(root.join(package).join("src/lib.rs"), source).unwrap();
+    }
+    std::fs::write(
+        root.join("Cargo.toml"),
+        "[workspace]\nmembers = [\"macros\", \"app\"]\nresolver = \"2\"\n",
+    )
+    .unwrap();
+    // The versions of this repository are those available offline
+    std::fs::copy(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../Cargo.lock"),
+        root.join("Cargo.lock"),
+    )
+    .unwrap();
+    root
+}
+
+/// Runs `cargo proc-debug -p app` with `args` in `root`, checking that the
+/// sources of `macros` are restored. The fixtures share a target directory, in
+/// which the library injected is this repository rather than a download.
+fn cargo_proc_debug(root: &Path, args: &[&str]) -> Output {
+    let target = Path::new(env!("CARGO_TARGET_TMPDIR")).join("fixtures");
+    let lib = target
+        .join("proc-debug-root")
+        .join(format!("proc-debug-{}", env!("CARGO_PKG_VERSION")));
+    std::fs::create_dir_all(lib.parent().unwrap()).unwrap();
+    let repository = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    match std::os::unix::fs::symlink(repository, &lib) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => panic!("{e}"),
+        _ => {}
+    }
+    let source = std::fs::read_to_string(root.join("macros/src/lib.rs")).unwrap();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_cargo-proc-debug"))
+        .arg("proc-debug")
+        .args(["-p", "app"])
+        .args(args)
+        .current_dir(root)
+        .env("CARGO_TARGET_DIR", &target)
+        .env("CARGO_NET_OFFLINE", "true")
+        .env_remove("PROC_DEBUG_FLAGS")
+        .output()
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(root.join("macros/src/lib.rs")).unwrap(),
+        source
+    );
+    output
+}
+
+const MACROS: &str = "\
+use proc_macro::TokenStream;
+
+#[proc_macro]
+pub fn good(input: TokenStream) -> TokenStream {
+    input
+}
+
+#[proc_macro]
+pub fn bad(_input: TokenStream) -> TokenStream {
+    \"compile_error!(\\\"boom\\\");\".parse().unwrap()
+}
+";
+
+#[test]
+fn check_expansions_pass() {
+    let root = fixture("check-pass", MACROS, "macros::good!(pub fn f() {});\n");
+    let output = cargo_proc_debug(&root, &["--only-consumer", "check-expansions"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(!stderr.contains("error: expansion"), "{stderr}");
+}
+
+#[test]
+fn check_expansions_fail() {
+    let root = fixture(
+        "check-fail",
+        MACROS,
+        "macros::good!(pub fn f() {});\nmacros::bad!();\n",
+    );
+    let output = cargo_proc_debug(&root, &["--only-consumer", "check-expansions"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(
+        stderr.contains(
+            "error: expansion of macros::bad (macros/src/lib.rs:9) does not parse or invokes \
+             compile_error!"
+        ),
+        "{stderr}"
+    );
+    assert!(!stderr.contains("expansion of macros::good"), "{stderr}");
+}
+
+#[test]
+fn check_expansions_build_failure() {
+    // No expansion is to blame, but the check fails with the build
+    let root = fixture(
+        "check-build-failure",
+        MACROS,
+        "macros::good!(pub fn f() -> u8 { \"\" });\n",
+    );
+    let output = cargo_proc_debug(&root, &["check-expansions"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(101), "{stderr}");
+    assert!(!stderr.contains("error: expansion"), "{stderr}");
+}
+
+#[test]
+fn cfg_registered_macros() {
+    // Registered under a cfg_attr or compiled under a cfg, the macros still
+    // build once wrapped
+    let macros = "\
+use proc_macro::TokenStream;
+
+#[cfg_attr(all(), proc_macro)]
+pub fn registered(input: TokenStream) -> TokenStream {
+    input
+}
+
+#[proc_macro]
+#[cfg(all())]
+pub fn kept(input: TokenStream) -> TokenStream {
+    input
+}
+
+#[proc_macro]
+#[cfg(any())]
+pub fn removed(input: TokenStream) -> TokenStream {
+    input
+}
+";
+    let root = fixture(
+        "cfg-registered",
+        macros,
+        "macros::registered!(pub fn f() {});\nmacros::kept!(pub fn g() {});\n",
+    );
+    let output = cargo_proc_debug(&root, &["check-expansions"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(
+        stderr.contains("instrumented 1 package(s): macros"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn never_invoked_and_expect() {
+    let root = fixture("never-invoked", MACROS, "macros::good!(pub fn f() {});\n");
+    let output = cargo_proc_debug(&root, &["--expect", "good"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(
+        stdout.contains("never invoked: macros::bad (instrumented but 0 expansions)"),
+        "{stdout}"
+    );
+    assert!(!stdout.contains("never invoked: macros::good"), "{stdout}");
+    let output = cargo_proc_debug(&root, &["--expect", "macros::good", "--expect", "bad"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(
+        stderr.contains("error: expected macro `bad` was never invoked"),
+        "{stderr}"
+    );
+    assert!(!stderr.contains("`macros::good`"), "{stderr}");
+}
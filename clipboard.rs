@@ -0,0 +1,93 @@
+//! Copying of the last expansion shown, for `--copy-last`.
+//!
+//! The output is sent to the clipboard of the terminal with an OSC 52 escape
+//! sequence, which the terminal handles even over SSH, without a clipboard
+//! library. It is written to stdout, or to `/dev/tty` when stdout is
+//! redirected, as under cargo. Terminals cap the size of the sequence, so
+//! larger outputs, and any output when there is no terminal, are written to
+//! `last-expansion.rs` in the dump directory instead.
+
+use crate::capability;
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+
+/// Longest base64 payload sent, below the limit of most terminals.
+const MAX_PAYLOAD: usize = 74_994;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn test_osc52() {
+    assert_eq!(base64(b""), "");
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"fo"), "Zm8=");
+    assert_eq!(base64(b"foo"), "Zm9v");
+    assert_eq!(base64("struct 型;".as_bytes()), "c3RydWN0IOWeizs=");
+    assert_eq!(osc52("foo").unwrap(), "\x1b]52;c;Zm9v\x07");
+    assert!(osc52(&"a".repeat(MAX_PAYLOAD / 4 * 3)).is_some());
+    assert!(osc52(&"a".repeat(MAX_PAYLOAD / 4 * 3 + 1)).is_none());
+}
+
+/// Returns the escape sequence setting the clipboard to `text`, or `None` if
+/// it is too large for terminals.
+fn osc52(text: &str) -> Option<String> {
+    let payload = base64(text.as_bytes());
+    (payload.len() <= MAX_PAYLOAD).then(|| format!("\x1b]52;c;{payload}\x07"))
+}
+
+/// Returns the terminal: stdout if it is one, or else the controlling
+/// terminal, as under cargo, which captures the output of rustc.
+fn terminal() -> Option<Box<dyn Write>> {
+    if std::io::stdout().is_terminal() {
+        return Some(Box::new(std::io::stdout()));
+    }
+    #[cfg(unix)]
+    {
+        let tty = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/tty")
+            .ok()?;
+        Some(Box::new(tty))
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+/// Copies `output` to the clipboard of the terminal, or writes it to
+/// `last-expansion.rs` in `dir` and tells where.
+pub(crate) fn copy(output: &str, dir: &Path) {
+    if let (Some(sequence), Some(mut terminal)) = (osc52(output), terminal()) {
+        let _ = terminal.write_all(sequence.as_bytes());
+        let _ = terminal.flush();
+        return;
+    }
+    if !capability::writable_dir(dir) {
+        return;
+    }
+    let path = dir.join("last-expansion.rs");
+    if std::fs::write(&path, format!("{output}\n")).is_ok() {
+        let _ = writeln!(
+            std::io::stderr(),
+            "proc-debug: wrote the last expansion to {}",
+            path.display()
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Options read from `proc-debug.toml`, in addition to `PROC_DEBUG_FLAGS`.
+//!
+//! The file is found through `PROC_DEBUG_CONFIG`, which `cargo proc-debug`
+//! sets, or else in the directory rustc runs in or one containing the crate
+//! being compiled. Each key is the long name of an option, with `queries` for
+//! the positional arguments:
+//!
+//! ```toml
+//! path = ["serde_derive::Serialize"]
+//! not = "test"
+//! depth = 2
+//! rustfmt = true
+//! ```
+//!
+//! The file only applies when `PROC_DEBUG_FLAGS` is set, and an option given
+//! there replaces the value of the file.
+
+use std::path::{Path, PathBuf};
+
+/// Name of the file looked for in the directories.
+const FILE_NAME: &str = "proc-debug.toml";
+
+/// Options of a configuration file.
+pub(crate) struct Config {
+    pub(crate) path: PathBuf,
+    /// Long name of each option, or `queries`, with its flags.
+    entries: Vec<(String, Vec<String>)>,
+}
+
+impl Config {
+    /// Returns the configuration file of this process, which is read once, or
+    /// `None` if there is none.
+    pub(crate) fn load() -> Option<&'static std::result::Result<Config, String>> {
+        static CONFIG: std::sync::OnceLock<Option<std::result::Result<Config, String>>> =
+            std::sync::OnceLock::new();
+        CONFIG
+            .get_or_init(|| {
+                let path = find()?;
+                let content = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("cannot read {}: {e}", path.display()));
+                Some(content.and_then(|content| Config::parse(path, &content)))
+            })
+            .as_ref()
+    }
+
+    fn parse(path: PathBuf, content: &str) -> std::result::Result<Config, String> {
+        let table = content
+            .parse::<toml::Table>()
+            .map_err(|e| format!("invalid {}: {e}", path.display()))?;
+        let mut entries = Vec::new();
+        for (key, value) in &table {
+            let name = key.replace('_', "-");
+            let flag = format!("--{name}");
+            let values = match value {
+                toml::Value::Array(values) => values.iter().collect(),
+                value => vec![value],
+            };
+            let mut flags = Vec::new();
+            for value in values {
+                let value = match value {
+                    toml::Value::Boolean(true) => None,
+                    toml::Value::Boolean(false) => continue,
+                    toml::Value::String(s) => Some(s.clone()),
+                    toml::Value::Integer(n) => Some(n.to_string()),
+                    _ => {
+                        return Err(format!(
+                        "{}: `{key}` must be a string, an integer, a boolean or an array of them",
+                        path.display()
+                    ))
+                    }
+                };
+                match (name.as_str(), value) {
+                    ("queries", Some(value)) => flags.push(value),
+                    (_, Some(value)) => flags.extend([flag.clone(), value]),
+                    (_, None) => flags.push(flag.clone()),
+                }
+            }
+            entries.push((name, flags));
+        }
+        Ok(Config { path, entries })
+    }
+
+    /// Long names of the options given in the file, with `queries` for the
+    /// positional arguments.
+    pub(crate) fn names(&self) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(_, flags)| !flags.is_empty())
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Combines the options of the file with `flags`, leaving out those given
+    /// in `flags`. The queries come last, as they take every argument after
+    /// them.
+    pub(crate) fn merge(&self, flags: &[String]) -> Vec<String> {
+        let given = crate::meta::given(flags);
+        let kept = self
+            .entries
+            .iter()
+            .filter(|(name, _)| !given.contains(&name.as_str()));
+        let (queries, options): (Vec<_>, Vec<_>) = kept.partition(|(name, _)| name == "queries");
+        options
+            .into_iter()
+            .flat_map(|(_, flags)| flags.iter().cloned())
+            .chain(flags.iter().cloned())
+            .chain(
+                queries
+                    .into_iter()
+                    .flat_map(|(_, flags)| flags.iter().cloned()),
+            )
+            .collect()
+    }
+}
+
+#[test]
+fn test_merge() {
+    let config = Config::parse(
+        PathBuf::from("proc-debug.toml"),
+        "queries = [\"my_macro\"]\npath = [\"a\", \"b\"]\ndepth = 2\nrustfmt = true\n\
+         regex = false\nignore_case = true\n",
+    )
+    .unwrap();
+    assert_eq!(
+        config.names(),
+        ["depth", "ignore-case", "path", "queries", "rustfmt"]
+    );
+    let merge =
+        |flags: &[&str]| config.merge(&flags.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+    assert_eq!(
+        merge(&[]),
+        [
+            "--depth",
+            "2",
+            "--ignore-case",
+            "--path",
+            "a",
+            "--path",
+            "b",
+            "--rustfmt",
+            "my_macro"
+        ]
+    );
+    // PROC_DEBUG_FLAGS takes precedence
+    assert_eq!(
+        merge(&["-d", "3", "-p", "c", "other"]),
+        ["--ignore-case", "--rustfmt", "-d", "3", "-p", "c", "other"]
+    );
+    let err = Config::parse(PathBuf::from("proc-debug.toml"), "depth = 2.5\n").err();
+    assert_eq!(
+        err.as_deref(),
+        Some(
+            "proc-debug.toml: `depth` must be a string, an integer, a boolean or an array of them"
+        )
+    );
+    assert!(Config::parse(PathBuf::from("proc-debug.toml"), "depth = \n").is_err());
+}
+
+/// Finds the configuration file: `PROC_DEBUG_CONFIG`, or `proc-debug.toml` in
+/// the current directory, or in the directory of the crate being compiled or
+/// one of its ancestors, such as the root of its workspace.
+fn find() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("PROC_DEBUG_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let manifest_dir = std::env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from);
+    std::env::current_dir()
+        .ok()
+        .into_iter()
+        .chain(
+            manifest_dir
+                .iter()
+                .flat_map(|dir| dir.ancestors().map(Path::to_owned)),
+        )
+        .map(|dir| dir.join(FILE_NAME))
+        .find(|path| path.is_file())
+}
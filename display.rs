@@ -0,0 +1,1201 @@
+//! Rendering of expansions to the terminal or to any other [`WriteColor`].
+
+use crate::{capability, Entry};
+use quote::ToTokens;
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// How the output of a macro relates to its input item, which only attribute
+/// macros are classified by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Changed,
+    Unchanged,
+    /// The output is empty.
+    Removed,
+    /// The output does not define an item with the name of the input item.
+    Replaced,
+}
+
+impl Outcome {
+    fn note(&self) -> &'static str {
+        match self {
+            Outcome::Changed => "",
+            Outcome::Unchanged => ", unchanged",
+            Outcome::Removed => ", item removed by macro",
+            Outcome::Replaced => ", item replaced (original not re-emitted)",
+        }
+    }
+}
+
+/// Banner colors selected by `--palette`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Palette {
+    Default,
+    HighContrast,
+    /// Bold and underline only, which survives log processors keeping only
+    /// the bold SGR.
+    Mono,
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Self::Default),
+            "high-contrast" => Ok(Self::HighContrast),
+            "mono" => Ok(Self::Mono),
+            _ => Err(format!(
+                "unknown palette `{s}`, expected one of: default, high-contrast, mono"
+            )),
+        }
+    }
+}
+
+/// When to color the output, selected by `--color`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "unknown color mode `{s}`, expected one of: auto, always, never"
+            )),
+        }
+    }
+}
+
+#[test]
+fn test_color_mode() {
+    // Redirected output is plain by default
+    assert!(!ColorMode::Auto.resolve(false, None, false));
+    assert!(ColorMode::Auto.resolve(true, None, false));
+    assert!(!ColorMode::Auto.resolve(true, None, true));
+    assert!(ColorMode::Auto.resolve(false, Some("always"), false));
+    assert!(!ColorMode::Auto.resolve(true, Some("never"), false));
+    assert!(ColorMode::Auto.resolve(true, Some("auto"), false));
+    assert!(ColorMode::Always.resolve(false, Some("never"), true));
+    assert!(!ColorMode::Never.resolve(true, Some("always"), false));
+    assert_eq!(
+        ColorMode::from_flags(&["--color".to_owned(), "never".to_owned()]),
+        ColorMode::Never
+    );
+    assert_eq!(ColorMode::from_flags(&[]), ColorMode::Auto);
+    assert_eq!(
+        ColorMode::from_flags(&["--color=always".to_owned()]),
+        ColorMode::Always
+    );
+}
+
+/// The value of the last `name` in raw flags, given as `name value` or
+/// `name=value`.
+fn flag_value<'a>(flags: &'a [String], name: &str) -> Option<&'a str> {
+    flags.iter().enumerate().rev().find_map(|(i, flag)| {
+        match flag.strip_prefix(name)?.strip_prefix('=') {
+            Some(value) => Some(value),
+            None if flag == name => flags.get(i + 1).map(String::as_str),
+            None => None,
+        }
+    })
+}
+
+impl ColorMode {
+    /// Finds `--color` in raw flags, for messages shown before they are
+    /// parsed successfully.
+    pub(crate) fn from_flags(flags: &[String]) -> Self {
+        flag_value(flags, "--color")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(Self::Auto)
+    }
+
+    /// Whether to color a stream, which is a `terminal` or not.
+    pub(crate) fn enabled(self, terminal: bool) -> bool {
+        self.resolve(
+            terminal,
+            std::env::var("CARGO_TERM_COLOR").ok().as_deref(),
+            std::env::var_os("NO_COLOR").is_some(),
+        )
+    }
+
+    /// With `auto`, `CARGO_TERM_COLOR` set to `always` or `never` decides as
+    /// it does for cargo, and otherwise only a terminal is colored, unless
+    /// `NO_COLOR` is set.
+    fn resolve(self, terminal: bool, cargo_term_color: Option<&str>, no_color: bool) -> bool {
+        match (self, cargo_term_color) {
+            (Self::Always, _) | (Self::Auto, Some("always")) => true,
+            (Self::Never, _) | (Self::Auto, Some("never")) => false,
+            (Self::Auto, _) => terminal && !no_color,
+        }
+    }
+}
+
+impl Palette {
+    /// Finds `--palette` in raw flags, for messages shown before they are
+    /// parsed successfully.
+    pub(crate) fn from_flags(flags: &[String]) -> Self {
+        flag_value(flags, "--palette")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(Self::Default)
+    }
+
+    /// Color of the input and output headers.
+    pub(crate) fn info(&self) -> ColorSpec {
+        let mut spec = ColorSpec::new();
+        match self {
+            Palette::Default => spec.set_bg(Some(Color::Cyan)).set_fg(Some(Color::Black)),
+            Palette::HighContrast => spec
+                .set_bg(Some(Color::Blue))
+                .set_fg(Some(Color::White))
+                .set_intense(true),
+            Palette::Mono => spec.set_underline(true),
+        };
+        spec.set_bold(true);
+        spec
+    }
+
+    /// Color of the output header, which stands out when the item was removed
+    /// or replaced.
+    pub(crate) fn outcome(&self, outcome: Outcome) -> ColorSpec {
+        let bg = match outcome {
+            Outcome::Changed | Outcome::Unchanged => return self.info(),
+            Outcome::Removed => Color::Red,
+            Outcome::Replaced => Color::Magenta,
+        };
+        let mut spec = self.info();
+        match self {
+            Palette::Default => spec.set_bg(Some(bg)).set_fg(Some(Color::White)),
+            Palette::HighContrast => spec.set_bg(Some(bg)),
+            Palette::Mono => spec.set_italic(true),
+        };
+        spec
+    }
+
+    /// Color of warnings about the output.
+    pub(crate) fn warning(&self) -> ColorSpec {
+        let mut spec = ColorSpec::new();
+        match self {
+            Palette::Default => spec.set_fg(Some(Color::Yellow)),
+            Palette::HighContrast => spec.set_fg(Some(Color::Yellow)).set_intense(true),
+            Palette::Mono => &mut spec,
+        };
+        spec.set_bold(true);
+        spec
+    }
+
+    /// Color of errors about the flags.
+    pub(crate) fn error(&self) -> ColorSpec {
+        let mut spec = ColorSpec::new();
+        match self {
+            Palette::Default => spec.set_bg(Some(Color::Yellow)).set_fg(Some(Color::Black)),
+            Palette::HighContrast => spec
+                .set_bg(Some(Color::Red))
+                .set_fg(Some(Color::White))
+                .set_intense(true),
+            Palette::Mono => &mut spec,
+        };
+        spec.set_bold(true);
+        spec
+    }
+}
+
+/// Destination of everything shown for one invocation.
+pub(crate) struct DisplayContext<'a> {
+    out: Box<dyn WriteColor + 'a>,
+    palette: Palette,
+    /// Highlight code with bat, which always writes to the process stdout.
+    highlight: bool,
+    /// Open long outputs in bat's pager.
+    paging: bool,
+    /// Receives a plain copy of everything written to `out`.
+    tee: Option<Box<dyn Write + 'a>>,
+    /// Shorten the headers of an expansion of the same macro as the last one.
+    group: bool,
+    /// Whether the current expansion continues the group of the last one.
+    continued: bool,
+    /// Instrumented macros running this one, with the label of the innermost.
+    nesting: Option<(usize, String)>,
+    /// Shown under the next output header.
+    warning: Option<String>,
+    /// How the next output was parsed, shown in its header.
+    parsed: Option<String>,
+    /// Write the headers in ASCII.
+    ascii: bool,
+    /// Template of the headers, see [`DisplayContext::heading`].
+    heading_format: Option<String>,
+    /// Written before the headers, such as the time of the expansion.
+    timestamp: Option<String>,
+}
+
+/// What expansions nested in another one showed while it ran, held until
+/// the input of the outer one is shown: the bytes for stdout, and the plain
+/// copy for `--tee`.
+#[derive(Default)]
+pub(crate) struct Held {
+    pub(crate) out: Vec<u8>,
+    pub(crate) tee: Vec<u8>,
+}
+
+impl Held {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.out.is_empty() && self.tee.is_empty()
+    }
+
+    pub(crate) fn append(&mut self, mut other: Held) {
+        self.out.append(&mut other.out);
+        self.tee.append(&mut other.tee);
+    }
+}
+
+/// Label and definition site of the last expansion shown with `--group`.
+static LAST_SHOWN: Mutex<Option<String>> = Mutex::new(None);
+
+#[cfg(feature = "pretty")]
+fn print_highlighted(content: &str, paging: bool) -> bool {
+    let mode = if paging {
+        bat::PagingMode::QuitIfOneScreen
+    } else {
+        bat::PagingMode::Never
+    };
+    let _ = bat::PrettyPrinter::new()
+        .input_from_bytes(content.as_bytes())
+        .language("rust")
+        .paging_mode(mode)
+        .print();
+    let _ = writeln!(std::io::stdout());
+    true
+}
+
+/// Highlighting is disabled without the `pretty` feature.
+#[cfg(not(feature = "pretty"))]
+fn print_highlighted(_content: &str, _paging: bool) -> bool {
+    false
+}
+
+/// Returns whether a pager may take over the terminal. It may not when stdout
+/// is redirected, nor when a jobserver is advertised, since cargo then runs
+/// several rustc processes which would fight over the terminal.
+fn can_page() -> bool {
+    std::io::stdout().is_terminal()
+        && ["CARGO_MAKEFLAGS", "MAKEFLAGS"]
+            .iter()
+            .all(|v| std::env::var_os(v).is_none())
+}
+
+impl DisplayContext<'static> {
+    /// Output to stdout, colored as `color` tells, with code highlighted by
+    /// bat when the `pretty` feature is enabled. With `paging`, long outputs
+    /// are paged when [`can_page`] allows it.
+    pub(crate) fn stdout(palette: Palette, color: ColorMode, paging: bool) -> Self {
+        let color = color.enabled(std::io::stdout().is_terminal());
+        let choice = if color {
+            ColorChoice::Always
+        } else {
+            ColorChoice::Never
+        };
+        Self {
+            out: Box::new(StandardStream::stdout(choice)),
+            palette,
+            highlight: color,
+            paging: paging && can_page(),
+            tee: None,
+            group: false,
+            continued: false,
+            nesting: None,
+            warning: None,
+            parsed: None,
+            ascii: false,
+            heading_format: None,
+            timestamp: None,
+        }
+    }
+
+    /// Also appends a plain copy of the output to `path`, if it is writable.
+    pub(crate) fn tee(self, path: Option<&Path>) -> Self {
+        Self {
+            tee: path
+                .and_then(capability::open_append)
+                .map(|f| Box::new(f) as Box<dyn Write>),
+            ..self
+        }
+    }
+}
+
+impl<'a> DisplayContext<'a> {
+    /// Output to `held`, colored for stdout as `color` tells, with a plain
+    /// copy when `tee` is set. Code is not highlighted, since bat writes to
+    /// stdout directly.
+    pub(crate) fn held(palette: Palette, color: ColorMode, tee: bool, held: &'a mut Held) -> Self {
+        let Held { out, tee: copy } = held;
+        let out: Box<dyn WriteColor + 'a> = if color.enabled(std::io::stdout().is_terminal()) {
+            Box::new(termcolor::Ansi::new(out))
+        } else {
+            Box::new(termcolor::NoColor::new(out))
+        };
+        Self {
+            out,
+            palette,
+            highlight: false,
+            paging: false,
+            tee: tee.then(|| Box::new(copy) as Box<dyn Write + 'a>),
+            group: false,
+            continued: false,
+            nesting: None,
+            warning: None,
+            parsed: None,
+            ascii: false,
+            heading_format: None,
+            timestamp: None,
+        }
+    }
+}
+
+impl DisplayContext<'_> {
+    /// Replaces the headers with short continuation lines while the same
+    /// macro is expanded repeatedly.
+    pub(crate) fn group(self, group: bool) -> Self {
+        Self { group, ..self }
+    }
+
+    /// Writes the headers in ASCII, with `>>` instead of `👉`.
+    pub(crate) fn ascii(self, ascii: bool) -> Self {
+        Self { ascii, ..self }
+    }
+
+    /// Formats the headers of inputs and outputs with `format`, see
+    /// [`DisplayContext::heading`].
+    pub(crate) fn heading_format(self, format: Option<&str>) -> Self {
+        Self {
+            heading_format: format.map(str::to_owned),
+            ..self
+        }
+    }
+
+    /// Writes `prefix` before the headers of the expansion.
+    pub(crate) fn timestamp(self, prefix: Option<String>) -> Self {
+        Self {
+            timestamp: prefix,
+            ..self
+        }
+    }
+
+    /// Indents everything by `depth` and notes the `parent` macro in the
+    /// headers, for an expansion run from within another instrumented macro.
+    pub(crate) fn nested(self, depth: usize, parent: Option<&str>) -> Self {
+        Self {
+            nesting: parent.map(|parent| (depth, parent.to_owned())),
+            ..self
+        }
+    }
+}
+
+#[test]
+fn test_helper_attributes() {
+    let item = "#[my_helper(rename_all = \"lower\")] enum A {
+        #[my_helper(skip)] B { #[my_helper] b: u8, #[other] c: u8 },
+        C(#[doc = \"c\"] #[my_helper(flatten)] u8),
+    }";
+    assert_eq!(
+        helper_attributes(item, "other_helper, my_helper"),
+        vec![
+            "#[my_helper (rename_all = \"lower\")] on the item",
+            "#[my_helper (skip)] on variant `B`",
+            "#[my_helper] on field `B::b`",
+            "#[my_helper (flatten)] on field `C::0`",
+        ]
+    );
+}
+
+/// Lists the attributes of the derive input `item` whose name is in the comma
+/// separated `helpers`, with where they are.
+fn helper_attributes(item: &str, helpers: &str) -> Vec<String> {
+    let Ok(item) = syn::parse_str::<syn::DeriveInput>(item) else {
+        return Vec::new();
+    };
+    let helpers = helpers.split(',').map(str::trim).collect::<Vec<_>>();
+    let mut found = Vec::new();
+    let mut push = |attrs: &[syn::Attribute], place: String| {
+        for attr in attrs {
+            if helpers.iter().any(|h| attr.path().is_ident(h)) {
+                let attr = crate::indent_tokens(attr.to_token_stream(), false);
+                found.push(format!("{attr} on {place}"));
+            }
+        }
+    };
+    let field_name = |n: usize, field: &syn::Field| match &field.ident {
+        Some(ident) => ident.to_string(),
+        None => n.to_string(),
+    };
+    push(&item.attrs, "the item".to_owned());
+    match &item.data {
+        syn::Data::Struct(data) => {
+            for (n, field) in data.fields.iter().enumerate() {
+                push(&field.attrs, format!("field `{}`", field_name(n, field)));
+            }
+        }
+        syn::Data::Enum(data) => {
+            for variant in &data.variants {
+                let v = &variant.ident;
+                push(&variant.attrs, format!("variant `{v}`"));
+                for (n, field) in variant.fields.iter().enumerate() {
+                    push(
+                        &field.attrs,
+                        format!("field `{v}::{}`", field_name(n, field)),
+                    );
+                }
+            }
+        }
+        syn::Data::Union(data) => {
+            for (n, field) in data.fields.named.iter().enumerate() {
+                push(&field.attrs, format!("field `{}`", field_name(n, field)));
+            }
+        }
+    }
+    found
+}
+
+/// Returns where `entry` is, as its definition site, also with its call site
+/// when `verbose`.
+fn location(entry: &Entry, verbose: bool) -> String {
+    match &entry.call_site {
+        Some((file, line)) if verbose => format!(
+            "defined at {}:{}, called from {file}:{line}",
+            entry.file, entry.line
+        ),
+        _ => format!("{}:{}", entry.file, entry.line),
+    }
+}
+
+/// Returns the inputs of `entry` as shown in the input section, with each
+/// part passed through `format`.
+fn input_content(entry: &Entry, verbose: bool, format: &dyn Fn(String) -> String) -> String {
+    let &Entry {
+        macro_kind,
+        macro_name,
+        macro_inputs,
+        macro_arg_names,
+        ..
+    } = entry;
+    let input = |n: usize| macro_inputs.get(n).map(String::as_str).unwrap_or_default();
+    let (parts, separator) = match macro_kind {
+        "function" if macro_inputs.len() == 1 => {
+            (vec![format!("{macro_name}!{{{}}}", input(0))], "\n")
+        }
+        "function" => (macro_inputs.to_vec(), "\n"),
+        "attribute" => (
+            vec![
+                format!("#[{}({})]", macro_name, input(0)),
+                input(1).to_owned(),
+            ],
+            "\n",
+        ),
+        "derive" => (
+            vec![format!("#[derive({})]", input(0)), input(1).to_owned()],
+            "\n",
+        ),
+        _ => (macro_inputs.to_vec(), ","),
+    };
+    let parts = parts.into_iter().map(format).collect::<Vec<_>>();
+    let helpers = match macro_kind {
+        "derive" if macro_inputs.len() > 2 => helper_attributes(input(1), input(2)),
+        _ => Vec::new(),
+    };
+    let content = if verbose || (macro_kind == "function" && parts.len() > 1) {
+        parts
+            .into_iter()
+            .enumerate()
+            .map(|(n, part)| match macro_arg_names.get(n) {
+                Some(name) => format!("// {name}:\n{part}"),
+                None => format!("// [{n}]:\n{part}"),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    } else {
+        parts.join(separator)
+    };
+    if helpers.is_empty() {
+        content
+    } else {
+        let helpers = helpers
+            .iter()
+            .map(|h| format!("//   {h}\n"))
+            .collect::<String>();
+        format!("// helper attributes:\n{helpers}{content}")
+    }
+}
+
+/// Returns the width of the terminal, from stdout, the controlling terminal
+/// when cargo captures stdout, or `$COLUMNS`.
+pub(crate) fn terminal_width() -> Option<usize> {
+    let size = terminal_size::terminal_size().or_else(|| {
+        #[cfg(unix)]
+        {
+            let tty = std::fs::File::open("/dev/tty").ok()?;
+            terminal_size::terminal_size_of(&tty)
+        }
+        #[cfg(not(unix))]
+        None
+    });
+    match size {
+        Some((terminal_size::Width(width), _)) => Some(width as usize),
+        None => std::env::var("COLUMNS").ok()?.parse().ok(),
+    }
+}
+
+/// Pads or truncates `line` to `width` columns, ending truncated lines with
+/// `…`.
+fn fit(line: &str, width: usize) -> String {
+    let mut out = String::new();
+    let mut used = 0;
+    let truncated = UnicodeWidthStr::width(line) > width;
+    let limit = if truncated {
+        width.saturating_sub(1)
+    } else {
+        width
+    };
+    for c in line.chars() {
+        let w = UnicodeWidthChar::width(c).unwrap_or(0);
+        if used + w > limit {
+            break;
+        }
+        out.push(c);
+        used += w;
+    }
+    if truncated && width > 0 {
+        out.push('…');
+        used += 1;
+    }
+    out + &" ".repeat(width.saturating_sub(used))
+}
+
+#[test]
+fn test_columns() {
+    assert_eq!(
+        columns(
+            "struct A;\nfn f() {}",
+            "struct A;\nimpl A { fn long_name() {} }\n",
+            29
+        ),
+        "struct A;     │ struct A;\n\
+         fn f() {}     │ impl A { fn …\n\
+         \x20             │"
+    );
+}
+
+/// Lays out `left` and `right` in two columns separated by ` │ `, `width`
+/// columns in total.
+fn columns(left: &str, right: &str, width: usize) -> String {
+    let column = width.saturating_sub(3) / 2;
+    let left = left.split('\n').collect::<Vec<_>>();
+    let right = right.split('\n').collect::<Vec<_>>();
+    (0..left.len().max(right.len()))
+        .map(|n| {
+            let l = fit(left.get(n).copied().unwrap_or_default(), column);
+            let r = fit(right.get(n).copied().unwrap_or_default(), column);
+            format!("{l} │ {r}").trim_end().to_owned()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl<'a> DisplayContext<'a> {
+    /// Output to `out`, with code written as plain text.
+    #[cfg(test)]
+    pub(crate) fn new(out: impl WriteColor + 'a) -> Self {
+        Self {
+            out: Box::new(out),
+            palette: Palette::Default,
+            highlight: false,
+            paging: false,
+            tee: None,
+            group: false,
+            continued: false,
+            nesting: None,
+            warning: None,
+            parsed: None,
+            ascii: false,
+            heading_format: None,
+            timestamp: None,
+        }
+    }
+
+    fn indent(&self) -> String {
+        let depth = self.nesting.as_ref().map_or(0, |(depth, _)| *depth);
+        "    ".repeat(depth)
+    }
+
+    /// Sets how the next output was parsed, such as `parsed as 3 Items`.
+    pub(crate) fn parsed_as(&mut self, parsed: String) {
+        self.parsed = Some(parsed);
+    }
+
+    fn parsed_note(&mut self) -> String {
+        match self.parsed.take() {
+            Some(parsed) => format!(" — {parsed}"),
+            None => String::new(),
+        }
+    }
+
+    fn nested_note(&self) -> String {
+        match &self.nesting {
+            Some((_, parent)) => format!(" (nested in {parent})"),
+            None => String::new(),
+        }
+    }
+
+    /// Formats a header of `what` (`input`, `output` or `input → output`) of
+    /// the macro `name`, with the `notes` following it.
+    ///
+    /// The template of `--heading-format` replaces `{marker}`, `{what}`,
+    /// `{macro}`, `{location}`, `{index}` and `{notes}`.
+    fn heading(
+        &self,
+        what: &str,
+        name: &str,
+        location: &str,
+        index: Option<usize>,
+        notes: &str,
+    ) -> String {
+        let heading = match &self.heading_format {
+            Some(format) => format
+                .replace("{marker}", "👉")
+                .replace("{what}", what)
+                .replace("{macro}", name)
+                .replace("{location}", location)
+                .replace("{index}", &index.map(|i| i.to_string()).unwrap_or_default())
+                .replace("{notes}", notes),
+            None => {
+                let index = index.map(|i| format!(" #{i}")).unwrap_or_default();
+                format!("👉 {what} of {name} ({location}){index}{notes}")
+            }
+        };
+        format!("{}{heading}", self.stamp())
+    }
+
+    fn stamp(&self) -> &str {
+        self.timestamp.as_deref().unwrap_or_default()
+    }
+
+    /// Replaces the symbols of a header with ASCII, if enabled.
+    fn header_text<'s>(&self, text: &'s str) -> std::borrow::Cow<'s, str> {
+        if !self.ascii {
+            return text.into();
+        }
+        [
+            ("👉", ">>"),
+            ("⚠", "!!"),
+            ("→", "->"),
+            ("—", "-"),
+            ("·", "-"),
+        ]
+        .iter()
+        .fold(text.to_owned(), |text, (from, to)| text.replace(from, to))
+        .into()
+    }
+
+    fn banner(&mut self, spec: &ColorSpec, text: &str) {
+        let text = self.header_text(text).into_owned();
+        let indent = self.indent();
+        let _ = write!(self.out, "{indent}");
+        let _ = self.out.set_color(spec);
+        let _ = write!(self.out, "{text}");
+        let _ = self.out.reset();
+        let _ = writeln!(self.out);
+        crate::budget::count(indent.len() + text.len() + 1);
+        self.write_tee(&format!("{indent}{text}\n"));
+    }
+
+    fn line(&mut self, text: &str) {
+        let text = format!("{}{}", self.indent(), self.header_text(text));
+        let _ = writeln!(self.out, "{text}");
+        crate::budget::count(text.len() + 1);
+        self.write_tee(&format!("{text}\n"));
+    }
+
+    fn write_tee(&mut self, text: &str) {
+        if let Some(tee) = &mut self.tee {
+            let _ = tee.write_all(text.as_bytes());
+        }
+    }
+
+    /// Writes what nested expansions showed, already counted in the budget.
+    pub(crate) fn write_held(&mut self, held: &mut Held) {
+        let Held { out, tee } = std::mem::take(held);
+        let _ = self.out.write_all(&out);
+        if let Some(copy) = &mut self.tee {
+            let _ = copy.write_all(&tee);
+        }
+    }
+
+    fn code(&mut self, content: &str, paging: bool) {
+        let indent = self.indent();
+        let content = content
+            .split('\n')
+            .map(|s| format!("{indent}  {}", s))
+            .collect::<Vec<_>>()
+            .join("\n");
+        crate::budget::count(content.len() + 2);
+        self.write_tee(&format!("{content}\n\n"));
+        if self.highlight {
+            let _ = self.out.flush();
+            if print_highlighted(&content, paging) {
+                return;
+            }
+        }
+        let _ = writeln!(self.out, "{content}\n");
+    }
+
+    /// Shows the raw `PROC_DEBUG_FLAGS` with a line for each of the `active`
+    /// filters and options.
+    pub(crate) fn flags_banner(&mut self, flags: &str, active: &[String]) {
+        self.banner(
+            &self.palette.info(),
+            &format!(
+                "👉 proc-debug in process {}: PROC_DEBUG_FLAGS={flags:?}",
+                std::process::id()
+            ),
+        );
+        for line in active {
+            self.line(&format!("  {line}"));
+        }
+        self.line("");
+    }
+
+    /// Tells that none of the first `evaluated` expansions passed the
+    /// `active` filters, with examples of the `rejected` macros.
+    pub(crate) fn no_match_hint(
+        &mut self,
+        evaluated: usize,
+        active: &[String],
+        rejected: &[String],
+    ) {
+        self.banner(
+            &self.palette.warning(),
+            &format!(
+                "⚠ proc-debug in process {}: none of the first {evaluated} expansions matched the filters",
+                std::process::id()
+            ),
+        );
+        for line in active {
+            self.line(&format!("  {line}"));
+        }
+        self.line(&format!("  rejected: {}", rejected.join(", ")));
+        self.line("  (--no-hints to hide this)");
+        self.line("");
+    }
+
+    /// Returns whether `entry` continues the group of the last expansion
+    /// shown, and remembers it as the last one.
+    fn continues(&self, entry: &Entry) -> bool {
+        self.group && {
+            let key = format!("{} ({}:{})", entry.label, entry.file, entry.line);
+            let mut last = LAST_SHOWN.lock().unwrap_or_else(|e| e.into_inner());
+            last.replace(key.clone()) == Some(key)
+        }
+    }
+
+    /// Shows the input of the invocation `index`. Each input is preceded by a
+    /// comment with its argument name with `verbose`, or when a function-like
+    /// macro takes several inputs.
+    pub(crate) fn macro_call(&mut self, index: usize, entry: &Entry, verbose: bool) {
+        let &Entry {
+            modpath,
+            macro_name,
+            ..
+        } = entry;
+        let content = input_content(entry, verbose, &|part| part);
+        self.continued = self.continues(entry);
+        if self.continued {
+            self.line(&format!("{}· invocation #{index}", self.stamp()));
+        } else {
+            let heading = self.heading(
+                "input",
+                &format!("{modpath}::{macro_name}"),
+                &location(entry, verbose),
+                Some(index),
+                &self.nested_note(),
+            );
+            self.banner(&self.palette.info(), &heading);
+        }
+        self.code(&content, false);
+    }
+
+    /// Shows the input of the invocation `index` and its `output` side by
+    /// side, in `width` columns. Each part of the input is formatted with
+    /// `format` to line up with the output.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn side_by_side(
+        &mut self,
+        index: usize,
+        entry: &Entry,
+        verbose: bool,
+        format: &dyn Fn(String) -> String,
+        output: &str,
+        outcome: Outcome,
+        width: usize,
+    ) {
+        let &Entry {
+            modpath,
+            macro_name,
+            ..
+        } = entry;
+        let input = input_content(entry, verbose, format);
+        self.continued = self.continues(entry);
+        let parsed = self.parsed_note();
+        if self.continued {
+            self.line(&format!(
+                "{}· invocation #{index} → output{parsed}{}",
+                self.stamp(),
+                outcome.note()
+            ));
+        } else {
+            let heading = self.heading(
+                "input → output",
+                &format!("{modpath}::{macro_name}"),
+                &location(entry, verbose),
+                Some(index),
+                &format!("{parsed}{}{}", outcome.note(), self.nested_note()),
+            );
+            self.banner(&self.palette.outcome(outcome), &heading);
+        }
+        self.show_warning();
+        let width = width.saturating_sub(self.indent().len() + 2);
+        let highlight = std::mem::replace(&mut self.highlight, false);
+        self.code(&columns(&input, output, width), false);
+        self.highlight = highlight;
+    }
+
+    pub(crate) fn macro_output(&mut self, entry: &Entry, content: &str, outcome: Outcome) {
+        let &Entry {
+            file,
+            line,
+            modpath,
+            macro_name,
+            ..
+        } = entry;
+        let parsed = self.parsed_note();
+        if self.continued {
+            self.line(&format!(
+                "{}· output{parsed}{}",
+                self.stamp(),
+                outcome.note()
+            ));
+        } else {
+            let heading = self.heading(
+                "output",
+                &format!("{modpath}::{macro_name}"),
+                &format!("{file}:{line}"),
+                None,
+                &format!("{parsed}{}{}", outcome.note(), self.nested_note()),
+            );
+            self.banner(&self.palette.outcome(outcome), &heading);
+        }
+        self.show_warning();
+        self.code(content, self.paging);
+    }
+
+    /// Shows the tokens passed to `debug_tokens` under `label`, called at
+    /// `location`.
+    pub(crate) fn tokens(&mut self, label: &str, location: &str, content: &str) {
+        let heading = self.heading("tokens", label, location, None, "");
+        self.banner(&self.palette.info(), &heading);
+        self.code(content, self.paging);
+    }
+
+    /// Shows `warning` under the next output header.
+    pub(crate) fn warn(&mut self, warning: String) {
+        self.warning = Some(warning);
+    }
+
+    fn show_warning(&mut self) {
+        if let Some(warning) = self.warning.take() {
+            self.banner(&self.palette.warning(), &format!("⚠ {warning}"));
+        }
+    }
+}
+
+/// In-memory [`WriteColor`] which records color changes as `<spec>` and
+/// resets as `</>` in the text.
+#[cfg(test)]
+#[derive(Default, Clone)]
+pub(crate) struct Recorder(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Recorder {
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for Recorder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl WriteColor for Recorder {
+    fn supports_color(&self) -> bool {
+        true
+    }
+
+    fn set_color(&mut self, spec: &ColorSpec) -> std::io::Result<()> {
+        let mut attrs = Vec::new();
+        if let Some(c) = spec.fg() {
+            attrs.push(format!("fg:{c:?}"));
+        }
+        if let Some(c) = spec.bg() {
+            attrs.push(format!("bg:{c:?}"));
+        }
+        if spec.bold() {
+            attrs.push("bold".to_owned());
+        }
+        if spec.underline() {
+            attrs.push("underline".to_owned());
+        }
+        if spec.italic() {
+            attrs.push("italic".to_owned());
+        }
+        write!(self, "<{}>", attrs.join(","))
+    }
+
+    fn reset(&mut self) -> std::io::Result<()> {
+        write!(self, "</>")
+    }
+}
+
+#[test]
+fn test_macro_call() {
+    let inputs = ["Debug".to_owned(), "struct A {\n    a: u8,\n}".to_owned()];
+    let entry = crate::test_entry("derive", &inputs);
+    let recorder = Recorder::default();
+    DisplayContext::new(recorder.clone()).macro_call(3, &entry, false);
+    assert_eq!(
+        recorder.text(),
+        "<fg:Black,bg:Cyan,bold>👉 input of my_crate::my_macro (lib.rs:1) #3</>\n\
+         \x20 #[derive(Debug)]\n\
+         \x20 struct A {\n\
+         \x20     a: u8,\n\
+         \x20 }\n\n"
+    );
+}
+
+#[test]
+fn test_macro_call_arg_names() {
+    let inputs = ["a, b".to_owned(), "fn f() {}".to_owned()];
+    let entry = Entry {
+        macro_arg_names: &["attr", "item"],
+        ..crate::test_entry("attribute", &inputs)
+    };
+    let recorder = Recorder::default();
+    DisplayContext::new(recorder.clone()).macro_call(0, &entry, true);
+    assert!(recorder.text().ends_with(
+        "\x20 // attr:\n\
+         \x20 #[my_macro(a, b)]\n\
+         \x20 // item:\n\
+         \x20 fn f() {}\n\n"
+    ));
+}
+
+#[test]
+fn test_macro_call_location() {
+    let entry = Entry {
+        call_site: Some(("src/main.rs".to_owned(), 12)),
+        ..crate::test_entry("function", &[])
+    };
+    let recorder = Recorder::default();
+    DisplayContext::new(recorder.clone()).macro_call(0, &entry, true);
+    assert!(recorder.text().starts_with(
+        "<fg:Black,bg:Cyan,bold>👉 input of my_crate::my_macro \
+         (defined at lib.rs:1, called from src/main.rs:12) #0</>"
+    ));
+}
+
+#[test]
+fn test_macro_call_function_inputs() {
+    let inputs = ["a + b".to_owned(), "struct A;".to_owned()];
+    let entry = Entry {
+        macro_arg_names: &["expr"],
+        ..crate::test_entry("function", &inputs)
+    };
+    let recorder = Recorder::default();
+    DisplayContext::new(recorder.clone()).macro_call(0, &entry, false);
+    assert!(recorder.text().ends_with(
+        "\x20 // expr:\n\
+         \x20 a + b\n\
+         \x20 // [1]:\n\
+         \x20 struct A;\n\n"
+    ));
+    let entry = crate::test_entry("attribute", &inputs[..1]);
+    DisplayContext::new(Recorder::default()).macro_call(0, &entry, false);
+}
+
+#[test]
+fn test_palette() {
+    let entry = crate::test_entry("attribute", &[]);
+    let recorder = Recorder::default();
+    DisplayContext {
+        palette: Palette::Mono,
+        ..DisplayContext::new(recorder.clone())
+    }
+    .macro_output(&entry, "", Outcome::Changed);
+    assert!(recorder.text().starts_with("<bold,underline>👉 output"));
+    let recorder = Recorder::default();
+    DisplayContext::new(recorder.clone()).macro_output(&entry, "", Outcome::Removed);
+    assert!(recorder
+        .text()
+        .starts_with("<fg:White,bg:Red,bold>👉 output of my_crate::my_macro (lib.rs:1), item removed by macro</>"));
+    let flags = ["-a", "--palette", "mono"].map(str::to_owned);
+    assert_eq!(Palette::from_flags(&flags), Palette::Mono);
+    let flags = ["--palette", "mono", "--palette=high-contrast"].map(str::to_owned);
+    assert_eq!(Palette::from_flags(&flags), Palette::HighContrast);
+}
+
+#[test]
+fn test_warning() {
+    let entry = crate::test_entry("attribute", &[]);
+    let recorder = Recorder::default();
+    let mut display = DisplayContext::new(recorder.clone());
+    display.warn("output does not parse".to_owned());
+    display.macro_output(&entry, "struct", Outcome::Changed);
+    assert!(recorder
+        .text()
+        .ends_with("</>\n<fg:Yellow,bold>⚠ output does not parse</>\n  struct\n\n"));
+}
+
+#[test]
+fn test_tee() {
+    let path = std::env::temp_dir().join(format!("proc-debug-tee-{}.log", std::process::id()));
+    let entry = crate::test_entry("attribute", &[]);
+    let recorder = Recorder::default();
+    DisplayContext::new(recorder.clone())
+        .tee(Some(&path))
+        .macro_output(&entry, "struct A;", Outcome::Changed);
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        recorder
+            .text()
+            .replace("<fg:Black,bg:Cyan,bold>", "")
+            .replace("</>", "")
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_group() {
+    let inputs = ["A".to_owned()];
+    let entry = Entry {
+        label: "test_group",
+        ..crate::test_entry("function", &inputs)
+    };
+    let recorder = Recorder::default();
+    for index in 0..2 {
+        let mut display = DisplayContext::new(recorder.clone()).group(true);
+        display.macro_call(index, &entry, false);
+        display.macro_output(&entry, "A", Outcome::Changed);
+    }
+    assert!(recorder.text().ends_with(
+        "<fg:Black,bg:Cyan,bold>👉 output of my_crate::my_macro (lib.rs:1)</>\n\
+         \x20 A\n\n\
+         · invocation #1\n\
+         \x20 my_macro!{A}\n\n\
+         · output\n\
+         \x20 A\n\n"
+    ));
+}
+
+#[test]
+fn test_macro_output() {
+    let entry = crate::test_entry("attribute", &[]);
+    let recorder = Recorder::default();
+    let mut display = DisplayContext::new(recorder.clone());
+    display.parsed_as("parsed as 1 ImplItem".to_owned());
+    display.macro_output(&entry, "fn f () {\n    ...\n}", Outcome::Unchanged);
+    assert_eq!(
+        recorder.text(),
+        "<fg:Black,bg:Cyan,bold>👉 output of my_crate::my_macro (lib.rs:1) — parsed as 1 ImplItem, unchanged</>\n\
+         \x20 fn f () {\n\
+         \x20     ...\n\
+         \x20 }\n\n"
+    );
+}
+
+#[test]
+fn test_nested() {
+    let inputs = ["A".to_owned()];
+    let entry = crate::test_entry("function", &inputs);
+    let recorder = Recorder::default();
+    let mut display = DisplayContext::new(recorder.clone()).nested(1, Some("outer"));
+    display.macro_call(1, &entry, false);
+    display.macro_output(&entry, "A", Outcome::Changed);
+    assert_eq!(
+        recorder.text(),
+        "    <fg:Black,bg:Cyan,bold>👉 input of my_crate::my_macro (lib.rs:1) #1 (nested in outer)</>\n\
+         \x20     my_macro!{A}\n\n\
+         \x20   <fg:Black,bg:Cyan,bold>👉 output of my_crate::my_macro (lib.rs:1) (nested in outer)</>\n\
+         \x20     A\n\n"
+    );
+}
+
+#[test]
+fn test_flags_banner() {
+    let recorder = Recorder::default();
+    DisplayContext::new(recorder.clone()).flags_banner(
+        "-p a::b",
+        &["paths: a::b".to_owned(), "depth: 2".to_owned()],
+    );
+    assert_eq!(
+        recorder.text(),
+        format!(
+            "<fg:Black,bg:Cyan,bold>👉 proc-debug in process {}: PROC_DEBUG_FLAGS=\"-p a::b\"</>\n\
+             \x20 paths: a::b\n\
+             \x20 depth: 2\n\n",
+            std::process::id()
+        )
+    );
+}
+
+#[test]
+fn test_heading() {
+    let inputs = ["A".to_owned()];
+    let entry = crate::test_entry("function", &inputs);
+    let recorder = Recorder::default();
+    let mut display = DisplayContext::new(recorder.clone()).ascii(true);
+    display.parsed_as("parsed as a Type".to_owned());
+    display.macro_output(&entry, "A", Outcome::Changed);
+    let mut display = DisplayContext::new(recorder.clone()).heading_format(Some(
+        "{marker} [{what} {index}] {macro}{notes} @ {location}",
+    ));
+    display.macro_call(2, &entry, false);
+    let mut display = DisplayContext::new(recorder.clone())
+        .timestamp(Some("[2024-05-01T09:30:00.125+09:00 +0.250s] ".to_owned()));
+    display.macro_call(3, &entry, false);
+    assert_eq!(
+        recorder.text(),
+        "<fg:Black,bg:Cyan,bold>>> output of my_crate::my_macro (lib.rs:1) - parsed as a Type</>\n\
+         \x20 A\n\n\
+         <fg:Black,bg:Cyan,bold>👉 [input 2] my_crate::my_macro @ lib.rs:1</>\n\
+         \x20 my_macro!{A}\n\n\
+         <fg:Black,bg:Cyan,bold>[2024-05-01T09:30:00.125+09:00 +0.250s] 👉 input of my_crate::my_macro (lib.rs:1) #3</>\n\
+         \x20 my_macro!{A}\n\n"
+    );
+}
@@ -0,0 +1,112 @@
+//! Folding of doc attributes in the displayed output, disabled by
+//! `--show-docs`.
+//!
+//! Runs of consecutive `#[doc = ..]` attributes are replaced by a single doc
+//! attribute holding a marker, which survives formatting by rustfmt or the
+//! indenter. After formatting, the marker is replaced with a line telling how
+//! many doc attributes were folded.
+
+use proc_macro2::{Delimiter, TokenStream, TokenTree};
+use quote::quote;
+
+const DOCS_MARKER: &str = "__proc_debug_docs:";
+
+fn is_doc(attr: &TokenTree) -> bool {
+    matches!(attr, TokenTree::Group(g) if g.delimiter() == Delimiter::Bracket
+        && matches!(g.stream().into_iter().next(), Some(TokenTree::Ident(i)) if i == "doc"))
+}
+
+#[test]
+fn test_fold_docs() {
+    let tokens = quote! {
+        /// A
+        /// struct
+        #[derive(Clone)]
+        /// more
+        #[doc = "docs"]
+        struct A {
+            /// field
+            a: u8,
+        }
+        struct B(#[doc = "b"] u8);
+    };
+    let formatted = crate::indent_tokens(fold_docs(tokens), false);
+    assert_eq!(
+        replace_folded(&formatted),
+        "/// (2 doc lines folded)
+#[derive (Clone)]
+/// (2 doc lines folded)
+struct A {
+    /// (1 doc line folded)
+    a : u8,
+}
+struct B (/* (1 doc line folded) */ u8);"
+    );
+}
+
+/// Replaces each run of outer doc attributes in `tokens`, at any depth, with a
+/// marker attribute.
+pub(crate) fn fold_docs(tokens: TokenStream) -> TokenStream {
+    let mut tokens = tokens.into_iter().peekable();
+    let mut out = TokenStream::new();
+    let mut folded = 0;
+    let flush = |out: &mut TokenStream, folded: &mut usize| {
+        if *folded > 0 {
+            let marker = format!("{DOCS_MARKER}{folded}");
+            out.extend(quote!(#[doc = #marker]));
+            *folded = 0;
+        }
+    };
+    while let Some(token) = tokens.next() {
+        match token {
+            TokenTree::Punct(p) if p.as_char() == '#' && tokens.peek().is_some_and(is_doc) => {
+                tokens.next();
+                folded += 1;
+            }
+            TokenTree::Group(g) => {
+                flush(&mut out, &mut folded);
+                let mut group = proc_macro2::Group::new(g.delimiter(), fold_docs(g.stream()));
+                group.set_span(g.span());
+                out.extend(Some(TokenTree::Group(group)));
+            }
+            token => {
+                flush(&mut out, &mut folded);
+                out.extend(Some(token));
+            }
+        }
+    }
+    flush(&mut out, &mut folded);
+    out
+}
+
+fn folded_note(count: &str) -> String {
+    let s = if count == "1" { "" } else { "s" };
+    format!("({count} doc line{s} folded)")
+}
+
+/// Replaces the marker attributes left by [`fold_docs`] in formatted output. A
+/// marker on its own line becomes a `///` comment, otherwise a `/* */` one.
+pub(crate) fn replace_folded(formatted: &str) -> String {
+    let prefix = format!("#[doc = \"{DOCS_MARKER}");
+    formatted
+        .split('\n')
+        .map(|line| {
+            let mut line = line.to_owned();
+            while let Some(start) = line.find(&prefix) {
+                let rest = &line[start + prefix.len()..];
+                let Some(end) = rest.find("\"]") else {
+                    break;
+                };
+                let note = folded_note(&rest[..end]);
+                let after = rest[end + 2..].to_owned();
+                line = if line[..start].trim().is_empty() && after.trim().is_empty() {
+                    format!("{}/// {note}", &line[..start])
+                } else {
+                    format!("{}/* {note} */{after}", &line[..start])
+                };
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,245 @@
+//! Plain-language notes about common generated patterns, shown by `--explain`.
+//!
+//! Rules are listed in [`RULES`]; each one matches on the shape of a top-level
+//! item. To add a rule, append an entry with a short name, the note and a
+//! matcher, plus a snippet pair in `test_rules`.
+
+use proc_macro2::TokenStream;
+use syn::parse::Parser;
+use syn::*;
+
+struct Rule {
+    name: &'static str,
+    note: &'static str,
+    matches: fn(&Item) -> bool,
+}
+
+fn attrs(item: &Item) -> &[Attribute] {
+    match item {
+        Item::Const(i) => &i.attrs,
+        Item::Enum(i) => &i.attrs,
+        Item::ExternCrate(i) => &i.attrs,
+        Item::Fn(i) => &i.attrs,
+        Item::ForeignMod(i) => &i.attrs,
+        Item::Impl(i) => &i.attrs,
+        Item::Macro(i) => &i.attrs,
+        Item::Mod(i) => &i.attrs,
+        Item::Static(i) => &i.attrs,
+        Item::Struct(i) => &i.attrs,
+        Item::Trait(i) => &i.attrs,
+        Item::TraitAlias(i) => &i.attrs,
+        Item::Type(i) => &i.attrs,
+        Item::Union(i) => &i.attrs,
+        Item::Use(i) => &i.attrs,
+        _ => &[],
+    }
+}
+
+fn has_attr(item: &Item, name: &str) -> bool {
+    attrs(item).iter().any(|a| a.path().is_ident(name))
+}
+
+fn has_attr_arg(item: &Item, name: &str, arg: &str) -> bool {
+    attrs(item).iter().any(|a| {
+        a.path().is_ident(name)
+            && matches!(&a.meta, Meta::List(l) if l.tokens.to_string().split(',').any(|s| s.trim() == arg))
+    })
+}
+
+fn last_segment(path: &Path) -> String {
+    path.segments
+        .last()
+        .map(|s| s.ident.to_string())
+        .unwrap_or_default()
+}
+
+fn impl_of(item: &Item, traits: &[&str]) -> bool {
+    matches!(item, Item::Impl(ItemImpl { trait_: Some((None, path, _)), .. })
+        if traits.contains(&last_segment(path).as_str()))
+}
+
+fn type_is(ty: &Type, name: &str) -> bool {
+    matches!(ty, Type::Path(p) if last_segment(&p.path) == name)
+}
+
+fn use_as_underscore(tree: &UseTree) -> bool {
+    match tree {
+        UseTree::Path(p) => use_as_underscore(&p.tree),
+        UseTree::Rename(r) => r.rename == "_",
+        UseTree::Group(g) => g.items.iter().any(use_as_underscore),
+        _ => false,
+    }
+}
+
+static RULES: &[Rule] = &[
+    Rule {
+        name: "anonymous-const",
+        note: "`const _: () = { ... }` — anonymous const used to scope imports without polluting the namespace",
+        matches: |item| matches!(item, Item::Const(c) if c.ident == "_"),
+    },
+    Rule {
+        name: "automatically-derived",
+        note: "`#[automatically_derived]` — marker attribute, no semantic effect",
+        matches: |item| has_attr(item, "automatically_derived"),
+    },
+    Rule {
+        name: "phantom-data",
+        note: "PhantomData field — carries generic parameter usage without storing a value",
+        matches: |item| match item {
+            Item::Struct(s) => s.fields.iter().any(|f| type_is(&f.ty, "PhantomData")),
+            _ => false,
+        },
+    },
+    Rule {
+        name: "doc-hidden",
+        note: "`#[doc(hidden)]` — public for the generated code only, not part of the documented API",
+        matches: |item| has_attr_arg(item, "doc", "hidden"),
+    },
+    Rule {
+        name: "lint-allow",
+        note: "`#[allow(..)]` — silences lints which generated code would otherwise trigger in the caller's crate",
+        matches: |item| has_attr(item, "allow"),
+    },
+    Rule {
+        name: "extern-crate-alias",
+        note: "`extern crate .. as ..` — reliable path to a dependency regardless of how the caller renamed it",
+        matches: |item| matches!(item, Item::ExternCrate(e) if e.rename.is_some()),
+    },
+    Rule {
+        name: "use-as-underscore",
+        note: "`use Trait as _` — brings trait methods into scope without binding a name",
+        matches: |item| matches!(item, Item::Use(u) if use_as_underscore(&u.tree)),
+    },
+    Rule {
+        name: "unsafe-send-sync",
+        note: "`unsafe impl Send/Sync` — thread-safety is asserted by the macro instead of checked by the compiler",
+        matches: |item| {
+            matches!(item, Item::Impl(i) if i.unsafety.is_some()) && impl_of(item, &["Send", "Sync"])
+        },
+    },
+    Rule {
+        name: "error-impl",
+        note: "`impl Error` — makes the type usable with `?` and `Box<dyn Error>` (typical of thiserror)",
+        matches: |item| impl_of(item, &["Error"]),
+    },
+    Rule {
+        name: "display-impl",
+        note: "`impl Display` — human-readable formatting, also used by `to_string()`",
+        matches: |item| impl_of(item, &["Display"]),
+    },
+    Rule {
+        name: "from-impl",
+        note: "`impl From` — conversion used by `.into()` and by `?` to convert errors",
+        matches: |item| impl_of(item, &["From"]),
+    },
+    Rule {
+        name: "link-section-static",
+        note: "static in a link section — registration collected at link time (inventory/linkme style)",
+        matches: |item| {
+            matches!(item, Item::Static(_)) && (has_attr(item, "link_section") || has_attr(item, "used"))
+        },
+    },
+];
+
+#[test]
+fn test_rules() {
+    let cases: &[(&str, &str, &str)] = &[
+        (
+            "anonymous-const",
+            "const _: () = { use std::fmt; };",
+            "const A: () = ();",
+        ),
+        (
+            "automatically-derived",
+            "#[automatically_derived] impl Clone for A {}",
+            "#[derive(Clone)] struct A;",
+        ),
+        (
+            "phantom-data",
+            "struct A<T> { _m: PhantomData<T> }",
+            "struct A<T> { m: Vec<T> }",
+        ),
+        (
+            "doc-hidden",
+            "#[doc(hidden)] pub fn f() {}",
+            "#[doc = \"hidden\"] pub fn f() {}",
+        ),
+        (
+            "lint-allow",
+            "#[allow(unused)] fn f() {}",
+            "#[deny(unused)] fn f() {}",
+        ),
+        (
+            "extern-crate-alias",
+            "extern crate serde as _serde;",
+            "extern crate serde;",
+        ),
+        (
+            "use-as-underscore",
+            "use std::io::{Read as _};",
+            "use std::io::Read as R;",
+        ),
+        (
+            "unsafe-send-sync",
+            "unsafe impl Send for A {}",
+            "impl Clone for A {}",
+        ),
+        (
+            "error-impl",
+            "impl std::error::Error for A {}",
+            "impl A { fn source() {} }",
+        ),
+        (
+            "display-impl",
+            "impl core::fmt::Display for A {}",
+            "impl core::fmt::Debug for A {}",
+        ),
+        (
+            "from-impl",
+            "impl From<u8> for A {}",
+            "impl TryFrom<u8> for A {}",
+        ),
+        (
+            "link-section-static",
+            "#[used] static A: u8 = 0;",
+            "static A: u8 = 0;",
+        ),
+    ];
+    assert_eq!(cases.len(), RULES.len());
+    for (name, hit, miss) in cases {
+        let rule = RULES.iter().find(|r| r.name == *name).unwrap();
+        assert!((rule.matches)(&parse_str(hit).unwrap()), "{name} on {hit}");
+        assert!(
+            !(rule.matches)(&parse_str(miss).unwrap()),
+            "{name} on {miss}"
+        );
+    }
+}
+
+/// Returns the notes of all rules matching `item`, prefixed by the rule name.
+fn notes(item: &Item) -> impl Iterator<Item = String> + '_ {
+    RULES
+        .iter()
+        .filter(|r| (r.matches)(item))
+        .map(|r| format!("[{}] {}", r.name, r.note))
+}
+
+/// Splits `tokens` into top-level items, each paired with its notes. Returns
+/// `None` if the tokens are not a sequence of items.
+pub(crate) fn explain(tokens: TokenStream) -> Option<Vec<(Vec<String>, TokenStream)>> {
+    let items = (|input: parse::ParseStream| {
+        let mut v = Vec::new();
+        while !input.is_empty() {
+            v.push(input.parse::<Item>()?);
+        }
+        Ok(v)
+    })
+    .parse2(tokens)
+    .ok()?;
+    Some(
+        items
+            .into_iter()
+            .map(|item| (notes(&item).collect(), quote::quote!(#item)))
+            .collect(),
+    )
+}
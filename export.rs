@@ -0,0 +1,423 @@
+//! Machine-readable summaries of expansions.
+
+use crate::capability;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Output format selected by `--format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Text,
+    Csv,
+    Tsv,
+    /// One [`MacroRecord`] per line.
+    #[cfg(feature = "serde")]
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "csv" => Ok(Self::Csv),
+            "tsv" => Ok(Self::Tsv),
+            #[cfg(feature = "serde")]
+            "json" => Ok(Self::Json),
+            #[cfg(not(feature = "serde"))]
+            "json" => Err("the json format requires the `serde` feature".to_owned()),
+            _ => Err(format!(
+                "unknown format `{s}`, expected one of: text, csv, tsv, json"
+            )),
+        }
+    }
+}
+
+impl Format {
+    fn separator(&self) -> &'static str {
+        match self {
+            Format::Tsv => "\t",
+            _ => ",",
+        }
+    }
+
+    fn escape<'a>(&self, field: &'a str) -> std::borrow::Cow<'a, str> {
+        match self {
+            Format::Csv if field.contains([',', '"', '\n', '\r']) => {
+                format!("\"{}\"", field.replace('"', "\"\"")).into()
+            }
+            Format::Tsv if field.contains(['\t', '\n', '\r', '\\']) => field
+                .replace('\\', "\\\\")
+                .replace('\t', "\\t")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r")
+                .into(),
+            _ => field.into(),
+        }
+    }
+
+    fn row(&self, fields: &[&str]) -> String {
+        let fields = fields.iter().map(|f| self.escape(f)).collect::<Vec<_>>();
+        format!("{}\r\n", fields.join(self.separator()))
+    }
+
+    /// What comes before the first record: the placeholders of
+    /// `--portable-paths` in `mapping`, as comment lines for csv and tsv and a
+    /// `{"paths": {..}}` object for json, then the header row.
+    fn header(&self, mapping: &[(&str, String)]) -> String {
+        match self {
+            #[cfg(feature = "serde")]
+            Format::Json if mapping.is_empty() => String::new(),
+            #[cfg(feature = "serde")]
+            Format::Json => {
+                let paths = mapping
+                    .iter()
+                    .map(|(name, path)| (name.to_string(), serde_json::Value::from(path.as_str())))
+                    .collect::<serde_json::Map<_, _>>();
+                format!("{}\n", serde_json::json!({ "paths": paths }))
+            }
+            _ => {
+                let comments = mapping
+                    .iter()
+                    .map(|(name, path)| format!("# {name} = {path}\r\n"))
+                    .collect::<String>();
+                comments + &self.row(HEADER)
+            }
+        }
+    }
+
+    fn row_of(&self, record: &MacroRecord) -> String {
+        match self {
+            #[cfg(feature = "serde")]
+            Format::Json => format!("{}\n", serde_json::to_string(record).unwrap()),
+            _ => {
+                let fields = record.fields();
+                self.row(&fields.iter().map(|s| s.as_str()).collect::<Vec<_>>())
+            }
+        }
+    }
+}
+
+/// One expansion, as written by `--format csv`, `tsv` and `json`.
+///
+/// With the `serde` feature, `--format json` writes each record serialized by
+/// `serde_json` on its own line, so that this struct can be used to read it
+/// back.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MacroRecord {
+    /// Index of the invocation among those of the rustc thread expanding it,
+    /// starting from 0
+    pub index: usize,
+    pub label: String,
+    /// `function`, `attribute`, `derive` or `unknown`
+    pub kind: String,
+    /// Module path of the macro definition
+    pub modpath: String,
+    pub macro_name: String,
+    pub def_file: String,
+    pub def_line: usize,
+    /// Inputs of the macro, such as the attribute arguments and the item
+    pub inputs: Vec<String>,
+    /// Parameter names of the inputs
+    pub arg_names: Vec<String>,
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    /// Output of the macro, indented but not truncated
+    pub output: String,
+    pub duration_ms: f64,
+    /// Local time the expansion started at, in ISO 8601 with milliseconds
+    pub timestamp: String,
+    /// Milliseconds from the first expansion of the rustc process to this one
+    pub offset_ms: f64,
+}
+
+const HEADER: &[&str] = &[
+    "index",
+    "label",
+    "kind",
+    "modpath",
+    "macro_name",
+    "def_file",
+    "def_line",
+    "input_tokens",
+    "output_tokens",
+    "duration_ms",
+    "timestamp",
+    "offset_ms",
+];
+
+impl MacroRecord {
+    fn fields(&self) -> [String; 12] {
+        [
+            self.index.to_string(),
+            self.label.clone(),
+            self.kind.clone(),
+            self.modpath.clone(),
+            self.macro_name.clone(),
+            self.def_file.clone(),
+            self.def_line.to_string(),
+            self.input_tokens.to_string(),
+            self.output_tokens.to_string(),
+            format!("{:.3}", self.duration_ms),
+            self.timestamp.clone(),
+            format!("{:.3}", self.offset_ms),
+        ]
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_json_round_trip() {
+    let record = MacroRecord {
+        index: 1,
+        label: "a::b".to_owned(),
+        kind: "attribute".to_owned(),
+        modpath: "a".to_owned(),
+        macro_name: "b".to_owned(),
+        def_file: "src/lib.rs".to_owned(),
+        def_line: 3,
+        inputs: vec!["".to_owned(), "struct \"A\";".to_owned()],
+        arg_names: vec!["attr".to_owned(), "item".to_owned()],
+        input_tokens: 2,
+        output_tokens: 3,
+        output: "struct A;".to_owned(),
+        duration_ms: 0.25,
+        timestamp: "2024-05-01T09:30:00.125+09:00".to_owned(),
+        offset_ms: 12.5,
+    };
+    let line = Format::Json.row_of(&record);
+    assert!(line.ends_with('\n') && !line.trim_end().contains('\n'));
+    assert_eq!(serde_json::from_str::<MacroRecord>(&line).unwrap(), record);
+}
+
+#[test]
+fn test_row_quoting() {
+    assert_eq!(
+        Format::Csv.row(&["a", "b,c", "say \"hi\"", "x\ny"]),
+        "a,\"b,c\",\"say \"\"hi\"\"\",\"x\ny\"\r\n"
+    );
+    assert_eq!(Format::Tsv.row(&["a\tb", "c\nd"]), "a\\tb\tc\\nd\r\n");
+    let mapping = [("{workspace}", "/home/me/app".to_owned())];
+    assert!(Format::Csv
+        .header(&mapping)
+        .starts_with("# {workspace} = /home/me/app\r\nindex,label,"));
+    assert!(Format::Tsv.header(&[]).starts_with("index\t"));
+    #[cfg(feature = "serde")]
+    assert_eq!(
+        Format::Json.header(&mapping),
+        "{\"paths\":{\"{workspace}\":\"/home/me/app\"}}\n"
+    );
+}
+
+/// Files named by the environment for the records of the expansions.
+#[derive(Default)]
+pub(crate) struct Destinations {
+    /// `PROC_DEBUG_OUT`, where [`write_record`] writes instead of stdout
+    pub(crate) out: Option<PathBuf>,
+    /// `PROC_DEBUG_RESULTS`, where [`record_invocation`] writes
+    pub(crate) results: Option<PathBuf>,
+}
+
+impl Destinations {
+    /// Returns the destinations of this process, whose environment is read
+    /// once.
+    pub(crate) fn get() -> &'static Self {
+        static DESTINATIONS: std::sync::OnceLock<Destinations> = std::sync::OnceLock::new();
+        DESTINATIONS.get_or_init(|| Destinations {
+            out: std::env::var_os("PROC_DEBUG_OUT").map(PathBuf::from),
+            results: std::env::var_os("PROC_DEBUG_RESULTS").map(PathBuf::from),
+        })
+    }
+}
+
+/// Writes `record` to the file `out`, or to stdout.
+///
+/// The header row, after the placeholders of `--portable-paths` in `mapping`,
+/// is written once per destination: when the file is empty, or on the first
+/// record of this process for stdout.
+pub(crate) fn write_record(
+    format: Format,
+    record: &MacroRecord,
+    out: Option<&Path>,
+    mapping: &[(&str, String)],
+) {
+    static STDOUT_HEADER: AtomicBool = AtomicBool::new(false);
+    let row = format.row_of(record);
+    match out {
+        Some(path) => {
+            let Some(mut file) = capability::open_append(path) else {
+                return;
+            };
+            let empty = file.metadata().map(|m| m.len() == 0).unwrap_or(false);
+            let header = if empty {
+                format.header(mapping)
+            } else {
+                String::new()
+            };
+            let _ = file.write_all(format!("{header}{row}").as_bytes());
+        }
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            if !STDOUT_HEADER.swap(true, Ordering::Relaxed) {
+                let _ = stdout.write_all(format.header(mapping).as_bytes());
+            }
+            let _ = stdout.write_all(row.as_bytes());
+        }
+    }
+}
+
+/// Appends `modpath::macro_name` to the file `results`, if any.
+///
+/// `cargo proc-debug` reads this file to find instrumented macros which were
+/// never invoked. Every invocation is recorded, whether it matches the filter
+/// or not.
+pub(crate) fn record_invocation(results: Option<&Path>, modpath: &str, macro_name: &str) {
+    if let Some(path) = results {
+        if let Some(mut file) = capability::open_append(path) {
+            let _ = file.write_all(format!("{modpath}::{macro_name}\n").as_bytes());
+        }
+    }
+}
+
+/// Directory of `--dump`, `proc-debug` in the target directory.
+pub(crate) fn default_dump_dir() -> PathBuf {
+    let target = std::env::var_os("CARGO_TARGET_DIR").unwrap_or_else(|| "target".into());
+    PathBuf::from(target).join("proc-debug")
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_dump_expansion() {
+    let dir = std::env::temp_dir().join(format!("proc-debug-dump-{}", std::process::id()));
+    let record = MacroRecord {
+        index: 2,
+        label: "a::b".to_owned(),
+        kind: "attribute".to_owned(),
+        modpath: "a".to_owned(),
+        macro_name: "r#b".to_owned(),
+        def_file: "src/lib.rs".to_owned(),
+        def_line: 3,
+        inputs: vec!["x".to_owned(), "struct A;".to_owned()],
+        arg_names: vec!["attr".to_owned(), "item".to_owned()],
+        input_tokens: 4,
+        output_tokens: 3,
+        output: "struct A;".to_owned(),
+        duration_ms: 0.0,
+        timestamp: "2024-05-01T09:30:00.125+09:00".to_owned(),
+        offset_ms: 0.0,
+    };
+    let mapping = [("{workspace}", "/home/me/app".to_owned())];
+    dump_expansion(&dir, &record, &mapping);
+    dump_expansion(&dir, &record, &[]);
+    let crate_dir = dir.join(sanitize(
+        &std::env::var("CARGO_CRATE_NAME").unwrap_or_default(),
+    ));
+    // The tests run on several threads
+    let name = DUMP_THREAD.with(|thread| dump_name(*thread, &record));
+    assert_eq!(
+        std::fs::read_to_string(crate_dir.join(format!("{name}.rs"))).unwrap(),
+        "struct A;\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(crate_dir.join(format!("{name}.input.rs"))).unwrap(),
+        "// attr:\nx\n// item:\nstruct A;\n"
+    );
+    let index = std::fs::read_to_string(dir.join("index.txt")).unwrap();
+    assert!(index.ends_with(&format!("{name}.rs\ta::r#b\tsrc/lib.rs:3\n")));
+    assert_eq!(
+        std::fs::read_to_string(dir.join("paths.txt")).unwrap(),
+        "{workspace} = /home/me/app\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(dump_name(0, &record), "2_r_b");
+    assert_eq!(dump_name(3, &record), "t3_2_r_b");
+}
+
+/// Threads of this process which dumped an expansion.
+static DUMP_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Number of this thread among those which dumped an expansion, in the
+    /// order of their first dump.
+    static DUMP_THREAD: usize = DUMP_THREADS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Name of the dump files of `record`: `<index>_<macro_name>`, prefixed by
+/// `t<thread>_` after the first thread, as each thread of a parallel front-end
+/// counts its invocations from 0.
+fn dump_name(thread: usize, record: &MacroRecord) -> String {
+    let name = format!("{}_{}", record.index, sanitize(&record.macro_name));
+    match thread {
+        0 => name,
+        thread => format!("t{thread}_{name}"),
+    }
+}
+
+/// Writes the output of `record` to `<index>_<macro_name>.rs` and its inputs
+/// to `<index>_<macro_name>.input.rs`, as named by [`dump_name`], in a
+/// subdirectory of `dir` named after the crate being compiled, since each
+/// rustc process counts invocations from 0. A line mapping the file back to
+/// the macro is appended to `index.txt`, and the placeholders of
+/// `--portable-paths` in `mapping` are written to `paths.txt` unless it exists.
+pub(crate) fn dump_expansion(dir: &Path, record: &MacroRecord, mapping: &[(&str, String)]) {
+    let krate = sanitize(&std::env::var("CARGO_CRATE_NAME").unwrap_or_default());
+    let crate_dir = dir.join(&krate);
+    if !capability::writable_dir(&crate_dir) {
+        return;
+    }
+    let name = DUMP_THREAD.with(|thread| dump_name(*thread, record));
+    let inputs = record
+        .inputs
+        .iter()
+        .enumerate()
+        .map(|(n, input)| match record.arg_names.get(n) {
+            Some(arg) => format!("// {arg}:\n{input}\n"),
+            None => format!("// [{n}]:\n{input}\n"),
+        })
+        .collect::<String>();
+    let output = format!("{}\n", record.output);
+    let written = std::fs::write(crate_dir.join(format!("{name}.rs")), output)
+        .and_then(|_| std::fs::write(crate_dir.join(format!("{name}.input.rs")), inputs));
+    if written.is_err() {
+        return;
+    }
+    if let Some(mut index) = capability::open_append(&dir.join("index.txt")) {
+        let line = format!(
+            "{}\t{}::{}\t{}:{}\n",
+            Path::new(&krate).join(format!("{name}.rs")).display(),
+            record.modpath,
+            record.macro_name,
+            record.def_file,
+            record.def_line
+        );
+        let _ = index.write_all(line.as_bytes());
+    }
+    if !mapping.is_empty() {
+        let paths = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dir.join("paths.txt"));
+        if let Ok(mut paths) = paths {
+            let lines = mapping
+                .iter()
+                .map(|(name, path)| format!("{name} = {path}\n"))
+                .collect::<String>();
+            let _ = paths.write_all(lines.as_bytes());
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Line-based unified diffs, as recorded in a [`Plan`](crate::Plan).
+
+use anyhow::{bail, Result};
+
+/// Lines of context around each change.
+const CONTEXT: usize = 3;
+
+const NO_NEWLINE: &str = "\\ No newline at end of file";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Shortest edit script from `a` to `b`, by the algorithm of Myers.
+fn edit_script(a: &[&str], b: &[&str]) -> Vec<Op> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let offset = n + m + 1;
+    let mut v = vec![0isize; 2 * offset as usize + 1];
+    // `trace[d]` holds `v[k]` for `k` in `-d..=d` before round `d`.
+    let mut trace = Vec::new();
+    'rounds: for d in 0..=n + m {
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d
+                || (k != d && v[(offset + k - 1) as usize] < v[(offset + k + 1) as usize])
+            {
+                v[(offset + k + 1) as usize]
+            } else {
+                v[(offset + k - 1) as usize] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[(offset + k) as usize] = x;
+            if x >= n && y >= m {
+                break 'rounds;
+            }
+        }
+    }
+    let mut ops = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let get = |k: isize| v[(k + d) as usize];
+        let k = x - y;
+        let (prev_x, prev_y) = if d == 0 {
+            (0, 0)
+        } else {
+            let prev_k = if k == -d || (k != d && get(k - 1) < get(k + 1)) {
+                k + 1
+            } else {
+                k - 1
+            };
+            (get(prev_k), get(prev_k) - prev_k)
+        };
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            ops.push(Op::Equal(x as usize, y as usize));
+        }
+        if d > 0 {
+            if x == prev_x {
+                y -= 1;
+                ops.push(Op::Insert(y as usize));
+            } else {
+                x -= 1;
+                ops.push(Op::Delete(x as usize));
+            }
+        }
+    }
+    ops.reverse();
+    ops
+}
+
+/// Appends `line` with `prefix`, marking a missing newline at the end.
+fn push_line(out: &mut String, prefix: char, line: &str) {
+    out.push(prefix);
+    match line.strip_suffix('\n') {
+        Some(line) => {
+            out.push_str(line);
+            out.push('\n');
+        }
+        None => {
+            out.push_str(line);
+            out.push('\n');
+            out.push_str(NO_NEWLINE);
+            out.push('\n');
+        }
+    }
+}
+
+/// Start of a hunk in a header, which is the line before it when the hunk is
+/// empty on that side.
+fn hunk_start(start: usize, count: usize) -> usize {
+    if count == 0 {
+        start
+    } else {
+        start + 1
+    }
+}
+
+#[test]
+fn test_unified_diff() {
+    let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj";
+    let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+    let diff = unified_diff("lib.rs", old, new);
+    assert_eq!(
+        diff,
+        "--- a/lib.rs\n+++ b/lib.rs\n\
+         @@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n\
+         @@ -7,4 +7,5 @@\n g\n h\n i\n-j\n\\ No newline at end of file\n+j\n+k\n"
+    );
+    assert_eq!(apply(old, &diff).unwrap(), new);
+    assert_eq!(apply(new, &unified_diff("lib.rs", new, old)).unwrap(), old);
+    assert_eq!(unified_diff("lib.rs", old, old), "");
+    assert_eq!(apply("", &unified_diff("lib.rs", "", new)).unwrap(), new);
+    assert!(apply("a\nx\nc\n", &diff).is_err());
+}
+
+/// Returns the unified diff from `old` to `new`, labelled with `path`, or an
+/// empty string if they are equal.
+pub(crate) fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    let a = old.split_inclusive('\n').collect::<Vec<_>>();
+    let b = new.split_inclusive('\n').collect::<Vec<_>>();
+    let ops = edit_script(&a, &b);
+    let changes = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, Op::Equal(..)))
+        .map(|(n, _)| n)
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        return String::new();
+    }
+    // Ranges of `ops` shown in each hunk, merging those whose context overlaps.
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &n in &changes {
+        let start = n.saturating_sub(CONTEXT);
+        let end = (n + CONTEXT + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    let mut out = format!("--- a/{path}\n+++ b/{path}\n");
+    for (start, end) in hunks {
+        // Position of the hunk on each side, from the ops before it.
+        let (mut old_start, mut new_start) = (0, 0);
+        for op in &ops[..start] {
+            match op {
+                Op::Equal(..) => {
+                    old_start += 1;
+                    new_start += 1;
+                }
+                Op::Delete(_) => old_start += 1,
+                Op::Insert(_) => new_start += 1,
+            }
+        }
+        let mut body = String::new();
+        let (mut old_count, mut new_count) = (0, 0);
+        for op in &ops[start..end] {
+            match *op {
+                Op::Equal(x, _) => {
+                    push_line(&mut body, ' ', a[x]);
+                    old_count += 1;
+                    new_count += 1;
+                }
+                Op::Delete(x) => {
+                    push_line(&mut body, '-', a[x]);
+                    old_count += 1;
+                }
+                Op::Insert(y) => {
+                    push_line(&mut body, '+', b[y]);
+                    new_count += 1;
+                }
+            }
+        }
+        out.push_str(&format!(
+            "@@ -{},{old_count} +{},{new_count} @@\n",
+            hunk_start(old_start, old_count),
+            hunk_start(new_start, new_count)
+        ));
+        out.push_str(&body);
+    }
+    out
+}
+
+/// Parses `-start,count` or `+start,count` of a hunk header, returning the
+/// 0-based index of the first line and the count.
+fn parse_range(range: Option<&str>, sign: char) -> Option<(usize, usize)> {
+    let (start, count) = range?.strip_prefix(sign)?.split_once(',')?;
+    let (start, count) = (start.parse::<usize>().ok()?, count.parse().ok()?);
+    Some((
+        if count == 0 {
+            start
+        } else {
+            start.checked_sub(1)?
+        },
+        count,
+    ))
+}
+
+/// Applies `diff`, made by [`unified_diff`], to `old`. Fails if a line of
+/// context or a removed line does not match `old`.
+pub(crate) fn apply(old: &str, diff: &str) -> Result<String> {
+    let old = old.split_inclusive('\n').collect::<Vec<_>>();
+    let mut out = String::new();
+    let mut pos = 0;
+    // Not `lines`, which would also strip a `\r` belonging to a line.
+    let mut lines = diff.split_terminator('\n').peekable();
+    while lines
+        .peek()
+        .is_some_and(|l| l.starts_with("--- ") || l.starts_with("+++ "))
+    {
+        lines.next();
+    }
+    while let Some(header) = lines.next() {
+        let mut ranges = header
+            .strip_prefix("@@ ")
+            .and_then(|h| h.strip_suffix(" @@"))
+            .map(|h| h.split(' '));
+        let old_range = ranges.as_mut().and_then(|r| parse_range(r.next(), '-'));
+        let Some((start, _)) = old_range else {
+            bail!("malformed hunk header `{header}`");
+        };
+        if start < pos || start > old.len() {
+            bail!("hunk `{header}` is out of order or beyond the end of the file");
+        }
+        out.extend(old[pos..start].iter().copied());
+        pos = start;
+        while let Some(line) = lines.next_if(|l| !l.starts_with("@@ ")) {
+            let newline = if lines.next_if_eq(&NO_NEWLINE).is_some() {
+                ""
+            } else {
+                "\n"
+            };
+            let (prefix, text) = line.split_at(line.len().min(1));
+            let text = format!("{text}{newline}");
+            if prefix == " " || prefix == "-" {
+                if old.get(pos) != Some(&text.as_str()) {
+                    bail!("line {} does not match the diff", pos + 1);
+                }
+                pos += 1;
+            }
+            match prefix {
+                " " | "+" => out.push_str(&text),
+                "-" => (),
+                _ => bail!("malformed line `{line}` in hunk `{header}`"),
+            }
+        }
+    }
+    out.extend(old[pos..].iter().copied());
+    Ok(out)
+}
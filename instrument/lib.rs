@@ -0,0 +1,1146 @@
+//! Source transformation behind `cargo proc-debug`, usable from build tools
+//! which do not go through cargo.
+//!
+//! [`instrument_package`] annotates every proc-macro function of a package
+//! with `#[::proc_debug::proc_debug]` and adds `proc-debug` to its
+//! dependencies, keeping each original file next to it as
+//! `<name>.proc-debug-bak`, and the hash of the modified file as
+//! `<name>.proc-debug-sha256`. [`restore_package`] puts the originals back,
+//! unless the modified files were edited since.
+//!
+//! Instrumenting is done in two steps, which can also be run separately to
+//! review the changes before any file is touched: [`plan_package`] computes a
+//! diff of each file to modify, and [`apply_package`] applies it.
+//!
+//! Only functions registered as proc-macros, by `#[proc_macro]`,
+//! `#[proc_macro_attribute]` or `#[proc_macro_derive]`, possibly under a
+//! `cfg_attr`, are annotated. [`apply_package`] checks this by parsing the
+//! modified file again.
+//!
+//! Proc-macros are at the root of their crate, so only the file of the library
+//! target is modified. Those of a file it includes with `include!` are
+//! reported in [`InstrumentReport::warnings`] instead.
+
+mod diff;
+
+use anyhow::Result;
+use quote::ToTokens;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+const BACKUP_SUFFIX: &str = ".proc-debug-bak";
+const HASH_SUFFIX: &str = ".proc-debug-sha256";
+
+/// Options for [`instrument_package`].
+#[derive(Debug, Clone)]
+pub struct InstrumentOptions {
+    /// Path of the `proc-debug` crate, added as a path dependency.
+    pub proc_debug_path: PathBuf,
+}
+
+/// Outcome of [`instrument_package`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstrumentReport {
+    /// Files modified by this call.
+    pub modified_files: Vec<PathBuf>,
+    /// Instrumented macros as `crate_name::fn_name`, which matches what the
+    /// library records for each invocation.
+    pub wrapped_macros: Vec<String>,
+    /// Instrumented macros which are only compiled under a `#[cfg(..)]`, with
+    /// the predicate, such as `feature = "derive"`. Predicates of enclosing
+    /// modules are combined with `all(..)`.
+    pub cfg_gated: Vec<(String, String)>,
+    /// Proc-macros which cannot be instrumented, such as those in a file
+    /// included with `include!`.
+    pub warnings: Vec<String>,
+}
+
+/// Attribute added to each proc-macro function.
+pub const ANNOTATION: &str = "::proc_debug::proc_debug";
+
+const REGISTRATIONS: [&str; 3] = ["proc_macro", "proc_macro_attribute", "proc_macro_derive"];
+
+/// Splits a `#[cfg_attr(predicate, attrs..)]` into its predicate and
+/// attributes.
+fn cfg_attr(attr: &syn::Attribute) -> Option<(String, Vec<syn::Meta>)> {
+    let syn::Meta::List(list) = &attr.meta else {
+        return None;
+    };
+    if !list.path.is_ident("cfg_attr") {
+        return None;
+    }
+    let mut metas = list
+        .parse_args_with(syn::punctuated::Punctuated::<syn::Meta, syn::Token![,]>::parse_terminated)
+        .ok()?
+        .into_iter();
+    let predicate = metas.next()?.to_token_stream().to_string();
+    Some((predicate, metas.collect()))
+}
+
+/// Tells whether `attrs` hold an attribute matching `is`, returning `None` if
+/// they do not, `Some(None)` if one applies unconditionally and
+/// `Some(Some(predicate))` if one is under a `cfg_attr`.
+fn find_attr(attrs: &[syn::Attribute], is: impl Fn(&syn::Path) -> bool) -> Option<Option<String>> {
+    attrs.iter().find_map(|attr| match cfg_attr(attr) {
+        Some((predicate, metas)) => metas
+            .iter()
+            .any(|meta| is(meta.path()))
+            .then_some(Some(predicate)),
+        None => is(attr.path()).then_some(None),
+    })
+}
+
+/// Lists the functions of `items` with an attribute matching `is`, named by
+/// their path from the file, with the predicate of the `cfg_attr` applying
+/// it, if any.
+fn collect_fns(
+    items: &[syn::Item],
+    prefix: &str,
+    is: &dyn Fn(&syn::Path) -> bool,
+    fns: &mut Vec<(String, Option<String>)>,
+) {
+    for item in items {
+        match item {
+            syn::Item::Fn(f) => {
+                if let Some(predicate) = find_attr(&f.attrs, is) {
+                    fns.push((format!("{prefix}{}", f.sig.ident), predicate));
+                }
+            }
+            syn::Item::Mod(syn::ItemMod {
+                ident,
+                content: Some((_, items)),
+                ..
+            }) => collect_fns(items, &format!("{prefix}{ident}::"), is, fns),
+            _ => (),
+        }
+    }
+}
+
+/// Lists the proc-macro functions of `content`, which are those with a
+/// registration attribute such as `#[proc_macro]`, possibly under a
+/// `cfg_attr`.
+fn registered_fns(content: &str) -> Result<Vec<(String, Option<String>)>> {
+    let file = syn::parse_file(content)?;
+    let mut fns = Vec::new();
+    let is = |path: &syn::Path| REGISTRATIONS.iter().any(|r| path.is_ident(r));
+    collect_fns(&file.items, "", &is, &mut fns);
+    Ok(fns)
+}
+
+#[test]
+fn test_validate_annotations() {
+    let original = r#"
+use proc_macro::TokenStream;
+#[proc_macro]
+pub fn used(input: TokenStream) -> TokenStream { helper(input) }
+#[cfg_attr(feature = "derive", proc_macro_derive(Gated, attributes(gated)))]
+pub fn gated(input: TokenStream) -> TokenStream { input }
+#[inline]
+fn helper(input: TokenStream) -> TokenStream { input }
+#[cfg_attr(test, allow(dead_code))]
+pub fn proc_macro_like(input: TokenStream) -> TokenStream { input }
+mod inner {
+    use proc_macro::TokenStream;
+    pub fn also_helper(input: TokenStream) -> TokenStream { input }
+}
+"#;
+    let modified = modify_rust_file(original.to_owned()).unwrap();
+    assert!(modified.contains(
+        "#[cfg_attr(feature = \"derive\", ::proc_debug::proc_debug)]\n\
+         #[cfg_attr(feature = \"derive\", proc_macro_derive(Gated, attributes(gated)))]"
+    ));
+    assert_eq!(
+        annotated_fns(&modified).unwrap(),
+        [
+            ("used".to_owned(), None),
+            ("gated".to_owned(), Some("feature = \"derive\"".to_owned()))
+        ]
+    );
+    validate_annotations(original, &modified).unwrap();
+    // A helper annotated by mistake, or an annotation injected elsewhere
+    let wrong = modified.replace("#[inline]", "#[::proc_debug::proc_debug]");
+    let err = validate_annotations(original, &wrong).unwrap_err();
+    assert!(err.to_string().contains("helper"), "{err}");
+    let decoy = "const HELP: &str = \"#[proc_macro]\";\n";
+    let err = validate_annotations(decoy, &modify_rust_file(decoy.to_owned()).unwrap());
+    assert!(err.is_err());
+}
+
+/// Lists the functions of `content` annotated with `#[proc_debug]`, as
+/// [`registered_fns`] does.
+fn annotated_fns(content: &str) -> Result<Vec<(String, Option<String>)>> {
+    let file = syn::parse_file(content)?;
+    let mut fns = Vec::new();
+    let is = |path: &syn::Path| path.to_token_stream().to_string().replace(' ', "") == ANNOTATION;
+    collect_fns(&file.items, "", &is, &mut fns);
+    Ok(fns)
+}
+
+/// Checks that the functions annotated in `modified` are exactly the
+/// proc-macro functions of `original`, under the same conditions, and that no
+/// annotation was written anywhere else, such as in a string.
+fn validate_annotations(original: &str, modified: &str) -> Result<()> {
+    let expected = registered_fns(original)?;
+    let annotated = annotated_fns(modified)?;
+    let written = modified.matches(ANNOTATION).count();
+    if annotated != expected || written != annotated.len() {
+        let names = |fns: &[(String, Option<String>)]| {
+            fns.iter()
+                .map(|(name, predicate)| match predicate {
+                    Some(predicate) => format!("{name} (if {predicate})"),
+                    None => name.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        anyhow::bail!(
+            "the annotations do not match the proc-macro functions: expected [{}], \
+             annotated [{}] with {written} annotation(s) written",
+            names(&expected),
+            names(&annotated)
+        );
+    }
+    Ok(())
+}
+
+/// Inserts `#[cfg_attr(predicate, ::proc_debug::proc_debug)]` before each
+/// registration attribute under a `cfg_attr`.
+fn annotate_cfg_attrs(content: &str) -> String {
+    let mut out = String::new();
+    let mut rest = content;
+    while let Some(start) = rest.find("#[cfg_attr") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let mut depth = 0;
+        let end = rest.char_indices().find_map(|(n, c)| {
+            match c {
+                '[' | '(' | '{' => depth += 1,
+                ']' | ')' | '}' => depth -= 1,
+                _ => (),
+            }
+            (depth == 0 && c == ']').then_some(n + 1)
+        });
+        let end = end.unwrap_or(rest.len());
+        let attr = syn::parse::Parser::parse_str(syn::Attribute::parse_outer, &rest[..end]);
+        if let Some(attr) = attr.ok().as_deref().and_then(|attrs| attrs.first()) {
+            if let Some(Some(predicate)) = find_attr(std::slice::from_ref(attr), |path| {
+                REGISTRATIONS.iter().any(|r| path.is_ident(r))
+            }) {
+                out.push_str(&format!("#[cfg_attr({predicate}, {ANNOTATION})]\n"));
+            }
+        }
+        out.push_str(&rest[..end]);
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[test]
+fn test_strip_comments() {
+    let source = "/// Résumé — déjà vu\n\
+        fn f<'a>(x: &'a str) -> char { /* nested /* comment */ here\n */ '/' }\n\
+        const S: &str = \"// not a comment\"; // comment\n\
+        const R: &str = r#\"/* \" */\"#; const C: char = '\\''; let r#type = b'\"';\n";
+    assert_eq!(
+        strip_comments(source).unwrap(),
+        "\n\
+         fn f<'a>(x: &'a str) -> char { \n '/' }\n\
+         const S: &str = \"// not a comment\"; \n\
+         const R: &str = r#\"/* \" */\"#; const C: char = '\\''; let r#type = b'\"';\n"
+    );
+    assert_eq!(
+        strip_comments("fn f() {}\n/* open /* nested */\n").unwrap_err(),
+        "unterminated block comment at line 2"
+    );
+    assert_eq!(
+        strip_comments("const S: &str = \"open;\n").unwrap_err(),
+        "unterminated string at line 1"
+    );
+}
+
+/// Returns the length of the string or character literal at the start of
+/// `tail`, or `None` if there is none, as for a lifetime or an identifier
+/// starting with `r`. Fails with the kind of literal if it is not closed.
+fn literal_len(tail: &str) -> std::result::Result<Option<usize>, &'static str> {
+    let prefix = usize::from(tail.starts_with(['b', 'c']));
+    let body = &tail[prefix..];
+    if let Some(raw) = body.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        if !raw[hashes..].starts_with('"') {
+            return Ok(None);
+        }
+        let start = prefix + 1 + hashes + 1;
+        let close = format!("\"{}", "#".repeat(hashes));
+        return match tail[start..].find(&close) {
+            Some(end) => Ok(Some(start + end + close.len())),
+            None => Err("raw string"),
+        };
+    }
+    let quote = match body.chars().next() {
+        Some(quote @ ('"' | '\'')) => quote,
+        _ => return Ok(None),
+    };
+    if quote == '\'' {
+        // A lifetime or a label, unless a character closed by a quote follows
+        let mut chars = body[1..].chars();
+        match (chars.next(), chars.next()) {
+            (Some('\\'), _) | (Some(_), Some('\'')) => (),
+            _ => return Ok(None),
+        }
+    }
+    let mut escaped = false;
+    for (n, c) in body.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == quote => return Ok(Some(prefix + n + 1)),
+            _ => (),
+        }
+    }
+    Err(if quote == '"' {
+        "string"
+    } else {
+        "character literal"
+    })
+}
+
+/// Removes the comments of the Rust source `content`, keeping the newlines of
+/// block comments so that lines keep their numbers. Fails on an unterminated
+/// comment or literal.
+fn strip_comments(content: &str) -> std::result::Result<String, String> {
+    let line = |at: usize| content[..at].matches('\n').count() + 1;
+    let mut out = String::with_capacity(content.len());
+    let mut at = 0;
+    while let Some(c) = content[at..].chars().next() {
+        let tail = &content[at..];
+        if tail.starts_with("//") {
+            at += tail.find('\n').unwrap_or(tail.len());
+            continue;
+        }
+        if tail.starts_with("/*") {
+            let (mut depth, mut n) = (0, 0);
+            loop {
+                let rest = &tail[n..];
+                if rest.starts_with("/*") {
+                    (depth, n) = (depth + 1, n + 2);
+                } else if rest.starts_with("*/") {
+                    (depth, n) = (depth - 1, n + 2);
+                    if depth == 0 {
+                        break;
+                    }
+                } else if let Some(c) = rest.chars().next() {
+                    if c == '\n' {
+                        out.push('\n');
+                    }
+                    n += c.len_utf8();
+                } else {
+                    return Err(format!("unterminated block comment at line {}", line(at)));
+                }
+            }
+            at += n;
+            continue;
+        }
+        // Prefixes of literals are only taken at the start of a token
+        let in_ident = out.ends_with(|c: char| c.is_alphanumeric() || c == '_');
+        if !in_ident || matches!(c, '"' | '\'') {
+            match literal_len(tail) {
+                Ok(Some(len)) => {
+                    out.push_str(&tail[..len]);
+                    at += len;
+                    continue;
+                }
+                Ok(None) => (),
+                Err(what) => return Err(format!("unterminated {what} at line {}", line(at))),
+            }
+        }
+        out.push(c);
+        at += c.len_utf8();
+    }
+    Ok(out)
+}
+
+/// Annotates the proc-macro functions of `content`. Comments are removed
+/// first, so that registration attributes in them are left alone, unless they
+/// cannot be, in which case `content` is annotated as is.
+fn modify_rust_file(content: String) -> Result<String> {
+    let content = strip_comments(&content).unwrap_or(content);
+    let content = annotate_cfg_attrs(&content);
+    let mut modified = Vec::new();
+    for line in content.lines() {
+        let line = line.replace(
+            "#[proc_macro]",
+            "#[::proc_debug::proc_debug]\n#[proc_macro]",
+        );
+        let line = line.replace(
+            "#[proc_macro_attribute]",
+            "#[::proc_debug::proc_debug]\n#[proc_macro_attribute]",
+        );
+        let line = line.replace(
+            "#[proc_macro_derive",
+            "#[::proc_debug::proc_debug]\n#[proc_macro_derive",
+        );
+        modified.push(line);
+    }
+    Ok(modified.join("\n"))
+}
+
+/// Adds the dependency on proc-debug at `lib_path`, with the `serde` feature
+/// which `--format json` requires.
+fn modify_toml_file(content: String, lib_path: &Path) -> Result<String> {
+    if content.find("proc-debug").is_some() {
+        Ok(content)
+    } else {
+        Ok(format!(
+            "{content}\n\n[dependencies.proc-debug]\npath = \"{}\"\nfeatures = [\"serde\"]",
+            lib_path.to_str().unwrap()
+        ))
+    }
+}
+
+/// File keeping the original of `path` while it is instrumented.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let fname = path.file_name().unwrap().to_str().unwrap();
+    path.with_file_name(format!("{fname}{BACKUP_SUFFIX}"))
+}
+
+/// File holding the sha256 of `path` as modified, to tell whether it was
+/// edited before restoring it.
+fn hash_path(path: &Path) -> PathBuf {
+    let fname = path.file_name().unwrap().to_str().unwrap();
+    path.with_file_name(format!("{fname}{HASH_SUFFIX}"))
+}
+
+/// Backs up and modifies `path`, recording the hash of the modified file. A
+/// file which already has a backup is refused, since its backup would be
+/// overwritten.
+fn backup_and_modify(path: &Path, f: impl FnOnce(String) -> Result<String>) -> Result<()> {
+    let bak_path = backup_path(path);
+    if bak_path.exists() {
+        anyhow::bail!("{} is already instrumented", path.display());
+    }
+    let content = String::from_utf8(std::fs::read(path)?)?;
+    let modified = f(content)?;
+    std::fs::write(hash_path(path), sha256(&modified))?;
+    std::fs::rename(path, &bak_path)?;
+    if let Err(e) = std::fs::write(path, modified) {
+        std::fs::rename(&bak_path, path)?;
+        Err(e)?;
+    }
+    Ok(())
+}
+
+fn sha256(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// Outcome of [`restore_entry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreOutcome {
+    /// The backup replaced the modified file.
+    Restored,
+    /// The modified file was missing, and the backup was put back in its
+    /// place.
+    RestoredMissing,
+    /// There is no backup, so the file is not modified.
+    NotModified,
+    /// The modified file was edited since it was written, so it and its
+    /// backup are left as they are, for the user to pick one.
+    Edited,
+    /// The directory of the file no longer exists, as when cargo removed a
+    /// registry source, so there is nothing left to restore.
+    Gone,
+}
+
+#[test]
+fn test_restore_entry() {
+    let dir = std::env::temp_dir().join(format!("proc-debug-restore-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("lib.rs");
+    std::fs::write(&path, "original").unwrap();
+    assert_eq!(restore_entry(&path).unwrap(), RestoreOutcome::NotModified);
+    backup_and_modify(&path, |_| Ok("modified".to_owned())).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "modified");
+    assert_eq!(restore_entry(&path).unwrap(), RestoreOutcome::Restored);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "original");
+    assert_eq!(restore_entry(&path).unwrap(), RestoreOutcome::NotModified);
+    backup_and_modify(&path, |_| Ok("modified".to_owned())).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        restore_entry(&path).unwrap(),
+        RestoreOutcome::RestoredMissing
+    );
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "original");
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(restore_entry(&path).unwrap(), RestoreOutcome::Gone);
+    // Edits of the modified file are not discarded
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(&path, "original").unwrap();
+    backup_and_modify(&path, |_| Ok("modified".to_owned())).unwrap();
+    std::fs::write(&path, "modified and edited").unwrap();
+    assert_eq!(restore_entry(&path).unwrap(), RestoreOutcome::Edited);
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "modified and edited"
+    );
+    assert_eq!(
+        std::fs::read_to_string(backup_path(&path)).unwrap(),
+        "original"
+    );
+    std::fs::write(&path, "modified").unwrap();
+    assert_eq!(restore_entry(&path).unwrap(), RestoreOutcome::Restored);
+    assert!(!hash_path(&path).exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Puts back the backup of `path` made by [`instrument_package`]. Restoring
+/// is idempotent: a file without a backup, or whose directory was removed, is
+/// left as is. So is a file whose hash differs from the one recorded when it
+/// was modified, whose edits would be lost.
+pub fn restore_entry(path: &Path) -> std::io::Result<RestoreOutcome> {
+    if !path.parent().is_some_and(Path::exists) {
+        return Ok(RestoreOutcome::Gone);
+    }
+    let bak_path = backup_path(path);
+    if !bak_path.exists() {
+        return Ok(RestoreOutcome::NotModified);
+    }
+    let hash_path = hash_path(path);
+    let outcome = if path.exists() {
+        if let Ok(recorded) = std::fs::read_to_string(&hash_path) {
+            let content = std::fs::read(path)?;
+            if String::from_utf8(content).map(|c| sha256(&c)).ok() != Some(recorded) {
+                return Ok(RestoreOutcome::Edited);
+            }
+        }
+        std::fs::remove_file(path)?;
+        RestoreOutcome::Restored
+    } else {
+        RestoreOutcome::RestoredMissing
+    };
+    std::fs::rename(bak_path, path)?;
+    let _ = std::fs::remove_file(hash_path);
+    Ok(outcome)
+}
+
+#[test]
+fn test_wrapped_fns() {
+    let content = modify_rust_file(
+        r#"
+use proc_macro::TokenStream;
+#[proc_macro]
+pub fn used(input: TokenStream) -> TokenStream { input }
+#[proc_macro_derive(Unused)]
+pub fn derive_unused(input: TokenStream) -> TokenStream { input }
+fn helper(input: TokenStream) -> TokenStream { input }
+"#
+        .to_owned(),
+    )
+    .unwrap();
+    assert_eq!(wrapped_fns(&content), vec!["used", "derive_unused"]);
+}
+
+/// Lists names of the functions annotated by [`modify_rust_file`].
+fn wrapped_fns(content: &str) -> Vec<String> {
+    content
+        .split(ANNOTATION)
+        .skip(1)
+        .filter_map(|s| {
+            let s = &s[s.find("fn ")? + 3..];
+            let end = s.find(|c: char| !c.is_alphanumeric() && c != '_')?;
+            Some(s[..end].trim().to_owned())
+        })
+        .collect()
+}
+
+#[test]
+fn test_cfg_gated_fns() {
+    let content = modify_rust_file(
+        r#"
+#[cfg(feature = "derive")]
+#[proc_macro_derive(A)]
+pub fn derive_a(input: TokenStream) -> TokenStream { input }
+#[proc_macro]
+pub fn b(input: TokenStream) -> TokenStream { input }
+#[cfg_attr(feature = "attr", proc_macro_attribute)]
+pub fn d(attr: TokenStream, item: TokenStream) -> TokenStream { item }
+#[cfg(not(docsrs))]
+mod inner {
+    #[cfg(unix)]
+    #[proc_macro]
+    pub fn c(input: TokenStream) -> TokenStream { input }
+}
+"#
+        .to_owned(),
+    )
+    .unwrap();
+    assert_eq!(
+        cfg_gated_fns(&content),
+        vec![
+            ("derive_a".to_owned(), "feature = \"derive\"".to_owned()),
+            ("d".to_owned(), "feature = \"attr\"".to_owned()),
+            ("c".to_owned(), "all(not (docsrs), unix)".to_owned()),
+        ]
+    );
+}
+
+fn cfg_predicates(attrs: &[syn::Attribute]) -> impl Iterator<Item = String> + '_ {
+    attrs.iter().filter_map(|attr| match &attr.meta {
+        syn::Meta::List(list) if list.path.is_ident("cfg") => Some(list.tokens.to_string()),
+        _ => None,
+    })
+}
+
+fn collect_cfg_gated(items: &[syn::Item], outer: &[String], gated: &mut Vec<(String, String)>) {
+    for item in items {
+        match item {
+            syn::Item::Fn(f) => {
+                let Some(annotation) = find_attr(&f.attrs, |path| {
+                    path.segments.last().unwrap().ident == "proc_debug"
+                }) else {
+                    continue;
+                };
+                let cfgs = outer
+                    .iter()
+                    .cloned()
+                    .chain(cfg_predicates(&f.attrs))
+                    .chain(annotation)
+                    .collect::<Vec<_>>();
+                match cfgs.len() {
+                    0 => (),
+                    1 => gated.push((f.sig.ident.to_string(), cfgs[0].clone())),
+                    _ => gated.push((f.sig.ident.to_string(), format!("all({})", cfgs.join(", ")))),
+                }
+            }
+            syn::Item::Mod(m) => {
+                if let Some((_, items)) = &m.content {
+                    let outer = outer
+                        .iter()
+                        .cloned()
+                        .chain(cfg_predicates(&m.attrs))
+                        .collect::<Vec<_>>();
+                    collect_cfg_gated(items, &outer, gated);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Lists functions annotated by [`modify_rust_file`] which are under a
+/// `#[cfg(..)]`, with its predicate. Nothing is listed if the file does not
+/// parse.
+fn cfg_gated_fns(content: &str) -> Vec<(String, String)> {
+    let mut gated = Vec::new();
+    if let Ok(file) = syn::parse_file(content) {
+        let outer = cfg_predicates(&file.attrs).collect::<Vec<_>>();
+        collect_cfg_gated(&file.items, &outer, &mut gated);
+    }
+    gated
+}
+
+#[test]
+fn test_cfg_enabled() {
+    let features = ["derive".to_owned(), "std".to_owned()];
+    let enabled = |predicate: &str| cfg_enabled(predicate, &features);
+    assert_eq!(enabled("feature = \"derive\""), Some(true));
+    assert_eq!(enabled("feature = \"serde\""), Some(false));
+    assert_eq!(enabled("not(feature = \"serde\")"), Some(true));
+    assert_eq!(enabled("docsrs"), None);
+    assert_eq!(enabled("not(docsrs)"), None);
+    assert_eq!(enabled("target_os = \"linux\""), None);
+    assert_eq!(
+        enabled("all(feature = \"derive\", feature = \"std\")"),
+        Some(true)
+    );
+    assert_eq!(enabled("all(feature = \"derive\", docsrs)"), None);
+    assert_eq!(enabled("all(feature = \"serde\", docsrs)"), Some(false));
+    assert_eq!(
+        enabled("any(feature = \"serde\", feature = \"std\")"),
+        Some(true)
+    );
+    assert_eq!(enabled("any(feature = \"serde\", docsrs)"), None);
+    assert_eq!(
+        enabled("any(feature = \"serde\", feature = \"alloc\")"),
+        Some(false)
+    );
+    assert_eq!(enabled("all()"), Some(true));
+    assert_eq!(enabled("any()"), Some(false));
+    assert_eq!(enabled("not(any(feature = \"derive\", unix))"), Some(false));
+    assert_eq!(enabled("feature ="), None);
+}
+
+/// Predicts whether the `#[cfg(..)]` `predicate` holds with `features`
+/// enabled. Only `feature = ".."` is known, so predicates depending on any
+/// other cfg, which would need the target and the flags of the build, give
+/// `None` unless their outcome does not depend on it.
+pub fn cfg_enabled(predicate: &str, features: &[String]) -> Option<bool> {
+    fn eval(meta: &syn::Meta, features: &[String]) -> Option<bool> {
+        match meta {
+            syn::Meta::NameValue(nv) if nv.path.is_ident("feature") => match &nv.value {
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(s),
+                    ..
+                }) => Some(features.contains(&s.value())),
+                _ => None,
+            },
+            syn::Meta::List(list) => {
+                let args = list
+                    .parse_args_with(
+                        syn::punctuated::Punctuated::<syn::Meta, syn::Token![,]>::parse_terminated,
+                    )
+                    .ok()?;
+                let mut values = args.iter().map(|m| eval(m, features));
+                if list.path.is_ident("not") && args.len() == 1 {
+                    values.next().unwrap().map(|v| !v)
+                } else if list.path.is_ident("all") {
+                    values
+                        .try_fold(Some(true), |acc, v| match (acc, v) {
+                            (_, Some(false)) => Err(()),
+                            (Some(true), Some(true)) => Ok(Some(true)),
+                            _ => Ok(None),
+                        })
+                        .unwrap_or(Some(false))
+                } else if list.path.is_ident("any") {
+                    values
+                        .try_fold(Some(false), |acc, v| match (acc, v) {
+                            (_, Some(true)) => Err(()),
+                            (Some(false), Some(false)) => Ok(Some(false)),
+                            _ => Ok(None),
+                        })
+                        .unwrap_or(Some(true))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+    eval(&syn::parse_str(predicate).ok()?, features)
+}
+
+/// Lists the files included with `include!` at the top of `content`, as
+/// written, or `None` for an argument which is not a string literal, such as
+/// `concat!(env!("OUT_DIR"), ..)`.
+fn included_files(content: &str) -> Vec<Option<String>> {
+    let Ok(file) = syn::parse_file(content) else {
+        return Vec::new();
+    };
+    file.items
+        .iter()
+        .filter_map(|item| match item {
+            syn::Item::Macro(m) if m.mac.path.is_ident("include") => Some(
+                m.mac
+                    .parse_body::<syn::LitStr>()
+                    .ok()
+                    .map(|path| path.value()),
+            ),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Warns about the proc-macros of the files which `content` of `src_path`
+/// includes with `include!`. Proc-macros must be at the root of the crate, so
+/// they cannot be elsewhere, but the lines of an included file cannot be
+/// patched in place as the crate root is.
+fn include_warnings(src_path: &Path, content: &str) -> Vec<String> {
+    included_files(content)
+        .into_iter()
+        .filter_map(|included| {
+            let Some(included) = included else {
+                return Some(format!(
+                    "{} includes a file which cannot be resolved, proc-macros in it are not \
+                     instrumented",
+                    src_path.display()
+                ));
+            };
+            let path = src_path.parent()?.join(&included);
+            let fns = registered_fns(&std::fs::read_to_string(&path).ok()?).ok()?;
+            (!fns.is_empty()).then(|| {
+                let names = fns.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+                format!(
+                    "{} is included with include!, so its proc-macros are not instrumented: {}",
+                    path.display(),
+                    names.join(", ")
+                )
+            })
+        })
+        .collect()
+}
+
+/// Library target of a package, read from its manifest.
+struct LibTarget {
+    src_path: PathBuf,
+    crate_name: String,
+}
+
+fn str_field<'a>(table: Option<&'a toml::Table>, key: &str) -> Option<&'a str> {
+    table?.get(key)?.as_str()
+}
+
+fn lib_target(root: &Path) -> Result<LibTarget> {
+    let manifest_path = root.join("Cargo.toml");
+    let manifest: toml::Table = std::fs::read_to_string(&manifest_path)?.parse()?;
+    let lib = manifest.get("lib").and_then(|l| l.as_table());
+    let src_path = str_field(lib, "path").unwrap_or("src/lib.rs");
+    let crate_name = match str_field(lib, "name") {
+        Some(name) => name.to_owned(),
+        None => str_field(manifest.get("package").and_then(|p| p.as_table()), "name")
+            .ok_or_else(|| anyhow::anyhow!("no package name in {}", manifest_path.display()))?
+            .replace('-', "_"),
+    };
+    // The source may be missing while it is being restored.
+    let src_path = root.join(src_path);
+    Ok(LibTarget {
+        src_path: src_path.canonicalize().unwrap_or(src_path),
+        crate_name,
+    })
+}
+
+#[test]
+fn test_instrument_package() {
+    let root = std::env::temp_dir().join(format!("proc-debug-instrument-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("macros")).unwrap();
+    let manifest =
+        "[package]\nname = \"my-macros\"\n\n[lib]\nproc-macro = true\npath = \"macros/lib.rs\"\n";
+    let source = "use proc_macro::TokenStream;\n\
+        #[proc_macro_attribute]\n\
+        pub fn my_attr(_: TokenStream, item: TokenStream) -> TokenStream { item }\n";
+    std::fs::write(root.join("Cargo.toml"), manifest).unwrap();
+    std::fs::write(root.join("macros/lib.rs"), source).unwrap();
+    let opts = InstrumentOptions {
+        proc_debug_path: PathBuf::from("/opt/proc-debug"),
+    };
+    let report = instrument_package(&root, &opts).unwrap();
+    assert_eq!(report.wrapped_macros, vec!["my_macros::my_attr"]);
+    assert_eq!(report.modified_files.len(), 2);
+    let modified = std::fs::read_to_string(root.join("Cargo.toml")).unwrap();
+    assert!(modified.ends_with(
+        "[dependencies.proc-debug]\npath = \"/opt/proc-debug\"\nfeatures = [\"serde\"]"
+    ));
+    // Already instrumented files are not modified twice.
+    let again = instrument_package(&root, &opts).unwrap();
+    assert!(again.modified_files.is_empty());
+    assert_eq!(again.wrapped_macros, report.wrapped_macros);
+    let restored = restore_package(&root).unwrap();
+    assert!(restored.iter().all(|(_, o)| *o == RestoreOutcome::Restored));
+    assert_eq!(
+        std::fs::read_to_string(root.join("Cargo.toml")).unwrap(),
+        manifest
+    );
+    assert_eq!(
+        std::fs::read_to_string(root.join("macros/lib.rs")).unwrap(),
+        source
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+    let restored = restore_package(&root).unwrap();
+    assert_eq!(restored[0].1, RestoreOutcome::Gone);
+}
+
+#[test]
+fn test_unstrippable_comments() {
+    let root = std::env::temp_dir().join(format!("proc-debug-comments-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("Cargo.toml"),
+        "[package]\nname = \"my-macros\"\n\n[lib]\nproc-macro = true\npath = \"lib.rs\"\n",
+    )
+    .unwrap();
+    let opts = InstrumentOptions {
+        proc_debug_path: PathBuf::from("/opt/proc-debug"),
+    };
+    // Non-ASCII text before a comment is kept as is
+    let source = "/// Déjà vu\n/* note */\n\
+        #[proc_macro]\n\
+        pub fn my_fn(item: TokenStream) -> TokenStream { item }\n";
+    std::fs::write(root.join("lib.rs"), source).unwrap();
+    let plan = plan_package(&root, &opts).unwrap();
+    assert_eq!(plan.wrapped_macros, ["my_macros::my_fn"]);
+    assert_eq!(plan.warnings, Vec::<String>::new());
+    // Comments which cannot be removed are left, with a warning
+    let source = "#[proc_macro]\n\
+        pub fn my_fn(item: TokenStream) -> TokenStream { item }\n\
+        /* open\n";
+    std::fs::write(root.join("lib.rs"), source).unwrap();
+    let plan = plan_package(&root, &opts).unwrap();
+    assert_eq!(plan.wrapped_macros, ["my_macros::my_fn"]);
+    assert_eq!(
+        plan.warnings,
+        [format!(
+            "cannot remove the comments of {} (unterminated block comment at line 3), \
+             instrumenting it with them",
+            root.canonicalize().unwrap().join("lib.rs").display()
+        )]
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_relocated_sources() {
+    let root = std::env::temp_dir().join(format!("proc-debug-relocated-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("macros/impls")).unwrap();
+    let manifest =
+        "[package]\nname = \"my-macros\"\n\n[lib]\nproc-macro = true\npath = \"macros/lib.rs\"\n";
+    let source = "use proc_macro::TokenStream;\n\
+        #[path = \"impls/helpers.rs\"]\n\
+        mod helpers;\n\
+        include!(\"generated.rs\");\n\
+        include!(concat!(env!(\"OUT_DIR\"), \"/more.rs\"));\n\
+        #[proc_macro]\n\
+        pub fn my_fn(item: TokenStream) -> TokenStream { helpers::expand(item) }\n";
+    let helpers =
+        "pub fn expand(item: proc_macro::TokenStream) -> proc_macro::TokenStream { item }\n";
+    let generated = "#[proc_macro_derive(Generated)]\n\
+        pub fn generated(item: TokenStream) -> TokenStream { item }\n";
+    std::fs::write(root.join("Cargo.toml"), manifest).unwrap();
+    std::fs::write(root.join("macros/lib.rs"), source).unwrap();
+    std::fs::write(root.join("macros/impls/helpers.rs"), helpers).unwrap();
+    std::fs::write(root.join("macros/generated.rs"), generated).unwrap();
+    let opts = InstrumentOptions {
+        proc_debug_path: PathBuf::from("/opt/proc-debug"),
+    };
+    // The root found through `[lib] path` is instrumented, and the module it
+    // relocates with `#[path]` holds no proc-macro to instrument
+    let plan = plan_package(&root, &opts).unwrap();
+    assert_eq!(plan.wrapped_macros, ["my_macros::my_fn"]);
+    let files = plan
+        .files
+        .iter()
+        .map(|f| f.path.strip_prefix(&root).unwrap().to_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        files,
+        [PathBuf::from("macros/lib.rs"), PathBuf::from("Cargo.toml")]
+    );
+    let lib = root.canonicalize().unwrap().join("macros");
+    assert_eq!(
+        plan.warnings,
+        [
+            format!(
+                "{} is included with include!, so its proc-macros are not instrumented: generated",
+                lib.join("generated.rs").display()
+            ),
+            format!(
+                "{} includes a file which cannot be resolved, proc-macros in it are not \
+                 instrumented",
+                lib.join("lib.rs").display()
+            ),
+        ]
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_plan_package() {
+    let root = std::env::temp_dir().join(format!("proc-debug-plan-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("src")).unwrap();
+    let manifest = "[package]\nname = \"my-macros\"\n\n[lib]\nproc-macro = true\n";
+    let source = "use proc_macro::TokenStream;\n\
+        #[proc_macro]\n\
+        pub fn my_fn(item: TokenStream) -> TokenStream { item }\n";
+    std::fs::write(root.join("Cargo.toml"), manifest).unwrap();
+    std::fs::write(root.join("src/lib.rs"), source).unwrap();
+    let opts = InstrumentOptions {
+        proc_debug_path: PathBuf::from("/opt/proc-debug"),
+    };
+    // Planning writes nothing, and the plan survives a round trip through
+    // JSON.
+    let plan = plan_package(&root, &opts).unwrap();
+    assert_eq!(plan.files.len(), 2);
+    assert!(plan.files[0]
+        .diff
+        .contains("+#[::proc_debug::proc_debug]\n #[proc_macro]\n"));
+    assert_eq!(
+        std::fs::read_to_string(root.join("src/lib.rs")).unwrap(),
+        source
+    );
+    let json = Plan {
+        packages: vec![plan],
+    }
+    .to_json();
+    let plan = Plan::from_json(&json).unwrap().packages.remove(0);
+    // Applying it gives the same files as instrumenting directly.
+    apply_package(&plan).unwrap();
+    let applied = std::fs::read_to_string(root.join("src/lib.rs")).unwrap();
+    restore_package(&root).unwrap();
+    instrument_package(&root, &opts).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(root.join("src/lib.rs")).unwrap(),
+        applied
+    );
+    restore_package(&root).unwrap();
+    // A file which changed since the plan is refused, and nothing is left
+    // modified.
+    std::fs::write(root.join("Cargo.toml"), format!("{manifest}# edited\n")).unwrap();
+    let err = apply_package(&plan).unwrap_err().to_string();
+    assert!(err.contains("changed since the plan was made"), "{err}");
+    assert_eq!(
+        std::fs::read_to_string(root.join("src/lib.rs")).unwrap(),
+        source
+    );
+    assert!(!backup_path(&root.join("src/lib.rs")).exists());
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+/// A file to modify, as planned by [`plan_package`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilePlan {
+    pub path: PathBuf,
+    /// SHA-256 of the file before modification, in hex.
+    pub sha256: String,
+    /// Unified diff to apply to the file.
+    pub diff: String,
+}
+
+/// Modifications of a package, computed by [`plan_package`] and applied by
+/// [`apply_package`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackagePlan {
+    pub root: PathBuf,
+    /// Files to modify, which excludes those already instrumented.
+    pub files: Vec<FilePlan>,
+    /// See [`InstrumentReport::wrapped_macros`].
+    pub wrapped_macros: Vec<String>,
+    /// See [`InstrumentReport::cfg_gated`].
+    pub cfg_gated: Vec<(String, String)>,
+    /// See [`InstrumentReport::warnings`].
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+/// Plans of several packages, as written by `cargo proc-debug plan`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Plan {
+    pub packages: Vec<PackagePlan>,
+}
+
+impl Plan {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+/// Computes the modifications of the package whose `Cargo.toml` is in `root`,
+/// without writing anything.
+pub fn plan_package(root: &Path, opts: &InstrumentOptions) -> Result<PackagePlan> {
+    let lib = lib_target(root)?;
+    let mut files = Vec::new();
+    let mut plan_file = |path: &Path, f: &dyn Fn(String) -> Result<String>| -> Result<String> {
+        let content = String::from_utf8(std::fs::read(path)?)?;
+        if backup_path(path).exists() {
+            return Ok(content);
+        }
+        let modified = f(content.clone())?;
+        let diff = diff::unified_diff(&path.display().to_string(), &content, &modified);
+        if !diff.is_empty() {
+            files.push(FilePlan {
+                path: path.to_owned(),
+                sha256: sha256(&content),
+                diff,
+            });
+        }
+        Ok(modified)
+    };
+    let content = plan_file(&lib.src_path, &modify_rust_file)?;
+    let mut warnings = include_warnings(&lib.src_path, &content);
+    if let Some(Err(e)) = (!backup_path(&lib.src_path).exists())
+        .then(|| std::fs::read_to_string(&lib.src_path).ok())
+        .flatten()
+        .map(|source| strip_comments(&source))
+    {
+        warnings.push(format!(
+            "cannot remove the comments of {} ({e}), instrumenting it with them",
+            lib.src_path.display()
+        ));
+    }
+    plan_file(&root.join("Cargo.toml"), &|content| {
+        modify_toml_file(content, &opts.proc_debug_path)
+    })?;
+    Ok(PackagePlan {
+        root: root.to_owned(),
+        files,
+        wrapped_macros: wrapped_fns(&content)
+            .into_iter()
+            .map(|f| format!("{}::{f}", lib.crate_name))
+            .collect(),
+        cfg_gated: cfg_gated_fns(&content)
+            .into_iter()
+            .map(|(f, predicate)| (format!("{}::{f}", lib.crate_name), predicate))
+            .collect(),
+        warnings,
+    })
+}
+
+/// Applies `plan`, refusing to modify a file which changed since the plan was
+/// made.
+///
+/// If a step fails, the files modified so far are restored before returning
+/// the error.
+pub fn apply_package(plan: &PackagePlan) -> Result<InstrumentReport> {
+    let mut report = InstrumentReport {
+        modified_files: Vec::new(),
+        wrapped_macros: plan.wrapped_macros.clone(),
+        cfg_gated: plan.cfg_gated.clone(),
+        warnings: plan.warnings.clone(),
+    };
+    let result = plan.files.iter().try_for_each(|file| {
+        backup_and_modify(&file.path, |content| {
+            let actual = sha256(&content);
+            if actual != file.sha256 {
+                anyhow::bail!(
+                    "{} changed since the plan was made (sha256 {actual}, planned {})",
+                    file.path.display(),
+                    file.sha256
+                );
+            }
+            let modified = diff::apply(&content, &file.diff)?;
+            if file.path.extension().is_some_and(|e| e == "rs") {
+                validate_annotations(&content, &modified).map_err(|e| {
+                    anyhow::anyhow!("not instrumenting {}: {e}", file.path.display())
+                })?;
+            }
+            Ok(modified)
+        })?;
+        report.modified_files.push(file.path.clone());
+        Ok(())
+    });
+    if let Err(e) = result {
+        for p in &report.modified_files {
+            let _ = restore_entry(p);
+        }
+        return Err(e);
+    }
+    Ok(report)
+}
+
+/// Instruments the package whose `Cargo.toml` is in `root`, by applying the
+/// plan of [`plan_package`].
+///
+/// If a step fails, the files modified so far are restored before returning
+/// the error.
+pub fn instrument_package(root: &Path, opts: &InstrumentOptions) -> Result<InstrumentReport> {
+    apply_package(&plan_package(root, opts)?)
+}
+
+/// Restores the files of the package in `root` which were modified by
+/// [`instrument_package`], returning each of them with its outcome.
+///
+/// The manifest is restored first, so that the library target is found even
+/// if the manifest was left modified or missing. A package whose directory
+/// was removed is considered restored.
+pub fn restore_package(root: &Path) -> Result<Vec<(PathBuf, RestoreOutcome)>> {
+    let manifest_path = root.join("Cargo.toml");
+    let manifest = restore_entry(&manifest_path)?;
+    if manifest == RestoreOutcome::Gone {
+        return Ok(vec![(manifest_path, manifest)]);
+    }
+    let src_path = lib_target(root)?.src_path;
+    let src = restore_entry(&src_path)?;
+    Ok(vec![(src_path, src), (manifest_path, manifest)])
+}
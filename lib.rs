@@ -1,33 +1,46 @@
 #![doc = include_str!("README.md")]
 
 use argp::FromArgs;
-use bat::PrettyPrinter;
 
 /// See module-level documentation
 pub use proc_debug_macro::proc_debug;
 use proc_macro2::{TokenStream, TokenTree};
 use quote::{quote, quote_spanned, ToTokens};
 use std::collections::VecDeque;
-use std::{io::Write, str::FromStr};
+use std::{
+    io::{IsTerminal, Write},
+    str::FromStr,
+};
 use syn::*;
-use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
-
-fn print<R>(f: impl FnOnce(&mut StandardStream) -> R) -> R {
-    let mut stdout = StandardStream::stdout(ColorChoice::Always);
-    stdout
-        .set_color(
-            ColorSpec::new()
-                .set_bg(Some(Color::Cyan))
-                .set_fg(Some(Color::Black))
-                .set_bold(true),
-        )
-        .unwrap();
-    f(&mut stdout)
-}
+use termcolor::{ColorChoice, StandardStream, WriteColor};
+
+mod budget;
+mod capability;
+mod clipboard;
+mod config;
+mod display;
+mod docs;
+mod explain;
+mod export;
+mod meta;
+mod paths;
+mod repeats;
+mod rules;
+mod sink;
+mod summary;
+
+use config::Config;
+use display::{ColorMode, DisplayContext, Held, Outcome, Palette};
+pub use export::MacroRecord;
+use export::{Destinations, Format};
+pub use sink::{set_sink, take_sink, ExpansionSink, VecSink};
 
 enum MacroOutput {
     Expr(Expr),
     Type(Type),
+    Lifetime(Lifetime),
+    WhereClause(WhereClause),
+    Generics(Generics),
     ImplItem(Vec<ImplItem>),
     TraitItem(Vec<TraitItem>),
     ForeignItem(Vec<ForeignItem>),
@@ -57,11 +70,35 @@ impl FromStr for MacroKind {
     }
 }
 
+impl MacroKind {
+    /// Parses the argument of `--kind`, which is never `Other`.
+    fn parse_filter(s: &str) -> std::result::Result<Self, String> {
+        match Self::from_str(s) {
+            Ok(Self::Other) | Err(_) => Err(format!(
+                "unknown macro kind `{s}`, expected one of: function, attribute, derive"
+            )),
+            Ok(kind) => Ok(kind),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Function => "function",
+            Self::Attribute => "attribute",
+            Self::Derive => "derive",
+            Self::Other => "unknown",
+        }
+    }
+}
+
 impl ToTokens for MacroOutput {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let rhs = match self {
             MacroOutput::Expr(expr) => quote!(#expr),
             MacroOutput::Type(ty) => quote!(#ty),
+            MacroOutput::Lifetime(lifetime) => quote!(#lifetime),
+            MacroOutput::WhereClause(clause) => quote!(#clause),
+            MacroOutput::Generics(generics) => quote!(#generics),
             MacroOutput::ImplItem(o) => quote!(#(#o)*),
             MacroOutput::ForeignItem(o) => quote!(#(#o)*),
             MacroOutput::TraitItem(o) => quote!(#(#o)*),
@@ -73,17 +110,158 @@ impl ToTokens for MacroOutput {
     }
 }
 
-fn simplify_and_replace(tokens: TokenStream, depth: usize) -> TokenStream {
-    let mut out = TokenStream::new();
+/// Counts token trees, including groups and everything nested in them.
+fn count_tokens(tokens: TokenStream) -> usize {
+    tokens
+        .into_iter()
+        .map(|token| match token {
+            TokenTree::Group(g) => 1 + count_tokens(g.stream()),
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Counts the levels of groups nested in `tokens`, 0 when there is none.
+fn max_group_depth(tokens: TokenStream) -> usize {
+    tokens
+        .into_iter()
+        .map(|token| match token {
+            TokenTree::Group(g) => 1 + max_group_depth(g.stream()),
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+#[test]
+fn test_output_stats() {
+    let stats = |s: &str, kind: &MacroKind| {
+        let tokens = TokenStream::from_str(s).unwrap();
+        output_stats(&tokens, &MacroOutput::from_tokens(tokens.clone(), kind))
+    };
+    assert_eq!(
+        stats("struct A; fn f() { g(h(1)) }", &MacroKind::Attribute),
+        "12 tokens, group depth 3, 2 Items"
+    );
+    assert_eq!(
+        stats("Vec<u8>", &MacroKind::Function),
+        "4 tokens, group depth 0, 1 Type"
+    );
+    assert_eq!(
+        stats("impl", &MacroKind::Function),
+        "1 token, group depth 0"
+    );
+    assert_eq!(count_tokens(TokenStream::new()), 0);
+    assert_eq!(max_group_depth(TokenStream::new()), 0);
+}
+
+/// Describes the size of the full output `ret`, parsed as `output`, for
+/// `--stats`: its tokens, the depth of its groups and its top-level parts.
+fn output_stats(ret: &TokenStream, output: &MacroOutput) -> String {
+    let tokens = count_tokens(ret.clone());
+    let plural = if tokens == 1 { "" } else { "s" };
+    let mut stats = format!(
+        "{tokens} token{plural}, group depth {}",
+        max_group_depth(ret.clone())
+    );
+    if let Some(len) = output.len() {
+        let plural = if len == 1 { "" } else { "s" };
+        stats += &format!(", {len} {}{plural}", output.name());
+    }
+    stats
+}
+
+/// Depth used when neither `-d` nor `-v` is given.
+const DEFAULT_DEPTH: usize = 4;
+
+/// Upper bound of the depth chosen by [`auto_depth`].
+const MAX_AUTO_DEPTH: usize = 64;
+
+/// Counts the tokens nested deeper than `depth`, which [`simplify_and_replace`]
+/// omits regardless of the number of statements.
+fn count_nested_below(tokens: TokenStream, depth: usize) -> usize {
     if depth == 0 {
-        out.extend(quote!(__proc_debug_ellipsis! {}));
+        return count_tokens(tokens);
+    }
+    tokens
+        .into_iter()
+        .map(|token| match token {
+            TokenTree::Group(g) => count_nested_below(g.stream(), depth - 1),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[test]
+fn test_auto_depth() {
+    let stmts = (0..50).map(|i| quote!(let a = #i;));
+    let nested = quote!(fn f() { { { { { #(#stmts)* } } } } });
+    assert_eq!(auto_depth(&nested, 4), (8, false));
+    let normal = quote!(struct A; impl A { fn f() { let a = 1; } });
+    assert_eq!(auto_depth(&normal, 4), (4, false));
+    let stmts = (0..500).map(|i| quote!(let a = #i;));
+    let deep = (0..100).fold(quote!(#(#stmts)*), |acc, _| quote!({ #acc }));
+    assert_eq!(auto_depth(&deep, 4), (MAX_AUTO_DEPTH, true));
+}
+
+/// Starting from `depth`, doubles the depth while more than 95% of `tokens`
+/// would be hidden by nesting, up to [`MAX_AUTO_DEPTH`]. Returns the depth and
+/// whether most of the tokens are still hidden at it.
+fn auto_depth(tokens: &TokenStream, depth: usize) -> (usize, bool) {
+    let total = count_tokens(tokens.clone());
+    let hidden = |depth| count_nested_below(tokens.clone(), depth) * 100 > total * 95;
+    let mut depth = depth;
+    while hidden(depth) && depth < MAX_AUTO_DEPTH {
+        depth = (depth * 2).clamp(1, MAX_AUTO_DEPTH);
+    }
+    (depth, hidden(depth))
+}
+
+#[test]
+fn test_simplify_depths() {
+    let show = |tokens, brace_depth, stmt_limit| {
+        replace_ellipsis(
+            &indent_tokens(
+                unreplace(simplify_and_replace(tokens, brace_depth, stmt_limit)),
+                false,
+            ),
+            "...",
+        )
+    };
+    let nested = quote!(
+        fn f() {
+            g(h(i(j(1))));
+        }
+    );
+    let long = quote!(let a = 1; let b = 2; let c = 3; let d = 4;);
+    assert_eq!(
+        show(nested.clone(), 3, 2),
+        "fn f () {\n    g (h (... /* 5 more tokens */));\n}"
+    );
+    assert_eq!(show(nested, 8, 2), "fn f () {\n    g (h (i (j (1))));\n}");
+    assert_eq!(
+        show(long.clone(), 1, 2),
+        "let a = 1;\nlet b = 2;\n... /* 10 more tokens */"
+    );
+    assert_eq!(show(long, 0, 8), "... /* 20 more tokens */");
+}
+
+/// Truncates `tokens` to `brace_depth` nested groups and `stmt_limit`
+/// statements per group, leaving `__proc_debug_ellipsis!{ n }` in place of the
+/// `n` omitted token trees.
+fn simplify_and_replace(tokens: TokenStream, brace_depth: usize, stmt_limit: usize) -> TokenStream {
+    let mut out = TokenStream::new();
+    if brace_depth == 0 {
+        let omitted = proc_macro2::Literal::usize_unsuffixed(count_tokens(tokens));
+        out.extend(quote!(__proc_debug_ellipsis! { #omitted }));
         return out;
     }
     let mut count = 0;
-    for token in tokens {
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
         match &token {
             TokenTree::Group(g) => {
-                let inner = simplify_and_replace(g.stream(), depth - 1);
+                let inner = simplify_and_replace(g.stream(), brace_depth - 1, stmt_limit);
                 out.extend(Some(TokenTree::Group(proc_macro2::Group::new(
                     g.delimiter(),
                     inner,
@@ -92,8 +270,10 @@ fn simplify_and_replace(tokens: TokenStream, depth: usize) -> TokenStream {
             TokenTree::Punct(p) if p.as_char() == ';' => {
                 count += 1;
                 out.extend(Some(token.clone()));
-                if count >= depth {
-                    out.extend(quote_spanned!(p.span() => __proc_debug_ellipsis!{}));
+                if count >= stmt_limit {
+                    let omitted = count_tokens(tokens.by_ref().collect());
+                    let omitted = proc_macro2::Literal::usize_unsuffixed(omitted);
+                    out.extend(quote_spanned!(p.span() => __proc_debug_ellipsis!{ #omitted }));
                     break;
                 }
             }
@@ -108,6 +288,138 @@ fn simplify_and_replace(tokens: TokenStream, depth: usize) -> TokenStream {
     out
 }
 
+/// Identifier left by [`unreplace`] in place of omitted tokens, suffixed with
+/// `_n` when `n > 0` token trees were omitted. It is replaced with the
+/// `--ellipsis` text after formatting by [`replace_ellipsis`], so that the text
+/// need not be valid tokens.
+const ELLIPSIS_MARKER: &str = "__proc_debug_ellipsis";
+
+#[test]
+fn test_replace_ellipsis() {
+    let formatted = "f (__proc_debug_ellipsis_3);\n__proc_debug_ellipsis";
+    assert_eq!(
+        replace_ellipsis(formatted, "comment"),
+        "f (/* ... 3 more tokens */);\n/* ... */"
+    );
+    assert_eq!(
+        replace_ellipsis(formatted, "dots"),
+        "f (... /* 3 more tokens */);\n..."
+    );
+    assert_eq!(
+        replace_ellipsis(formatted, "todo!()"),
+        "f (todo!() /* 3 more tokens */);\ntodo!()"
+    );
+}
+
+/// Replaces each `marker! { "text" }` left in formatted output by a fold with
+/// `replace(text)`. Lines after the first of the replacement are indented as
+/// the marker.
+fn replace_markers(formatted: &str, marker: &str, replace: impl Fn(&str) -> String) -> String {
+    let mut out = String::new();
+    let mut rest = formatted;
+    while let Some(start) = rest.find(marker) {
+        let after = &rest[start..];
+        let text = after.find('"').and_then(|open| {
+            let mut escaped = false;
+            let len = after[open + 1..].find(|c| {
+                let end = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                end
+            })?;
+            let literal = &after[open..open + len + 2];
+            let close = open + len + 2 + after[open + len + 2..].find('}')?;
+            Some((syn::parse_str::<syn::LitStr>(literal).ok()?.value(), close))
+        });
+        let Some((text, close)) = text else {
+            out.push_str(&rest[..start + marker.len()]);
+            rest = &rest[start + marker.len()..];
+            continue;
+        };
+        out.push_str(&rest[..start]);
+        let line_start = out.rfind('\n').map_or(0, |n| n + 1);
+        let indent = &out[line_start..];
+        let indent = if indent.trim().is_empty() {
+            indent.to_owned()
+        } else {
+            String::new()
+        };
+        out.push_str(&replace(&text).replace('\n', &format!("\n{indent}")));
+        rest = &after[close + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Replaces the markers with `ellipsis`, which is either a preset (`comment`
+/// for `/* ... */`, `dots` for `...`) or the text itself.
+fn replace_ellipsis(formatted: &str, ellipsis: &str) -> String {
+    let comment = ellipsis == "comment";
+    let ellipsis = match ellipsis {
+        "comment" => "/* ... */",
+        "dots" => "...",
+        text => text,
+    };
+    let mut out = String::new();
+    let mut rest = formatted;
+    while let Some(start) = rest.find(ELLIPSIS_MARKER) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + ELLIPSIS_MARKER.len()..];
+        let digits = rest
+            .strip_prefix('_')
+            .map(|s| s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len())
+            .unwrap_or(0);
+        if digits > 0 {
+            let omitted = &rest[1..1 + digits];
+            let s = if omitted == "1" { "" } else { "s" };
+            if comment {
+                out.push_str(&format!("/* ... {omitted} more token{s} */"));
+            } else {
+                out.push_str(&format!("{ellipsis} /* {omitted} more token{s} */"));
+            }
+            rest = &rest[1 + digits..];
+        } else {
+            out.push_str(ellipsis);
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[test]
+fn test_truncate_output() {
+    let formatted = "struct A;\nstruct B;\nstruct C;".to_owned();
+    assert_eq!(
+        truncate_output(formatted.clone(), 15),
+        "struct A;\n/* … truncated, 20 more bytes */"
+    );
+    assert_eq!(truncate_output(formatted.clone(), 0), formatted);
+    assert_eq!(
+        truncate_output("f (\"ああ\");".to_owned(), 6),
+        "f\n/* … truncated, 12 more bytes */"
+    );
+}
+
+/// Cuts `formatted` to at most `max` bytes, preferably at a line break, and
+/// notes how many bytes were dropped. `0` means unlimited.
+fn truncate_output(formatted: String, max: usize) -> String {
+    if max == 0 || formatted.len() <= max {
+        return formatted;
+    }
+    let mut end = max;
+    while !formatted.is_char_boundary(end) {
+        end -= 1;
+    }
+    let end = formatted[..end]
+        .rfind('\n')
+        .or_else(|| formatted[..end].rfind(char::is_whitespace))
+        .unwrap_or(end);
+    let kept = formatted[..end].trim_end();
+    format!(
+        "{kept}\n/* … truncated, {} more bytes */",
+        formatted.len() - kept.len()
+    )
+}
+
 fn unreplace(tokens: TokenStream) -> TokenStream {
     let mut out = TokenStream::new();
     let mut tokens: VecDeque<_> = tokens.into_iter().collect();
@@ -121,7 +433,14 @@ fn unreplace(tokens: TokenStream) -> TokenStream {
                         {
                             match ident.to_string().as_str() {
                                 "__proc_debug_ellipsis" => {
-                                    out.extend(quote_spanned!(ident.span() => ...));
+                                    let marker = match g.stream().to_string().parse::<usize>() {
+                                        Ok(0) | Err(_) => ELLIPSIS_MARKER.to_owned(),
+                                        Ok(n) => format!("{ELLIPSIS_MARKER}_{n}"),
+                                    };
+                                    out.extend(Some(TokenTree::Ident(Ident::new(
+                                        &marker,
+                                        ident.span(),
+                                    ))));
                                     continue;
                                 }
                                 "__proc_debug_dollar_crate" => {
@@ -151,6 +470,303 @@ fn unreplace(tokens: TokenStream) -> TokenStream {
     out
 }
 
+/// Lightweight pretty-printer used when rustfmt is disabled or fails.
+///
+/// Breaks lines after `;`, attributes and around brace groups, and indents by
+/// brace depth. Works on tokens rather than on the stringified stream, so that
+/// braces in literals never affect the layout.
+#[derive(Default)]
+struct Indenter {
+    show_invisible: bool,
+    out: String,
+    depth: usize,
+    inline: usize,
+    line_start: bool,
+    spacing: bool,
+}
+
+impl Indenter {
+    fn word(&mut self, s: &str) {
+        if self.line_start {
+            self.out.extend(std::iter::repeat_n("    ", self.depth));
+            self.line_start = false;
+        } else if self.spacing {
+            self.out.push(' ');
+        }
+        self.out.push_str(s);
+        self.spacing = true;
+    }
+
+    fn newline(&mut self) {
+        if !self.line_start && self.inline == 0 {
+            self.out.push('\n');
+            self.line_start = true;
+        }
+    }
+
+    fn tokens(&mut self, tokens: TokenStream) {
+        let mut after_pound = false;
+        for token in tokens {
+            let pound = matches!(&token, TokenTree::Punct(p)
+                if p.as_char() == '#' || (p.as_char() == '!' && after_pound));
+            match token {
+                TokenTree::Group(g) if g.delimiter() == proc_macro2::Delimiter::Brace => {
+                    let inline = std::mem::take(&mut self.inline);
+                    self.word("{");
+                    self.depth += 1;
+                    self.newline();
+                    self.tokens(g.stream());
+                    self.depth -= 1;
+                    self.newline();
+                    self.word("}");
+                    self.inline = inline;
+                    self.newline();
+                }
+                TokenTree::Group(g) => {
+                    let (open, close) = match g.delimiter() {
+                        proc_macro2::Delimiter::Parenthesis => ("(", ")"),
+                        proc_macro2::Delimiter::Bracket => ("[", "]"),
+                        _ if self.show_invisible => ("⟦", "⟧"),
+                        _ => ("", ""),
+                    };
+                    self.word(open);
+                    self.spacing = false;
+                    self.inline += 1;
+                    self.tokens(g.stream());
+                    self.inline -= 1;
+                    self.spacing = false;
+                    self.word(close);
+                    if after_pound && g.delimiter() == proc_macro2::Delimiter::Bracket {
+                        self.newline();
+                    }
+                }
+                TokenTree::Punct(p) => {
+                    if matches!(p.as_char(), ';' | ',') {
+                        self.spacing = false;
+                    }
+                    self.word(&p.as_char().to_string());
+                    // `$crate` and `#[..]` are written without inner spaces
+                    self.spacing =
+                        p.spacing() == proc_macro2::Spacing::Alone && p.as_char() != '$' && !pound;
+                    if p.as_char() == ';' {
+                        self.newline();
+                    }
+                }
+                o => self.word(&o.to_string()),
+            }
+            after_pound = pound;
+        }
+    }
+}
+
+#[test]
+fn test_indent_tokens() {
+    let tokens = quote! {
+        #[automatically_derived]
+        impl Trait for A {
+            type Output = [u8; 2];
+            fn f(&self) -> &'static str { let _ = $crate::g(); "{ not a block" }
+        }
+    };
+    let tokens = unreplace(simplify_and_replace(tokens, 3, 3));
+    assert_eq!(
+        replace_ellipsis(&indent_tokens(tokens.clone(), false), "..."),
+        r#"#[automatically_derived]
+impl Trait for A {
+    type Output = [u8; 2];
+    fn f (& self) -> & 'static str {
+        let _ = $crate :: g (...);
+        "{ not a block"
+    }
+}"#
+    );
+    assert!(
+        replace_ellipsis(&indent_tokens(tokens, false), "/* …snip… */")
+            .contains("let _ = $crate :: g (/* …snip… */);")
+    );
+}
+
+#[test]
+fn test_invisible_groups() {
+    fn none_group(tokens: TokenStream) -> TokenStream {
+        TokenStream::from(TokenTree::Group(proc_macro2::Group::new(
+            proc_macro2::Delimiter::None,
+            tokens,
+        )))
+    }
+    let inner = none_group(quote!(a + b));
+    let nested = none_group(quote!(#inner * c));
+    let tokens = quote!(let x = #nested;);
+    assert_eq!(indent_tokens(tokens.clone(), false), "let x = a + b * c;");
+    assert_eq!(
+        indent_tokens(tokens.clone(), true),
+        "let x = ⟦⟦a + b⟧ * c⟧;"
+    );
+    let flat = quote!(let x = a + b * c;);
+    assert!(tokens_eq(tokens.clone(), flat.clone(), true));
+    assert!(!tokens_eq(tokens.clone(), flat, false));
+    assert!(tokens_eq(tokens.clone(), tokens.clone(), false));
+
+    let inputs = ["x".to_owned()];
+    let ret = wrap(
+        &test_entry("function", &inputs),
+        Some(&test_args(&["-a", "--show-invisible-groups"])),
+        &Destinations::default(),
+        || tokens.clone(),
+    );
+    assert!(tokens_eq(ret, tokens, false));
+}
+
+#[test]
+fn test_non_ascii() {
+    let tokens = quote! {
+        /// 型の説明
+        struct 型 { r#型: &'static str }
+        const 名前: &str = "こんにちは { 世界";
+    };
+    let tokens = unreplace(simplify_and_replace(tokens, 4, 4));
+    assert_eq!(
+        indent_tokens(tokens, false),
+        r#"#[doc = r" 型の説明"]
+struct 型 {
+    r#型 : & 'static str
+}
+const 名前 : & str = "こんにちは { 世界";"#
+    );
+    let raw = MacroOutput::from_tokens(quote!(r#Type), &MacroKind::Function);
+    assert!(matches!(raw, MacroOutput::Type(_)));
+}
+
+fn indent_tokens(tokens: TokenStream, show_invisible: bool) -> String {
+    let mut indenter = Indenter {
+        show_invisible,
+        ..Default::default()
+    };
+    indenter.tokens(tokens);
+    indenter.out.trim_end().to_owned()
+}
+
+/// Token stream flattened into delimiters and leaves, ignoring spans and
+/// spacing.
+#[derive(PartialEq, Eq)]
+enum FlatToken {
+    Open(proc_macro2::Delimiter),
+    Close,
+    Leaf(String),
+}
+
+fn flatten_tokens(tokens: TokenStream, flatten_none: bool, out: &mut Vec<FlatToken>) {
+    for token in tokens {
+        match token {
+            TokenTree::Group(g)
+                if flatten_none && g.delimiter() == proc_macro2::Delimiter::None =>
+            {
+                flatten_tokens(g.stream(), flatten_none, out)
+            }
+            TokenTree::Group(g) => {
+                out.push(FlatToken::Open(g.delimiter()));
+                flatten_tokens(g.stream(), flatten_none, out);
+                out.push(FlatToken::Close);
+            }
+            TokenTree::Punct(p) => out.push(FlatToken::Leaf(p.as_char().to_string())),
+            o => out.push(FlatToken::Leaf(o.to_string())),
+        }
+    }
+}
+
+/// Structural equality of token streams. With `flatten_none`, invisible
+/// (`Delimiter::None`) groups compare equal to their contents.
+fn tokens_eq(a: TokenStream, b: TokenStream, flatten_none: bool) -> bool {
+    let (mut fa, mut fb) = (Vec::new(), Vec::new());
+    flatten_tokens(a, flatten_none, &mut fa);
+    flatten_tokens(b, flatten_none, &mut fb);
+    fa == fb
+}
+
+/// Formats the output with rustfmt, falling back to [`indent_tokens`] when
+/// rustfmt is disabled, unavailable or rejects the tokens. Invisible group
+/// markers are not valid Rust, so rustfmt is skipped when they are shown.
+fn format_tokens(tokens: TokenStream, rustfmt: bool, show_invisible: bool) -> String {
+    if rustfmt && !show_invisible {
+        if let Some(formatted) = run_rustfmt(&tokens.to_string()) {
+            return formatted.trim_end().to_owned();
+        }
+    }
+    indent_tokens(tokens, show_invisible)
+}
+
+fn run_rustfmt(source: &str) -> Option<String> {
+    use std::process::{Command, Stdio};
+    let mut child = Command::new(std::env::var("RUSTFMT").unwrap_or("rustfmt".to_owned()))
+        .args(["--emit", "stdout", "--edition", "2021"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    child.stdin.take()?.write_all(source.as_bytes()).ok()?;
+    let output = child.wait_with_output().ok()?;
+    if output.status.success() {
+        String::from_utf8(output.stdout).ok()
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_classification() {
+    let classify = |tokens, kind| MacroOutput::from_tokens(tokens, &kind).describe();
+    assert_eq!(
+        classify(quote!(r#Type), MacroKind::Function),
+        "parsed as a Type"
+    );
+    assert_eq!(
+        classify(
+            quote!(
+                struct A;
+                struct B;
+            ),
+            MacroKind::Attribute
+        ),
+        "parsed as 2 Items"
+    );
+    assert_eq!(
+        classify(
+            quote!(
+                fn f() {}
+            ),
+            MacroKind::Attribute
+        ),
+        "parsed as 1 ImplItem"
+    );
+    assert_eq!(
+        classify(quote!(value), MacroKind::Function),
+        "parsed as an Expr"
+    );
+    assert_eq!(
+        classify(quote!('a), MacroKind::Function),
+        "parsed as a Lifetime"
+    );
+    assert_eq!(
+        classify(quote!(where T: Clone), MacroKind::Function),
+        "parsed as a WhereClause"
+    );
+    assert_eq!(
+        classify(quote!(<'a, T: Clone + 'a>), MacroKind::Function),
+        "parsed as Generics"
+    );
+    assert_eq!(
+        classify(quote!(), MacroKind::Function),
+        "parsed as 0 ImplItems"
+    );
+    assert_eq!(
+        classify(quote!(struct), MacroKind::Derive),
+        "parsed as Other"
+    );
+    assert_eq!(MacroOutput::parse_name("other"), Ok("Other"));
+    assert!(MacroOutput::parse_name("items").is_err());
+}
+
 impl MacroOutput {
     fn from_tokens(tokens: TokenStream, kind: &MacroKind) -> Self {
         struct Sequentary<T>(Vec<T>);
@@ -168,7 +784,15 @@ impl MacroOutput {
         }
         if kind == &MacroKind::Function {
             if let Ok(ident) = parse2::<Ident>(tokens.clone()) {
-                if ident.to_string().chars().next().unwrap().is_uppercase() {
+                use syn::ext::IdentExt;
+                if ident
+                    .unraw()
+                    .to_string()
+                    .chars()
+                    .next()
+                    .unwrap()
+                    .is_uppercase()
+                {
                     return Self::Type(parse_quote! {#ident});
                 } else {
                     return Self::Expr(parse_quote!(#ident));
@@ -177,6 +801,18 @@ impl MacroOutput {
             if let Ok(ty) = parse2::<Type>(tokens.clone()) {
                 return Self::Type(ty);
             }
+            if let Ok(lifetime) = parse2::<Lifetime>(tokens.clone()) {
+                return Self::Lifetime(lifetime);
+            }
+            if let Ok(clause) = parse2::<WhereClause>(tokens.clone()) {
+                return Self::WhereClause(clause);
+            }
+            // Generics also parse from nothing, which is not a parameter list.
+            if let Ok(generics) = parse2::<Generics>(tokens.clone()) {
+                if generics.lt_token.is_some() {
+                    return Self::Generics(generics);
+                }
+            }
         }
         if let Ok(s) = parse2::<Sequentary<_>>(tokens.clone()) {
             return Self::ImplItem(s.0);
@@ -196,6 +832,80 @@ impl MacroOutput {
         Self::Other(tokens)
     }
 
+    /// Names of the variants, as taken by `--classified-as`.
+    const NAMES: &'static [&'static str] = &[
+        "Expr",
+        "Type",
+        "Lifetime",
+        "WhereClause",
+        "Generics",
+        "ImplItem",
+        "TraitItem",
+        "ForeignItem",
+        "Item",
+        "Stmt",
+        "Other",
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            MacroOutput::Expr(_) => "Expr",
+            MacroOutput::Type(_) => "Type",
+            MacroOutput::Lifetime(_) => "Lifetime",
+            MacroOutput::WhereClause(_) => "WhereClause",
+            MacroOutput::Generics(_) => "Generics",
+            MacroOutput::ImplItem(_) => "ImplItem",
+            MacroOutput::TraitItem(_) => "TraitItem",
+            MacroOutput::ForeignItem(_) => "ForeignItem",
+            MacroOutput::Item(_) => "Item",
+            MacroOutput::Stmt(_) => "Stmt",
+            MacroOutput::Other(_) => "Other",
+        }
+    }
+
+    /// Parses the argument of `--classified-as`, ignoring case.
+    fn parse_name(s: &str) -> std::result::Result<&'static str, String> {
+        Self::NAMES
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "unknown classification `{s}`, expected one of: {}",
+                    Self::NAMES.join(", ")
+                )
+            })
+    }
+
+    /// Tells how the output was parsed, such as `parsed as 3 Items`.
+    fn describe(&self) -> String {
+        let len = match self {
+            MacroOutput::Expr(_) => return "parsed as an Expr".to_owned(),
+            MacroOutput::Type(_) => return "parsed as a Type".to_owned(),
+            MacroOutput::Lifetime(_) => return "parsed as a Lifetime".to_owned(),
+            MacroOutput::WhereClause(_) => return "parsed as a WhereClause".to_owned(),
+            MacroOutput::Generics(_) => return "parsed as Generics".to_owned(),
+            MacroOutput::Other(_) => return "parsed as Other".to_owned(),
+            o => o.len().unwrap(),
+        };
+        let plural = if len == 1 { "" } else { "s" };
+        format!("parsed as {len} {}{plural}", self.name())
+    }
+
+    /// Number of top-level parts of the output, one for a single node such as
+    /// an Expr, or `None` if it was not parsed.
+    fn len(&self) -> Option<usize> {
+        match self {
+            MacroOutput::ImplItem(o) => Some(o.len()),
+            MacroOutput::TraitItem(o) => Some(o.len()),
+            MacroOutput::ForeignItem(o) => Some(o.len()),
+            MacroOutput::Item(o) => Some(o.len()),
+            MacroOutput::Stmt(o) => Some(o.len()),
+            MacroOutput::Other(_) => None,
+            _ => Some(1),
+        }
+    }
+
     fn emit(&self) -> TokenStream {
         match self {
             MacroOutput::Expr(expr) => quote! {#expr},
@@ -205,79 +915,253 @@ impl MacroOutput {
     }
 }
 
-fn show_macro_call(
-    modpath: &str,
-    macro_name: &str,
-    file: &str,
-    line: usize,
-    macro_kind: &str,
-    macro_inputs: &[String],
-) {
-    let content = match macro_kind {
-        "function" => format!("{macro_name}!{{{}}}", macro_inputs[0]),
-        "attribute" => format!(
-            "#[{}({})]\n{}",
-            macro_name, macro_inputs[0], macro_inputs[1]
-        ),
-        "derive" => format!("#[derive({})]\n{}", macro_inputs[0], macro_inputs[1]),
-        _ => format!("{}", macro_inputs.join(",")),
-    };
-    let content = content
-        .split("\n")
-        .map(|s| format!("  {}", s))
-        .collect::<Vec<_>>()
-        .join("\n");
-    print(|out| writeln!(out, "👉 input of {modpath}::{macro_name} ({file}:{line})",)).unwrap();
-    PrettyPrinter::new()
-        .input_from_reader(content.as_bytes())
-        .language("rust")
-        .print()
-        .unwrap();
-    writeln!(std::io::stdout(), "",).unwrap();
-}
-
-fn show_macro_output(modpath: &str, macro_name: &str, file: &str, line: usize, macro_output: &str) {
-    print(|out| writeln!(out, "👉 output of {modpath}::{macro_name} ({file}:{line})",)).unwrap();
-    let content = macro_output
-        .split("\n")
-        .map(|s| format!("  {}", s))
-        .collect::<Vec<_>>()
-        .join("\n");
-    PrettyPrinter::new()
-        .input_from_bytes(content.as_bytes())
-        .language("rust")
-        .print()
-        .unwrap();
-    writeln!(std::io::stdout(), "",).unwrap();
-}
-
 /// Input for `proc-debug`
 #[derive(FromArgs)]
 struct ProcDebugArgs {
     /// debug all macros
     #[argp(switch, short = 'a')]
     all: bool,
-    /// hide outputs match
-    #[argp(option, short = 'n')]
-    not: Vec<String>,
+    /// hide outputs match, even with --all
+    #[argp(option, short = 'n', from_str_fn(Pattern::parse))]
+    not: Vec<Pattern>,
     /// full or partial path of macro definition
-    #[argp(option, short = 'p')]
-    path: Vec<String>,
+    #[argp(option, short = 'p', from_str_fn(Pattern::parse))]
+    path: Vec<Pattern>,
+    /// hide macros whose path matches as with --path, even with --all
+    #[argp(option, arg_name = "path", from_str_fn(Pattern::parse))]
+    not_path: Vec<Pattern>,
+    /// show macros with exactly this label, as set by
+    /// #[proc_debug(label = "..")]
+    #[argp(option, arg_name = "label")]
+    label: Vec<String>,
+    /// show macros whose label begins with this
+    #[argp(option, arg_name = "prefix")]
+    label_prefix: Vec<String>,
+    /// show only macros of this kind: function, attribute or derive, even
+    /// with --all
+    #[argp(option, short = 'k', from_str_fn(MacroKind::parse_filter))]
+    kind: Vec<MacroKind>,
+    /// show only derive macros of this trait, given by name or path, even
+    /// with --all; other macros are unaffected
+    #[argp(option, arg_name = "name")]
+    derive: Vec<String>,
+    /// show only macros defined or invoked in a file matching this glob,
+    /// even with --all
+    #[argp(option, arg_name = "glob", from_str_fn(Pattern::parse))]
+    file: Vec<Pattern>,
+    /// hide macros defined or invoked in a file matching this glob
+    #[argp(option, arg_name = "glob", from_str_fn(Pattern::parse))]
+    not_file: Vec<Pattern>,
+    /// show only macros defined in this half-open range of lines, such as
+    /// 200..400, 200.. or ..400, for use with --file or --path, even with
+    /// --all
+    #[argp(option, arg_name = "range", from_str_fn(LineRange::parse))]
+    lines: Vec<LineRange>,
+    /// show only macros whose input contains this text, ignoring whitespace,
+    /// even with --all
+    #[argp(option, arg_name = "text")]
+    input_contains: Vec<String>,
+    /// hide macros whose input contains this text, ignoring whitespace
+    #[argp(option, arg_name = "text")]
+    input_not_contains: Vec<String>,
+    /// show only macros of the package with this name, or `name@version`,
+    /// even with --all
+    #[argp(option, arg_name = "name")]
+    crate_name: Vec<String>,
+    /// show only macros of a package with this version, even with --all
+    #[argp(option, arg_name = "version")]
+    crate_version: Vec<String>,
+    /// show only expansions whose output contains this text, ignoring
+    /// whitespace, even with --all
+    #[argp(option, arg_name = "text")]
+    output_contains: Vec<String>,
+    /// hide expansions whose output contains this text, ignoring whitespace
+    #[argp(option, arg_name = "text")]
+    output_not_contains: Vec<String>,
+    /// show only macros matching every query, instead of any
+    #[argp(switch)]
+    all_queries: bool,
     /// search queries to show debug
-    #[argp(positional, greedy)]
-    queries: Vec<String>,
-    /// depth to show in macro output
+    #[argp(positional, greedy, from_str_fn(Pattern::parse))]
+    queries: Vec<Pattern>,
+    /// depth to show in macro output, setting both --brace-depth and
+    /// --stmt-limit
     #[argp(option, short = 'd')]
     depth: Option<usize>,
+    /// nested groups to expand in macro output
+    #[argp(option)]
+    brace_depth: Option<usize>,
+    /// statements to show per block in macro output
+    #[argp(option)]
+    stmt_limit: Option<usize>,
     /// verbose
     #[argp(switch, short = 'v')]
     verbose: bool,
+    /// format output with rustfmt
+    #[argp(switch)]
+    rustfmt: bool,
+    /// output format: text, csv, tsv or json (written to $PROC_DEBUG_OUT if
+    /// set)
+    #[argp(option, default = "Format::Text", from_str_fn(Format::from_str))]
+    format: Format,
+    /// show invisible groups as ⟦ ... ⟧
+    #[argp(switch)]
+    show_invisible_groups: bool,
+    /// do not treat invisible groups as their contents on comparison
+    #[argp(switch)]
+    strict_groups: bool,
+    /// show per-macro totals instead of each expansion
+    #[argp(switch)]
+    summary: bool,
+    /// show the tokens, the group depth and the top-level parts of each full
+    /// output under it
+    #[argp(switch)]
+    stats: bool,
+    /// annotate the expansion with the given index (shown after `#` in the
+    /// input header) with notes about common generated patterns
+    #[argp(option, arg_name = "index")]
+    explain: Option<usize>,
+    /// shown in place of omitted tokens: comment (/* ... */), dots (...) or
+    /// any text (default: comment)
+    #[argp(option, default = "\"comment\".to_owned()")]
+    ellipsis: String,
+    /// show doc attributes instead of folding them into a count
+    #[argp(switch)]
+    show_docs: bool,
+    /// banner colors: default, high-contrast or mono
+    #[argp(option, default = "Palette::Default", from_str_fn(Palette::from_str))]
+    palette: Palette,
+    /// color the output: auto (on a terminal, or as CARGO_TERM_COLOR says),
+    /// always or never (default: auto)
+    #[argp(option, default = "ColorMode::Auto", from_str_fn(ColorMode::from_str))]
+    color: ColorMode,
+    /// truncate the output after this many bytes, 0 for unlimited (default:
+    /// 65536)
+    #[argp(option, default = "65536")]
+    max_output_bytes: usize,
+    /// stop showing expansions once this many bytes were printed by the
+    /// process, only counting them, 0 for unlimited (default: 0)
+    #[argp(option, default = "0", arg_name = "bytes")]
+    max_total_bytes: usize,
+    /// show only attribute macros which removed their item
+    #[argp(switch)]
+    removed_only: bool,
+    /// show only outputs which do not parse or invoke compile_error!
+    #[argp(switch)]
+    errors_only: bool,
+    /// hide attribute macros which left their item unchanged, and derive
+    /// macros which emitted nothing
+    #[argp(switch)]
+    only_changed: bool,
+    /// count but do not show the first N expansions passing the other
+    /// filters (default: 0)
+    #[argp(option, default = "0", arg_name = "N")]
+    skip: usize,
+    /// show at most N expansions of each macro, 0 for unlimited (default: 0)
+    #[argp(option, default = "0", arg_name = "N")]
+    count_per_macro: usize,
+    /// same as --count-per-macro N, to go with --last
+    #[argp(option, arg_name = "N")]
+    first: Option<usize>,
+    /// also show the last N matched expansions of each macro beyond those of
+    /// --count-per-macro, all of them being held back until the compiling
+    /// thread exits, so that they are lost if rustc exits first (default: 0)
+    #[argp(option, default = "0", arg_name = "N")]
+    last: usize,
+    /// show only the expansion with this index (shown after `#` in the input
+    /// header), whatever the other filters but --not, --not-path and
+    /// --not-file; each thread of a parallel front-end has its own indexes
+    #[argp(option, arg_name = "index")]
+    nth: Vec<usize>,
+    /// page long outputs with bat on an interactive terminal (not under
+    /// parallel cargo builds)
+    #[argp(switch)]
+    paging: bool,
+    /// also append the shown expansions to this file, without colors
+    #[argp(option, arg_name = "path")]
+    tee: Option<std::path::PathBuf>,
+    /// shorten the headers of consecutive expansions of the same macro
+    #[argp(switch)]
+    group: bool,
+    /// show the flags in effect in each output, with where their values come
+    /// from
+    #[argp(switch)]
+    verbose_meta: bool,
+    /// read more filters from this file, with one `path: ..`, `not: ..` or
+    /// `query: ..` per line
+    #[argp(option, arg_name = "path")]
+    filter_file: Option<std::path::PathBuf>,
+    /// match queries, paths and --not as regexes, as a pattern written
+    /// /like this/ is without it
+    #[argp(switch)]
+    regex: bool,
+    /// match queries, paths, --not and the content filters ignoring case
+    #[argp(switch, short = 'i')]
+    ignore_case: bool,
+    /// write the full output and the inputs of each shown expansion to .rs
+    /// files in this directory
+    #[argp(option, arg_name = "path")]
+    dump_dir: Option<std::path::PathBuf>,
+    /// same as --dump-dir target/proc-debug
+    #[argp(switch)]
+    dump: bool,
+    /// show only the first of consecutive items which differ in a few
+    /// identifiers or literals
+    #[argp(switch)]
+    fold_repeats: bool,
+    /// show the input and the output side by side
+    #[argp(switch)]
+    side_by_side: bool,
+    /// show them one after the other on terminals narrower than this
+    /// (default: 100)
+    #[argp(option, default = "100", arg_name = "columns")]
+    side_by_side_min_width: usize,
+    /// show only outputs parsed as this: Expr, Type, Lifetime, WhereClause,
+    /// Generics, ImplItem, TraitItem, ForeignItem, Item, Stmt or Other
+    #[argp(option, arg_name = "kind", from_str_fn(MacroOutput::parse_name))]
+    classified_as: Option<&'static str>,
+    /// do not list the active filters before the first expansion
+    #[argp(switch)]
+    no_banner: bool,
+    /// do not hint at the filters when they matched none of the first 50
+    /// expansions
+    #[argp(switch)]
+    no_hints: bool,
+    /// do not show the expansions passed to a sink registered with
+    /// proc_debug::set_sink
+    #[argp(switch)]
+    sink_only: bool,
+    /// show the transcriber of each arm of macro_rules! definitions
+    #[argp(switch)]
+    show_transcribers: bool,
+    /// show only the matcher of each arm of macro_rules! definitions
+    #[argp(switch)]
+    arms_only: bool,
+    /// write the headers in ASCII, with `>>` instead of `👉`
+    #[argp(switch)]
+    ascii: bool,
+    /// template of the headers of inputs and outputs, with {marker}, {what},
+    /// {macro}, {location}, {index} and {notes}
+    #[argp(option, arg_name = "template")]
+    heading_format: Option<String>,
+    /// write paths relative to {workspace}, {registry}, {git} or {home}, to
+    /// share logs
+    #[argp(switch)]
+    portable_paths: bool,
+    /// prefix the headers with the local time and the time since the first
+    /// expansion
+    #[argp(switch)]
+    timestamps: bool,
+    /// copy the full output of each expansion shown to the clipboard of the
+    /// terminal, or to last-expansion.rs in the dump directory
+    #[argp(switch)]
+    copy_last: bool,
 }
 
 #[test]
 fn test_split_args() {
     assert_eq!(
-        split_args(r#"  --all  -a  ' b c ' " -d '' "  "#),
+        split_args(r#"  --all  -a  ' b c ' " -d '' "  "#).unwrap(),
         vec![
             "--all".to_owned(),
             "-a".to_owned(),
@@ -285,39 +1169,80 @@ fn test_split_args() {
             " -d '' ".to_owned()
         ]
     );
+    // Multi-line blocks of CI configurations
+    assert_eq!(
+        split_args("-a\n\t-n test\r\n-p 'a::\tb\nc'\n").unwrap(),
+        ["-a", "-n", "test", "-p", "a::\tb\nc"]
+    );
+    assert_eq!(
+        split_args("-n \\\n  test \"a\\\nb\"").unwrap(),
+        ["-n", "test", "ab"]
+    );
+    assert_eq!(
+        split_args("-a\n-n test\\").unwrap_err(),
+        "cannot split PROC_DEBUG_FLAGS: trailing backslash (line 2, column 8)\n\
+         \x20   -n test\\\n\
+         \x20          ^"
+    );
+    assert_eq!(
+        split_args("-a\n-p \"a::b\n-d 2").unwrap_err(),
+        "cannot split PROC_DEBUG_FLAGS: unclosed `\"` (line 2, column 4)\n\
+         \x20   -p \"a::b\n\
+         \x20      ^"
+    );
 }
 
-fn split_args(s: &str) -> Vec<String> {
-    let mut it = s.chars().fuse();
+/// Describes an error at the byte `offset` of `s`, showing its line.
+fn split_error(s: &str, offset: usize, error: &str) -> String {
+    let start = s[..offset].rfind('\n').map_or(0, |n| n + 1);
+    let end = s[offset..]
+        .find(['\r', '\n'])
+        .map_or(s.len(), |n| offset + n);
+    let line = s[..offset].matches('\n').count() + 1;
+    let column = s[start..offset].chars().count() + 1;
+    format!(
+        "cannot split PROC_DEBUG_FLAGS: {error} (line {line}, column {column})\n    {}\n    {}^",
+        &s[start..end],
+        " ".repeat(column - 1)
+    )
+}
+
+/// Splits flags as a shell would, with quotes, backslashes and line
+/// continuations. Newlines and tabs separate flags like spaces.
+fn split_args(s: &str) -> std::result::Result<Vec<String>, String> {
+    let mut it = s.char_indices().peekable();
     let mut res = Vec::new();
     let mut r = String::new();
-    while let Some(c) = it.next() {
+    // Pushes the character escaped by the backslash at `offset`.
+    let escape = |it: &mut std::iter::Peekable<std::str::CharIndices>, r: &mut String, offset| {
+        match it.next() {
+            Some((_, '\n')) => (),
+            Some((_, '\r')) if it.next_if(|&(_, c)| c == '\n').is_some() => (),
+            Some((_, c)) => r.push(c),
+            None => return Err(split_error(s, offset, "trailing backslash")),
+        }
+        Ok(())
+    };
+    while let Some((offset, c)) = it.next() {
         match c {
-            '\\' => {
-                if let Some(c) = it.next() {
-                    r.push(c);
-                }
-            }
+            '\\' => escape(&mut it, &mut r, offset)?,
             '"' | '\'' => {
                 let delim = c;
-                while let Some(c) = it.next() {
-                    match c {
-                        '\\' => {
-                            if let Some(c) = it.next() {
-                                r.push(c);
-                            }
-                        }
-                        c if c == delim => {
+                loop {
+                    match it.next() {
+                        Some((escaped, '\\')) => escape(&mut it, &mut r, escaped)?,
+                        Some((_, c)) if c == delim => {
                             res.push(r);
                             r = String::new();
                             break;
                         }
-                        c => r.push(c),
+                        Some((_, c)) => r.push(c),
+                        None => return Err(split_error(s, offset, &format!("unclosed `{delim}`"))),
                     }
                 }
             }
             c if c.is_ascii_whitespace() => {
-                if r.len() > 0 {
+                if !r.is_empty() {
                     res.push(r);
                     r = String::new();
                 }
@@ -325,95 +1250,1294 @@ fn split_args(s: &str) -> Vec<String> {
             c => r.push(c),
         }
     }
-    if r.len() > 0 {
+    if !r.is_empty() {
         res.push(r);
     }
-    res
+    Ok(res)
+}
+
+#[test]
+fn test_parse_flags() {
+    let parse = |flags: &[&str]| {
+        ProcDebugArgs::parse_flags(&flags.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    };
+    assert!(parse(&["-a", "--depth", "2"]).is_ok());
+    let err = parse(&["--depth", "deep"]).err().unwrap();
+    assert!(err.ends_with("Set PROC_DEBUG_FLAGS=\"--help\" for more information."));
+    let help = parse(&["--help"]).err().unwrap();
+    assert!(help.starts_with("Usage: proc-debug"), "{help}");
+    assert!(parse(&["--regex", "te(st"]).is_err());
 }
 
+/// `PROC_DEBUG_FLAGS` split once per process, `None` when it is not set.
+type SplitFlags = std::sync::OnceLock<Option<std::result::Result<Vec<String>, String>>>;
+
 impl ProcDebugArgs {
-    fn from_env() -> Option<Self> {
-        let flags = std::env::var("PROC_DEBUG_FLAGS").ok()?;
-        let flags = split_args(&flags);
-        Some(
-            ProcDebugArgs::from_args(&["proc-debug"], &flags).unwrap_or_else(|early_exit| {
-                let mut stderr = StandardStream::stderr(ColorChoice::Always);
-                stderr
-                    .set_color(
-                        ColorSpec::new()
-                            .set_bg(Some(Color::Yellow))
-                            .set_fg(Some(Color::Black))
-                            .set_bold(true),
-                    )
-                    .unwrap();
-                match early_exit {
-                    argp::EarlyExit::Help(help) => {
-                        writeln!(&mut stderr, "{}", help.generate_default()).unwrap()
-                    }
-                    argp::EarlyExit::Err(err) => writeln!(
-                        &mut stderr,
-                        "{} \n\n Set PROC_DEBUG_FLAGS=\"--help\" for more information.",
-                        err
-                    )
-                    .unwrap(),
+    /// Splits `PROC_DEBUG_FLAGS`, which is read once per process, or returns
+    /// `None` if it is not set.
+    fn split_env_flags() -> Option<&'static std::result::Result<Vec<String>, String>> {
+        static FLAGS: SplitFlags = std::sync::OnceLock::new();
+        Self::split_flags_once(&FLAGS, || std::env::var("PROC_DEBUG_FLAGS"))
+    }
+
+    /// Splits the value of `PROC_DEBUG_FLAGS` given by `var` into `flags` on
+    /// the first call, and returns the same flags on the next ones.
+    fn split_flags_once(
+        flags: &SplitFlags,
+        var: impl FnOnce() -> std::result::Result<String, std::env::VarError>,
+    ) -> Option<&std::result::Result<Vec<String>, String>> {
+        flags
+            .get_or_init(|| match var() {
+                Ok(flags) => Some(split_args(&flags)),
+                Err(std::env::VarError::NotPresent) => None,
+                Err(std::env::VarError::NotUnicode(_)) => {
+                    Some(Err("PROC_DEBUG_FLAGS is not valid UTF-8".to_owned()))
                 }
-                std::process::exit(1)
-            }),
-        )
+            })
+            .as_ref()
     }
-}
 
-#[allow(unused)]
-struct Entry<'a> {
-    label: &'a str,
-    file: &'a str,
-    line: usize,
-    modpath: &'a str,
-    macro_kind: &'a str,
-    macro_name: &'a str,
-    macro_inputs: &'a [String],
-}
+    /// Returns the flags of `PROC_DEBUG_FLAGS`, or `None` if it is not set or
+    /// cannot be split.
+    fn env_flags() -> Option<&'static [String]> {
+        Self::split_env_flags()?.as_deref().ok()
+    }
 
-impl<'a> Entry<'a> {
-    fn check_filter(&self, args: &ProcDebugArgs) -> bool {
-        let content = [&self.label, &self.file, &self.modpath, &self.macro_name];
-        let pattern = format!("{}::{}", &self.modpath, &self.macro_name);
+    /// Returns the flags parsed by [`ProcDebugArgs::from_env`] on the first
+    /// call, since a macro may be invoked thousands of times by one rustc
+    /// process.
+    fn cached() -> Option<&'static Self> {
+        static ARGS: std::sync::OnceLock<Option<ProcDebugArgs>> = std::sync::OnceLock::new();
+        ARGS.get_or_init(Self::from_env).as_ref()
+    }
 
-        if args.all {
-            return true;
+    /// Parses `PROC_DEBUG_FLAGS`, with the options of the configuration file
+    /// it does not give. Invalid flags are reported once per process and give
+    /// `None` like an unset variable, so that the build goes on.
+    fn from_env() -> Option<Self> {
+        let flags = match Self::split_env_flags()? {
+            Ok(flags) => flags,
+            Err(message) => {
+                Self::report(message, &[]);
+                return None;
+            }
+        };
+        let flags = match Config::load() {
+            None => flags.clone(),
+            Some(Ok(config)) => config.merge(flags),
+            Some(Err(message)) => {
+                Self::report(message, flags);
+                return None;
+            }
+        };
+        Self::parse_flags(&flags)
+            .map_err(|message| Self::report(&message, &flags))
+            .ok()
+    }
+
+    /// Parses `flags` with the filters of `--filter-file`. Invalid flags, and
+    /// `--help`, give the message to report instead of exiting, which would
+    /// abort the rustc process and leave instrumented sources behind.
+    fn parse_flags(flags: &[String]) -> std::result::Result<Self, String> {
+        ProcDebugArgs::from_args(&["proc-debug"], flags)
+            .map_err(|early_exit| match early_exit {
+                argp::EarlyExit::Help(help) => help.generate_default(),
+                argp::EarlyExit::Err(err) => format!(
+                    "{} \n\n Set PROC_DEBUG_FLAGS=\"--help\" for more information.",
+                    err
+                ),
+            })
+            .and_then(|mut args| {
+                args.read_filter_file()?;
+                args.compile_patterns()?;
+                args.apply_first()?;
+                Ok(args)
+            })
+    }
+
+    /// Reports why the `flags` are ignored, once per process.
+    fn report(message: &str, flags: &[String]) {
+        // Every invocation parses the flags again, but the build should not be
+        // flooded by the same message.
+        static REPORTED: std::sync::Once = std::sync::Once::new();
+        REPORTED.call_once(|| {
+            let color = ColorMode::from_flags(flags).enabled(std::io::stderr().is_terminal());
+            let choice = if color {
+                ColorChoice::Always
+            } else {
+                ColorChoice::Never
+            };
+            let mut stderr = StandardStream::stderr(choice);
+            let _ = stderr.set_color(&Palette::from_flags(flags).error());
+            let _ = writeln!(&mut stderr, "{message}");
+            let _ = stderr.reset();
+            let _ = writeln!(&mut stderr, "proc-debug: continuing without debug output");
+        });
+    }
+
+    /// Returns `text` lowercased with `--ignore-case`, or as is.
+    fn fold<'s>(&self, text: &'s str) -> std::borrow::Cow<'s, str> {
+        if self.ignore_case {
+            text.to_lowercase().into()
+        } else {
+            text.into()
         }
-        if content
+    }
+
+    /// Sets `--count-per-macro` to the value of `--first`, which is another
+    /// name for it.
+    fn apply_first(&mut self) -> std::result::Result<(), String> {
+        match self.first {
+            Some(first) if self.count_per_macro > 0 && self.count_per_macro != first => Err(
+                format!(
+                    "--first {first} contradicts --count-per-macro {}, which it is another name for",
+                    self.count_per_macro
+                ),
+            ),
+            Some(first) => {
+                self.count_per_macro = first;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Whether `label` is one of `--label`, or begins with one of
+    /// `--label-prefix`.
+    fn selects_label(&self, label: &str) -> bool {
+        let label = self.fold(label);
+        self.label.iter().any(|l| self.fold(l) == label)
+            || self
+                .label_prefix
+                .iter()
+                .any(|prefix| label.starts_with(&*self.fold(prefix)))
+    }
+
+    /// Compiles the filters taken as regexes and the globs of the files into
+    /// their [`Pattern`]s, failing on the first invalid one.
+    fn compile_patterns(&mut self) -> std::result::Result<(), String> {
+        let (regex, ignore_case) = (self.regex, self.ignore_case);
+        for (flag, patterns) in [
+            ("query", &mut self.queries),
+            ("--path", &mut self.path),
+            ("--not-path", &mut self.not_path),
+            ("--not", &mut self.not),
+        ] {
+            for pattern in patterns {
+                match filter_regex(&pattern.text, regex, ignore_case) {
+                    Some(Ok(compiled)) => pattern.regex = Some(compiled),
+                    Some(Err(err)) => {
+                        return Err(format!("invalid regex in {flag} `{pattern}`: {err}"))
+                    }
+                    None => (),
+                }
+            }
+        }
+        for (flag, patterns) in [
+            ("--file", &mut self.file),
+            ("--not-file", &mut self.not_file),
+        ] {
+            for pattern in patterns {
+                match file_glob(&pattern.text) {
+                    Ok(compiled) => pattern.glob = Some(compiled),
+                    Err(err) => return Err(format!("invalid glob in {flag} `{pattern}`: {err}")),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the filters of `--filter-file`, if given.
+    fn read_filter_file(&mut self) -> std::result::Result<(), String> {
+        let Some(path) = &self.filter_file else {
+            return Ok(());
+        };
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read filter file {}: {e}", path.display()))?;
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let directive = line.split_once(':').map(|(d, v)| (d.trim(), v.trim()));
+            match directive {
+                Some(("path", value)) => self.path.push(value.into()),
+                Some(("not", value)) => self.not.push(value.into()),
+                Some(("query", value)) => self.queries.push(value.into()),
+                _ => {
+                    return Err(format!(
+                        "{}:{}: expected `path: ..`, `not: ..` or `query: ..`, found `{line}`",
+                        path.display(),
+                        n + 1
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes the filters and the depth in effect, one per line, for the
+    /// banner shown before the first expansion.
+    fn active(&self) -> Vec<String> {
+        let mut active = Vec::new();
+        if self.all {
+            active.push("all macros".to_owned());
+        }
+        for (name, values) in [
+            ("queries", self.queries.join(", ")),
+            ("paths", self.path.join(", ")),
+            ("not paths", self.not_path.join(", ")),
+            ("labels", self.label.join(", ")),
+            ("label prefixes", self.label_prefix.join(", ")),
+            ("not", self.not.join(", ")),
+        ] {
+            if !values.is_empty() {
+                active.push(format!("{name}: {values}"));
+            }
+        }
+        if !self.kind.is_empty() {
+            let kinds = self.kind.iter().map(MacroKind::name).collect::<Vec<_>>();
+            active.push(format!("kinds: {}", kinds.join(", ")));
+        }
+        if !self.lines.is_empty() {
+            let lines = self.lines.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+            active.push(format!("lines: {}", lines.join(", ")));
+        }
+        for (name, values) in [
+            ("derives", self.derive.join(", ")),
+            ("files", self.file.join(", ")),
+            ("not files", self.not_file.join(", ")),
+            ("inputs containing", self.input_contains.join(", ")),
+            ("inputs not containing", self.input_not_contains.join(", ")),
+            ("crates", self.crate_name.join(", ")),
+            ("crate versions", self.crate_version.join(", ")),
+            ("outputs containing", self.output_contains.join(", ")),
+            (
+                "outputs not containing",
+                self.output_not_contains.join(", "),
+            ),
+        ] {
+            if !values.is_empty() {
+                active.push(format!("{name}: {values}"));
+            }
+        }
+        if let Some(path) = &self.filter_file {
+            active.push(format!("filter file: {}", path.display()));
+        }
+        if self.regex {
+            active.push("filters are regexes".to_owned());
+        }
+        if self.ignore_case {
+            active.push("filters ignore case".to_owned());
+        }
+        if self.all_queries {
+            active.push("every query must match".to_owned());
+        }
+        if let Some(depth) = self.depth {
+            active.push(format!("depth: {depth}"));
+        }
+        if self.skip > 0 {
+            active.push(format!("skipping the first {} matches", self.skip));
+        }
+        match (self.count_per_macro, self.last) {
+            (0, 0) => {}
+            (first, 0) => active.push(format!("at most {first} per macro")),
+            (0, last) => active.push(format!("the last {last} per macro")),
+            (first, last) => {
+                active.push(format!("the first {first} and the last {last} per macro"))
+            }
+        }
+        if !self.nth.is_empty() {
+            let nth = self.nth.iter().map(|n| format!("#{n}")).collect::<Vec<_>>();
+            active.push(format!("only: {}", nth.join(", ")));
+        }
+        active
+    }
+}
+
+#[test]
+fn test_active() {
+    let args = test_args(&["-p", "a::b", "-n", "test", "-d", "2", "my_macro", "other"]);
+    assert_eq!(
+        args.active(),
+        [
+            "queries: my_macro, other",
+            "paths: a::b",
+            "not: test",
+            "depth: 2"
+        ]
+    );
+    assert_eq!(test_args(&["-a"]).active(), ["all macros"]);
+}
+
+#[allow(unused)]
+struct Entry<'a> {
+    label: &'a str,
+    file: &'a str,
+    line: usize,
+    modpath: &'a str,
+    /// Name and version of the package defining the macro
+    crate_name: &'a str,
+    crate_version: &'a str,
+    macro_kind: &'a str,
+    macro_name: &'a str,
+    macro_inputs: &'a [String],
+    macro_arg_names: &'a [&'a str],
+    /// File and line of the macro invocation, as opposed to `file` and
+    /// `line` of the definition, when the compiler tells them
+    call_site: Option<(String, usize)>,
+}
+
+/// Half-open range of lines given to `--lines`, with optional bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LineRange {
+    start: Option<usize>,
+    end: Option<usize>,
+}
+
+#[test]
+fn test_line_range() {
+    let parse = |s| LineRange::parse(s).map(|r| r.to_string());
+    assert_eq!(parse("200..400").as_deref(), Ok("200..400"));
+    assert_eq!(parse("200..").as_deref(), Ok("200.."));
+    assert_eq!(parse(" ..400").as_deref(), Ok("..400"));
+    for invalid in [
+        "200",
+        "..",
+        "a..400",
+        "200..=400",
+        "200...400",
+        "400..200",
+        "1..1",
+    ] {
+        assert!(parse(invalid).is_err(), "{invalid}");
+    }
+    assert_eq!(
+        parse("400..200"),
+        Err("empty line range `400..200`, its start must be below its end".to_owned())
+    );
+    let range = LineRange::parse("200..400").unwrap();
+    assert!(!range.contains(199) && range.contains(200) && !range.contains(400));
+    let entry = test_entry("function", &[]);
+    let shown = |flags: &[&str]| entry.check_filter(&test_args(&[&["-a"], flags].concat()));
+    // Defined on line 1
+    assert!(shown(&["--file", "lib.rs", "--lines", "..2"]));
+    assert!(!shown(&["--file", "lib.rs", "--lines", "2.."]));
+    assert!(shown(&["--lines", "2..", "--lines", "1..2"]));
+    assert!(ProcDebugArgs::from_args(&["proc-debug"], &["--lines", "200"]).is_err());
+}
+
+impl LineRange {
+    /// Parses the argument of `--lines`: `start..end`, `start..` or `..end`.
+    fn parse(s: &str) -> std::result::Result<Self, String> {
+        let expected =
+            || format!("expected a line range like 200..400, 200.. or ..400, found `{s}`");
+        let (start, end) = s.trim().split_once("..").ok_or_else(expected)?;
+        let bound = |b: &str| match b {
+            "" => Ok(None),
+            b => b.parse().map(Some).map_err(|_| expected()),
+        };
+        let range = Self {
+            start: bound(start)?,
+            end: bound(end)?,
+        };
+        match range {
+            Self {
+                start: None,
+                end: None,
+            } => Err(expected()),
+            Self {
+                start: Some(start),
+                end: Some(end),
+            } if start >= end => Err(format!(
+                "empty line range `{s}`, its start must be below its end"
+            )),
+            range => Ok(range),
+        }
+    }
+
+    fn contains(&self, line: usize) -> bool {
+        self.start.is_none_or(|start| start <= line) && self.end.is_none_or(|end| line < end)
+    }
+}
+
+impl std::fmt::Display for LineRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(start) = self.start {
+            write!(f, "{start}")?;
+        }
+        write!(f, "..")?;
+        if let Some(end) = self.end {
+            write!(f, "{end}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A filter as given in the flags, with its regex or glob once
+/// [`ProcDebugArgs::compile_patterns`] compiled it, since each is matched on
+/// every invocation.
+#[derive(Clone)]
+struct Pattern {
+    text: String,
+    regex: Option<regex::Regex>,
+    glob: Option<globset::GlobMatcher>,
+}
+
+impl Pattern {
+    fn parse(text: &str) -> std::result::Result<Self, String> {
+        Ok(text.into())
+    }
+}
+
+impl From<&str> for Pattern {
+    fn from(text: &str) -> Self {
+        Pattern {
+            text: text.to_owned(),
+            regex: None,
+            glob: None,
+        }
+    }
+}
+
+impl std::ops::Deref for Pattern {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.text
+    }
+}
+
+impl std::borrow::Borrow<str> for Pattern {
+    fn borrow(&self) -> &str {
+        &self.text
+    }
+}
+
+impl std::fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.text.fmt(f)
+    }
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.text.fmt(f)
+    }
+}
+
+impl PartialEq<&str> for Pattern {
+    fn eq(&self, other: &&str) -> bool {
+        self.text == *other
+    }
+}
+
+/// Returns the regex of a filter `pattern`, if it is one: with `--regex`, or
+/// when written `/like this/`. With `ignore_case`, it is compiled with the `i`
+/// flag.
+fn filter_regex(
+    pattern: &str,
+    regex: bool,
+    ignore_case: bool,
+) -> Option<std::result::Result<regex::Regex, regex::Error>> {
+    let source = match pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+        Some(source) => source,
+        None if regex => pattern,
+        None => return None,
+    };
+    Some(
+        regex::RegexBuilder::new(source)
+            .case_insensitive(ignore_case)
+            .build(),
+    )
+}
+
+/// Returns the matcher of a `--file` or `--not-file` glob, in which `*` does
+/// not cross a `/` but `**` does.
+fn file_glob(pattern: &str) -> std::result::Result<globset::GlobMatcher, globset::Error> {
+    globset::GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map(|glob| glob.compile_matcher())
+}
+
+/// Returns `path` as given and, relative to the current directory, as the
+/// other of an absolute and a relative path, so that a glob can be written
+/// either way.
+fn path_forms(path: &str) -> Vec<std::path::PathBuf> {
+    // rustc does not change its current directory while expanding
+    static CURRENT: std::sync::OnceLock<Option<std::path::PathBuf>> = std::sync::OnceLock::new();
+    let path = std::path::Path::new(path);
+    let mut forms = vec![path.to_path_buf()];
+    if let Some(current) = CURRENT.get_or_init(|| std::env::current_dir().ok()) {
+        if path.is_absolute() {
+            forms.extend(path.strip_prefix(current).map(std::path::Path::to_path_buf));
+        } else {
+            forms.push(current.join(path));
+        }
+    }
+    forms
+}
+
+#[test]
+fn test_file_filter() {
+    let current = std::env::current_dir().unwrap();
+    let absolute = current.join("crates/codegen/src/lib.rs");
+    let entry = Entry {
+        file: absolute.to_str().unwrap(),
+        call_site: Some(("tests/expand.rs".to_owned(), 3)),
+        ..test_entry("function", &[])
+    };
+    assert!(entry.check_filter(&test_args(&["-a", "--file", "crates/codegen/**"])));
+    assert!(entry.check_filter(&test_args(&["-a", "--file", "tests/*.rs"])));
+    let absolute_glob = format!("{}/**/codegen/**", current.display());
+    assert!(entry.check_filter(&test_args(&["-a", "--file", &absolute_glob])));
+    assert!(!entry.check_filter(&test_args(&["-a", "--file", "crates/*.rs"])));
+    assert!(entry.check_filter(&test_args(&[
+        "-a",
+        "--file",
+        "src/**",
+        "--file",
+        "crates/**"
+    ])));
+    assert!(!entry.check_filter(&test_args(&["-a", "--not-file", "tests/**"])));
+    assert!(!entry.check_filter(&test_args(&[
+        "--file",
+        "crates/**",
+        "--not-file",
+        "**/lib.rs",
+        "my_macro"
+    ])));
+    assert!(!entry.check_filter(&test_args(&["--file", "crates/**"])));
+    let err = ProcDebugArgs::parse_flags(&["--file", "src/[a"].map(str::to_owned))
+        .err()
+        .unwrap();
+    assert!(err.starts_with("invalid glob in --file `src/[a`:"));
+}
+
+#[test]
+fn test_path_matches() {
+    let path = "my_crate::internal::derives::derive_impl";
+    for pattern in [
+        "my_crate::internal::derives::derive_impl",
+        "my_crate::internal",
+        "derives::derive_impl",
+        "my_crate::**::derive_impl",
+        "my_crate::internal::*::derive_impl",
+        "my_crate::*::derives",
+        "**::derive_*",
+        "**",
+        "my_crate::**",
+    ] {
+        assert!(path_matches(pattern, path), "{pattern}");
+    }
+    for pattern in [
+        "internal",
+        "my_crate::*::derive_impl",
+        "my_crate::derive_impl",
+        "my_crate::inter",
+        "other::**",
+    ] {
+        assert!(!path_matches(pattern, path), "{pattern}");
+    }
+    assert!(path_matches(
+        "**::serde_derive::*",
+        "vendored::serde_derive::Serialize"
+    ));
+    assert!(path_matches(
+        "**::serde_derive::*",
+        "serde_derive::Serialize"
+    ));
+    assert!(!path_matches("**::serde_derive::*", "serde_derive"));
+    assert!(path_matches(
+        "my_crate::**::derive_impl",
+        "my_crate::derive_impl"
+    ));
+}
+
+/// Whether the `--path` `pattern` matches `path`, written `module::macro`.
+///
+/// Both are compared by segment: `*` in a segment matches any characters of
+/// it, and a `**` segment any number of segments. A pattern also matches the
+/// paths it begins or ends, as if followed or preceded by `::**`.
+fn path_matches(pattern: &str, path: &str) -> bool {
+    fn segment_matches(pattern: &str, segment: &str) -> bool {
+        match pattern.split_once('*') {
+            None => pattern == segment,
+            Some((head, tail)) => {
+                let Some(rest) = segment.strip_prefix(head) else {
+                    return false;
+                };
+                (0..=rest.len())
+                    .filter(|n| rest.is_char_boundary(*n))
+                    .any(|n| segment_matches(tail, &rest[n..]))
+            }
+        }
+    }
+    fn matches(pattern: &[&str], path: &[&str]) -> bool {
+        match (pattern.split_first(), path.split_first()) {
+            (None, None) => true,
+            (Some((&"**", rest)), _) => (0..=path.len()).any(|n| matches(rest, &path[n..])),
+            (Some((first, rest)), Some((segment, path))) => {
+                segment_matches(first, segment) && matches(rest, path)
+            }
+            _ => false,
+        }
+    }
+    let pattern = pattern.split("::").collect::<Vec<_>>();
+    let path = path.split("::").collect::<Vec<_>>();
+    [
+        pattern.clone(),
+        [&pattern[..], &["**"]].concat(),
+        [&["**"], &pattern[..]].concat(),
+    ]
+    .iter()
+    .any(|pattern| matches(pattern, &path))
+}
+
+#[test]
+fn test_crate_filter() {
+    let entry = test_entry("function", &[]);
+    let shown = |flags: &[&str]| entry.check_filter(&test_args(&[&["-a"], flags].concat()));
+    assert!(shown(&["--crate-name", "my-crate"]));
+    assert!(shown(&["--crate-name", "my_crate"]));
+    assert!(shown(&["--crate-name", "my_crate@0.1.0"]));
+    assert!(!shown(&["--crate-name", "my_crate@0.2.0"]));
+    assert!(!shown(&["--crate-name", "other"]));
+    assert!(shown(&[
+        "--crate-name",
+        "other",
+        "--crate-name",
+        "my-crate"
+    ]));
+    assert!(shown(&["--crate-version", "0.1.0"]));
+    assert!(!shown(&[
+        "--crate-name",
+        "my-crate",
+        "--crate-version",
+        "0.2.0"
+    ]));
+}
+
+#[test]
+fn test_derive_filter() {
+    let inputs = ["Deserialize".to_owned(), "struct A;".to_owned()];
+    let entry = test_entry("derive", &inputs);
+    let shown = |flags: &[&str]| entry.check_filter(&test_args(&[&["-a"], flags].concat()));
+    assert!(shown(&["--derive", "Deserialize"]));
+    assert!(shown(&["--derive", "serde::Deserialize"]));
+    assert!(shown(&["--derive", "Serialize", "--derive", "Deserialize"]));
+    assert!(!shown(&["--derive", "Serialize"]));
+    assert!(!shown(&["--derive", "eserialize"]));
+    assert!(!shown(&["--derive", "serde::de::Deserializer"]));
+    assert_eq!(entry.derive_name(), Some("Deserialize"));
+    // Other kinds are unaffected
+    let entry = test_entry("function", &inputs);
+    assert!(entry.check_filter(&test_args(&["-a", "--derive", "Serialize"])));
+    assert_eq!(entry.derive_name(), None);
+}
+
+/// Whether one of `haystacks` contains one of `texts`. Whitespace is ignored,
+/// since tokens are printed with spaces between them, and so is case with
+/// `--ignore-case` in `args`.
+fn contains_any<'s>(
+    haystacks: impl IntoIterator<Item = &'s str>,
+    texts: &[String],
+    args: &ProcDebugArgs,
+) -> bool {
+    if texts.is_empty() {
+        return false;
+    }
+    let strip = |s: &str| args.fold(s).split_whitespace().collect::<String>();
+    let texts = texts.iter().map(|text| strip(text)).collect::<Vec<_>>();
+    haystacks.into_iter().any(|haystack| {
+        let haystack = strip(haystack);
+        texts.iter().any(|text| haystack.contains(text))
+    })
+}
+
+#[test]
+fn test_input_filter() {
+    let inputs = [
+        "Config".to_owned(),
+        quote!(
+            struct Config {
+                #[serde(flatten)]
+                extra: Extra,
+            }
+        )
+        .to_string(),
+    ];
+    let entry = test_entry("derive", &inputs);
+    let args = |flags: &[&str]| test_args(&[&["-a"], flags].concat());
+    assert!(entry.check_filter(&args(&["--input-contains", "#[serde(flatten)]"])));
+    assert!(entry.check_filter(&args(&[
+        "--input-contains",
+        "skip",
+        "--input-contains",
+        "extra: Extra"
+    ])));
+    assert!(!entry.check_filter(&args(&["--input-contains", "#[serde(skip)]"])));
+    assert!(!entry.check_filter(&args(&["--input-not-contains", "flatten"])));
+    assert!(entry.check_filter(&args(&["--input-not-contains", "skip"])));
+}
+
+#[test]
+fn test_check_filter() {
+    let entry = Entry {
+        file: "src/latest.rs",
+        ..test_entry("function", &[])
+    };
+    assert!(!entry.check_filter(&test_args(&["-n", "test", "my_macro"])));
+    assert!(entry.check_filter(&test_args(&["-n", "/\\btest\\b/", "my_macro"])));
+    assert!(entry.check_filter(&test_args(&["--regex", "-n", "^test", "^my_"])));
+    assert!(entry.check_filter(&test_args(&["--regex", "-p", "^my_crate::my_.*$"])));
+    assert!(!entry.check_filter(&test_args(&["--regex", "-p", "^my_macro$"])));
+    assert!(entry.check_filter(&test_args(&["-p", "my_macro"])));
+    assert!(entry.check_filter(&test_args(&["-a", "-k", "derive", "-k", "function"])));
+    assert!(!entry.check_filter(&test_args(&["-a", "-k", "derive"])));
+    assert!(!entry.check_filter(&test_args(&["-k", "attribute", "my_macro"])));
+    assert!(ProcDebugArgs::from_args(&["proc-debug"], &["-k", "derives"]).is_err());
+    assert_eq!(
+        MacroKind::parse_filter("unknown"),
+        Err(
+            "unknown macro kind `unknown`, expected one of: function, attribute, derive".to_owned()
+        )
+    );
+    // One query in the file and the other in the name
+    assert!(entry.check_filter(&test_args(&["--all-queries", "latest", "my_macro"])));
+    assert!(!entry.check_filter(&test_args(&["--all-queries", "latest", "other"])));
+    assert!(entry.check_filter(&test_args(&["latest", "other"])));
+    assert!(!entry.check_filter(&test_args(&["--all-queries"])));
+    assert!(entry.check_filter(&test_args(&["--all-queries", "-p", "my_crate", "other"])));
+    assert!(!entry.check_filter(&test_args(&["--all-queries", "-n", "latest", "my_macro"])));
+    assert!(!entry.check_filter(&test_args(&["My_Macro"])));
+    assert!(entry.check_filter(&test_args(&["-i", "My_Macro"])));
+    assert!(entry.check_filter(&test_args(&["--ignore-case", "-p", "MY_CRATE"])));
+    assert!(entry.check_filter(&test_args(&["-i", "--regex", "^MY_"])));
+    assert!(!entry.check_filter(&test_args(&["-i", "-n", "LATEST", "my_macro"])));
+    // --not-path by segment, before --all and --path
+    assert!(!entry.check_filter(&test_args(&["-a", "--not-path", "my_crate::*"])));
+    assert!(!entry.check_filter(&test_args(&["-p", "my_crate", "--not-path", "my_macro"])));
+    assert!(entry.check_filter(&test_args(&["-a", "--not-path", "my_cr"])));
+    assert!(!entry.check_filter(&test_args(&["-a", "--regex", "--not-path", "_macro$"])));
+    // The label of test_entry is `label`
+    assert!(entry.check_filter(&test_args(&["--label", "label"])));
+    assert!(!entry.check_filter(&test_args(&["--label", "lab"])));
+    assert!(entry.check_filter(&test_args(&["--label-prefix", "lab"])));
+    assert!(entry.check_filter(&test_args(&["-i", "--label", "LABEL"])));
+    assert!(!entry.check_filter(&test_args(&["-n", "latest", "--label", "label"])));
+    let err = ProcDebugArgs::parse_flags(&["--regex", "-n", "te(st"].map(str::to_owned))
+        .err()
+        .unwrap();
+    assert!(err.starts_with("invalid regex in --not `te(st`: regex parse error:"));
+    assert!(err.contains("\n    te(st\n      ^\n"));
+}
+
+impl<'a> Entry<'a> {
+    /// Whether `text` matches a filter `pattern`, as a regex or as a
+    /// substring.
+    fn matches(pattern: &Pattern, text: &str, args: &ProcDebugArgs) -> bool {
+        match &pattern.regex {
+            Some(compiled) => compiled.is_match(text),
+            None => args.fold(text).contains(&*args.fold(pattern)),
+        }
+    }
+
+    /// Whether the file of the definition or of the invocation matches one
+    /// of `globs`.
+    fn matches_file(&self, globs: &[Pattern]) -> bool {
+        if globs.is_empty() {
+            return false;
+        }
+        let files = std::iter::once(self.file).chain(self.call_site.as_ref().map(|(f, _)| &**f));
+        files.flat_map(path_forms).any(|path| {
+            globs.iter().any(|glob| {
+                glob.glob
+                    .as_ref()
+                    .is_some_and(|compiled| compiled.is_match(&path))
+            })
+        })
+    }
+
+    /// Whether the macro is of the package `name`, or `name@version`. `-` and
+    /// `_` are the same in names, as cargo takes them.
+    fn is_crate(&self, name: &str) -> bool {
+        let (name, version) = match name.split_once('@') {
+            Some((name, version)) => (name, Some(version)),
+            None => (name, None),
+        };
+        name.replace('-', "_") == self.crate_name.replace('-', "_")
+            && version.is_none_or(|version| version == self.crate_version)
+    }
+
+    /// The trait a derive macro derives, passed as its first input.
+    fn derive_name(&self) -> Option<&str> {
+        match self.macro_kind {
+            "derive" => self.macro_inputs.first().map(String::as_str),
+            _ => None,
+        }
+    }
+
+    /// Whether the macro is not a derive, or derives `name`, which may be
+    /// given as a path such as `serde::Deserialize`.
+    fn is_derive(&self, name: &str) -> bool {
+        self.derive_name().is_none_or(|derive| {
+            name.rsplit_once("::")
+                .map_or(name, |(_, last)| last)
+                .eq(derive)
+        })
+    }
+
+    /// Whether an input of the macro contains one of `texts`, see
+    /// [`contains_any`].
+    fn input_contains(&self, texts: &[String], args: &ProcDebugArgs) -> bool {
+        contains_any(self.macro_inputs.iter().map(String::as_str), texts, args)
+    }
+
+    /// Whether `module::macro` matches one of `patterns`, as a regex or by
+    /// segment, see [`path_matches`].
+    fn matches_path(&self, patterns: &[Pattern], args: &ProcDebugArgs) -> bool {
+        let path = format!("{}::{}", &self.modpath, &self.macro_name);
+        patterns.iter().any(|m| match &m.regex {
+            Some(compiled) => compiled.is_match(&path),
+            None => path_matches(&args.fold(m), &args.fold(&path)),
+        })
+    }
+
+    /// Whether `--not`, `--not-path` or `--not-file` hides the macro, for
+    /// `--nth`.
+    fn is_excluded(&self, args: &ProcDebugArgs) -> bool {
+        let content = [&self.label, &self.file, &self.modpath, &self.macro_name];
+        content
             .iter()
-            .any(|s| args.not.iter().any(|t| s.contains(t)))
+            .any(|s| args.not.iter().any(|t| Self::matches(t, s, args)))
+            || self.matches_path(&args.not_path, args)
+            || self.matches_file(&args.not_file)
+    }
+
+    /// Whether the macro passes the filters on its definition and input.
+    ///
+    /// `--not-path` and the filters applying even with `--all` come first and
+    /// hide the macro, then `--all` shows it, then `--not` hides it, and
+    /// finally `--path` or the queries show it.
+    fn check_filter(&self, args: &ProcDebugArgs) -> bool {
+        let content = [&self.label, &self.file, &self.modpath, &self.macro_name];
+
+        if self.matches_path(&args.not_path, args) {
+            return false;
+        }
+        if !args.kind.is_empty()
+            && !args
+                .kind
+                .contains(&MacroKind::from_str(self.macro_kind).unwrap())
         {
             return false;
         }
-        if args.path.iter().any(|m| {
-            m == &pattern
-                || pattern.starts_with(&format!("{}::", m))
-                || pattern.ends_with(&format!("::{}", m))
-        }) {
-            return true;
+        if !args.derive.is_empty() && !args.derive.iter().any(|d| self.is_derive(d)) {
+            return false;
+        }
+        if !args.file.is_empty() && !self.matches_file(&args.file)
+            || self.matches_file(&args.not_file)
+        {
+            return false;
+        }
+        if !args.lines.is_empty() && !args.lines.iter().any(|r| r.contains(self.line)) {
+            return false;
+        }
+        if !args.crate_name.is_empty() && !args.crate_name.iter().any(|c| self.is_crate(c))
+            || !args.crate_version.is_empty()
+                && !args.crate_version.iter().any(|v| v == self.crate_version)
+        {
+            return false;
+        }
+        if !args.input_contains.is_empty() && !self.input_contains(&args.input_contains, args)
+            || self.input_contains(&args.input_not_contains, args)
+        {
+            return false;
         }
         if content
             .iter()
-            .any(|s| args.queries.iter().any(|t| s.contains(t)))
+            .any(|s| args.not.iter().any(|t| Self::matches(t, s, args)))
         {
+            return false;
+        }
+        if args.all {
             return true;
         }
-        false
+        if self.matches_path(&args.path, args) || args.selects_label(self.label) {
+            return true;
+        }
+        let found = |query: &Pattern| content.iter().any(|s| Self::matches(query, s, args));
+        if args.all_queries {
+            !args.queries.is_empty() && args.queries.iter().all(found)
+        } else {
+            args.queries.iter().any(found)
+        }
+    }
+}
+
+#[test]
+fn test_filter_file() {
+    let path = std::env::temp_dir().join(format!("proc-debug-filters-{}", std::process::id()));
+    std::fs::write(
+        &path,
+        "# checked in filters\npath: serde_derive::Serialize\n\n  not : test\nquery: my macro\n",
+    )
+    .unwrap();
+    let path_flag = path.to_str().unwrap();
+    let mut args = test_args(&["-p", "a", "--filter-file", path_flag]);
+    args.read_filter_file().unwrap();
+    assert_eq!(args.path, ["a", "serde_derive::Serialize"]);
+    assert_eq!(args.not, ["test"]);
+    assert_eq!(args.queries, ["my macro"]);
+    std::fs::write(&path, "path: a\npaths: b\n").unwrap();
+    let mut args = test_args(&["--filter-file", path_flag]);
+    assert_eq!(
+        args.read_filter_file().unwrap_err(),
+        format!("{path_flag}:2: expected `path: ..`, `not: ..` or `query: ..`, found `paths: b`")
+    );
+    std::fs::remove_file(&path).unwrap();
+    assert!(args.read_filter_file().is_err());
+}
+
+#[test]
+fn test_cached_args() {
+    let flags = SplitFlags::new();
+    let first = ProcDebugArgs::split_flags_once(&flags, || Ok("-a 'my macro'".to_owned()));
+    assert_eq!(
+        first,
+        Some(&Ok(vec!["-a".to_owned(), "my macro".to_owned()]))
+    );
+    // Later values of the variable are not read
+    let second = ProcDebugArgs::split_flags_once(&flags, || Ok("--not-a-flag".to_owned()));
+    assert!(std::ptr::eq(first.unwrap(), second.unwrap()));
+    let unset = SplitFlags::new();
+    assert_eq!(
+        ProcDebugArgs::split_flags_once(&unset, || Err(std::env::VarError::NotPresent)),
+        None
+    );
+    assert_eq!(
+        ProcDebugArgs::split_flags_once(&unset, || Ok("-a".to_owned())),
+        None
+    );
+    let invalid = SplitFlags::new();
+    let var = || Err(std::env::VarError::NotUnicode(std::ffi::OsString::new()));
+    assert_eq!(
+        ProcDebugArgs::split_flags_once(&invalid, var),
+        Some(&Err("PROC_DEBUG_FLAGS is not valid UTF-8".to_owned()))
+    );
+}
+
+#[cfg(test)]
+fn test_args(flags: &[&str]) -> ProcDebugArgs {
+    let mut args = ProcDebugArgs::from_args(&["proc-debug"], flags).unwrap();
+    args.compile_patterns().unwrap();
+    args.apply_first().unwrap();
+    args
+}
+
+#[cfg(test)]
+fn test_entry<'a>(macro_kind: &'a str, macro_inputs: &'a [String]) -> Entry<'a> {
+    Entry {
+        label: "label",
+        file: "lib.rs",
+        line: 1,
+        modpath: "my_crate",
+        crate_name: "my-crate",
+        crate_version: "0.1.0",
+        macro_kind,
+        macro_name: "my_macro",
+        macro_inputs,
+        macro_arg_names: &[],
+        call_site: None,
+    }
+}
+
+#[test]
+fn test_proc_wrapper_passthrough() {
+    let tokens = quote! { struct A; impl A { fn f() {} } };
+    let inputs = ["A".to_owned(), "struct A;".to_owned()];
+    let entry = test_entry("derive", &inputs);
+    let none = Destinations::default();
+    let shown = wrap(&entry, Some(&test_args(&["-a"])), &none, || tokens.clone());
+    let hidden = wrap(&entry, Some(&test_args(&["-n", "my_crate"])), &none, || {
+        tokens.clone()
+    });
+    let disabled = wrap(&entry, None, &none, || tokens.clone());
+    for ret in [shown, hidden, disabled] {
+        assert!(tokens_eq(ret.clone(), tokens.clone(), false));
+        assert_eq!(ret.to_string(), tokens.to_string());
+    }
+}
+
+#[test]
+fn test_read_only_paths() {
+    let dir = std::env::temp_dir().join(format!("proc-debug-read-only-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // Paths under a regular file cannot be written, even by the superuser who
+    // ignores the permissions of the directory.
+    let denied = dir.join("blocker");
+    std::fs::write(&denied, "").unwrap();
+    let mut permissions = std::fs::metadata(&dir).unwrap().permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(&dir, permissions.clone()).unwrap();
+    let path = |name: &str| denied.join(name).to_str().unwrap().to_owned();
+    let destinations = Destinations {
+        out: Some(denied.join("out.tsv")),
+        results: Some(denied.join("results.txt")),
+    };
+    let tokens = quote! { struct A; impl A { fn f() {} } };
+    let inputs = ["A".to_owned(), "struct A;".to_owned()];
+    let entry = test_entry("derive", &inputs);
+    let (tee, dump) = (path("tee.txt"), path("dump"));
+    for flags in [
+        &["-a", "--tee", &tee, "--dump-dir", &dump][..],
+        &["-a", "--format", "tsv"],
+    ] {
+        let ret = wrap(&entry, Some(&test_args(flags)), &destinations, || {
+            tokens.clone()
+        });
+        assert_eq!(ret.to_string(), tokens.to_string());
+    }
+    assert!(denied.is_file());
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    std::fs::set_permissions(&dir, permissions).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Start of the first expansion of this process.
+static FIRST_EXPANSION: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+
+/// Whether the banner of the flags was shown by this process.
+static BANNER_SHOWN: std::sync::Mutex<bool> = std::sync::Mutex::new(false);
+
+thread_local! {
+    /// Invocations on this thread so far, see [`count`].
+    static COUNTER: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+
+    /// Expansions on this thread which passed the filters, for `--skip`.
+    /// Like [`COUNTER`], these count per thread so that a run shows the same
+    /// expansions whatever the scheduling of a parallel front-end.
+    static MATCHED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+
+    /// Matched expansions on this thread of each macro label, for
+    /// `--count-per-macro` and `--first`.
+    static SHOWN_PER_MACRO: std::cell::RefCell<std::collections::BTreeMap<String, usize>> =
+        const { std::cell::RefCell::new(std::collections::BTreeMap::new()) };
+
+    /// Labels of the instrumented macros running on this thread, outermost
+    /// first. A macro calling another one directly, rather than through its
+    /// output, runs it nested.
+    /// With what the expansions nested in each showed, held until its input
+    /// is shown.
+    static CALL_STACK: std::cell::RefCell<Vec<(String, Held)>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Pops the [`CALL_STACK`] when dropped, even if the macro panics.
+struct CallGuard;
+
+impl CallGuard {
+    fn enter(label: &str) -> Self {
+        CALL_STACK.with(|stack| stack.borrow_mut().push((label.to_owned(), Held::default())));
+        CallGuard
+    }
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        CALL_STACK.with(|stack| stack.borrow_mut().pop());
     }
 }
 
+#[test]
+fn test_nesting() {
+    let outer = Entry {
+        label: "outer",
+        ..test_entry("function", &[])
+    };
+    let inner = test_entry("function", &[]);
+    let none = Destinations::default();
+    wrap(&outer, None, &none, || {
+        wrap(&inner, None, &none, || {
+            assert_eq!(nesting(), (2, Some("label".to_owned())));
+            quote!()
+        });
+        assert_eq!(nesting(), (1, Some("outer".to_owned())));
+        quote!()
+    });
+    assert_eq!(nesting(), (0, None));
+    // A nested expansion waits for the input of the one running it
+    let args = test_args(&["-a", "--no-banner", "--no-hints", "--color", "never"]);
+    wrap(&outer, Some(&args), &none, || {
+        wrap(&inner, Some(&args), &none, || {
+            quote!(
+                struct A;
+            )
+        });
+        let held = CALL_STACK.with(|stack| stack.borrow()[0].1.out.clone());
+        let held = String::from_utf8(held).unwrap();
+        assert!(held.starts_with("    👉 input of my_crate::my_macro (lib.rs:1) #"));
+        assert!(held.contains("(nested in outer)"));
+        quote!()
+    });
+    assert!(take_held().is_empty());
+}
+
+/// Returns the number of instrumented macros running, and the label of the
+/// innermost one.
+fn nesting() -> (usize, Option<String>) {
+    CALL_STACK.with(|stack| {
+        let stack = stack.borrow();
+        (stack.len(), stack.last().map(|(label, _)| label.clone()))
+    })
+}
+
+/// Takes what the expansions nested in the innermost running macro showed.
+fn take_held() -> Held {
+    CALL_STACK.with(|stack| {
+        stack
+            .borrow_mut()
+            .last_mut()
+            .map(|(_, held)| std::mem::take(held))
+            .unwrap_or_default()
+    })
+}
+
+/// Writes what the expansions nested in a macro showed, after its own input
+/// or when it is not shown: into what the macro running it holds, if any,
+/// or else to stdout and the `--tee` file.
+fn release(held: Held, args: &ProcDebugArgs) {
+    if held.is_empty() {
+        return;
+    }
+    // The stack may be gone when the expansions held back by --last are shown
+    // as the thread exits
+    let mut held = Some(held);
+    let _ = CALL_STACK.try_with(|stack| {
+        if let Some((_, parent)) = stack.borrow_mut().last_mut() {
+            parent.append(held.take().unwrap());
+        }
+    });
+    if let Some(held) = held {
+        let _ = std::io::stdout().lock().write_all(&held.out);
+        if let Some(mut tee) = args.tee.as_deref().and_then(capability::open_append) {
+            let _ = tee.write_all(&held.tee);
+        }
+    }
+}
+
+#[test]
+fn test_count() {
+    // Every invocation is counted, shown or not, so that the indexes of a run
+    // showing everything pick the same expansions with --nth
+    let first = count();
+    let entry = test_entry("function", &[]);
+    let none = Destinations::default();
+    wrap(&entry, None, &none, TokenStream::new);
+    // Invocations on other threads, as of the other tests, do not shift them
+    std::thread::spawn(|| (count(), count())).join().unwrap();
+    wrap(
+        &entry,
+        Some(&test_args(&["-n", "my_macro"])),
+        &none,
+        TokenStream::new,
+    );
+    assert_eq!(count(), first + 3);
+    assert_eq!(std::thread::spawn(count).join().unwrap(), 0);
+}
+
+/// Returns the index of this invocation among those of its thread, starting
+/// from 0. Every invocation is counted, shown or not.
+///
+/// rustc expands macros in order on one thread, so the indexes follow the
+/// expansion order and are the same between runs on the same sources, while
+/// a counter shared by the threads of a parallel front-end would depend on
+/// their scheduling. Each such thread numbers its own invocations instead.
+fn count() -> usize {
+    COUNTER.with(|counter| {
+        let n = counter.get();
+        counter.set(n + 1);
+        n
+    })
+}
+
+#[test]
+fn test_timestamp() {
+    let timestamp = Timestamp {
+        wall: std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_123),
+        offset: std::time::Duration::from_millis(1500),
+    };
+    let local = timestamp.local();
+    assert_eq!(local.len(), "2023-11-14T22:13:20.123+00:00".len());
+    assert!(local.contains(".123"));
+    assert!(timestamp.prefix().ends_with(" +1.500s] "));
+}
+
+/// When an expansion started.
+///
+/// Taken for every invocation, so it is only formatted once an expansion is
+/// shown or recorded.
+struct Timestamp {
+    wall: std::time::SystemTime,
+    /// Time since the first expansion of this process
+    offset: std::time::Duration,
+}
+
+impl Timestamp {
+    fn now() -> Self {
+        let offset = FIRST_EXPANSION
+            .get_or_init(std::time::Instant::now)
+            .elapsed();
+        Timestamp {
+            wall: std::time::SystemTime::now(),
+            offset,
+        }
+    }
+
+    /// Local time, in ISO 8601 with milliseconds.
+    fn local(&self) -> String {
+        jiff::Zoned::try_from(self.wall)
+            .map(|zoned| zoned.strftime("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string())
+            .unwrap_or_default()
+    }
+
+    /// Prefix of the headers with `--timestamps`.
+    fn prefix(&self) -> String {
+        format!("[{} +{:.3}s] ", self.local(), self.offset.as_secs_f64())
+    }
+}
+
+/// Returns `true` on the first call in this process, even when expanding on
+/// several threads.
+fn first_banner() -> bool {
+    let mut shown = BANNER_SHOWN.lock().unwrap_or_else(|e| e.into_inner());
+    !std::mem::replace(&mut *shown, true)
+}
+
+/// Returns the location of the macro invocation being expanded. Outside of a
+/// proc-macro, there is none.
+fn call_site() -> Option<(String, usize)> {
+    let span = proc_macro2::Span::call_site();
+    let line = span.start().line;
+    let file = span.file();
+    (line > 0 && !file.is_empty() && !file.starts_with('<')).then_some((file, line))
+}
+
 #[doc(hidden)]
+#[allow(clippy::too_many_arguments)]
 pub fn proc_wrapper<F: FnOnce() -> TokenStream>(
     label: &str,
     file: &str,
     line: usize,
     modpath: &str,
+    crate_name: &str,
+    crate_version: &str,
     macro_kind: &str,
     macro_name: &str,
     macro_inputs: &[String],
+    macro_arg_names: &[&str],
     f: F,
 ) -> TokenStream {
     let entry = Entry {
@@ -421,38 +2545,1115 @@ pub fn proc_wrapper<F: FnOnce() -> TokenStream>(
         file,
         line,
         modpath,
+        crate_name,
+        crate_version,
         macro_kind,
         macro_name,
         macro_inputs,
+        macro_arg_names,
+        call_site: call_site(),
     };
-    let ret = f();
-    if let Some(args) = ProcDebugArgs::from_env() {
-        if entry.check_filter(&args) {
-            show_macro_call(modpath, macro_name, file, line, macro_kind, macro_inputs);
-            let tokens: TokenStream = ret.into();
-            let output =
-                MacroOutput::from_tokens(tokens.clone(), &MacroKind::from_str(macro_kind).unwrap());
-            let simplified = simplify_and_replace(
-                tokens,
-                if args.verbose {
-                    usize::MAX
-                } else {
-                    args.depth.unwrap_or(4)
-                },
-            );
+    wrap(&entry, ProcDebugArgs::cached(), Destinations::get(), f)
+}
 
-            show_macro_output(
-                modpath,
-                macro_name,
-                file,
-                line,
-                &unreplace(simplified).to_string(),
-            );
-            output.emit().into()
+/// Shows `tokens` under `label`, like `dbg!`, for intermediate token streams
+/// of a proc-macro rather than its output.
+///
+/// The tokens are shown with the depth and formatting of `PROC_DEBUG_FLAGS`
+/// when `label` passes its filters: `--all`, or a query matching it and no
+/// `--not`. Nothing is done when `PROC_DEBUG_FLAGS` is not set.
+///
+/// ```ignore
+/// let fields = expand_fields(&input);
+/// proc_debug::debug_tokens("my_derive fields", &fields);
+/// ```
+#[track_caller]
+pub fn debug_tokens(label: &str, tokens: &TokenStream) {
+    let Some(args) = ProcDebugArgs::cached() else {
+        return;
+    };
+    if !label_matches(args, label)
+        || args.format != Format::Text
+        || args.summary
+        || budget::exceeded(args.max_total_bytes)
+    {
+        return;
+    }
+    let caller = std::panic::Location::caller();
+    let location = format!("{}:{}", caller.file(), caller.line());
+    let mut display = DisplayContext::stdout(args.palette, args.color, args.paging)
+        .tee(args.tee.as_deref())
+        .ascii(args.ascii)
+        .heading_format(args.heading_format.as_deref());
+    show_tokens(&mut display, args, label, &location, tokens);
+}
+
+/// Whether proc-debug is active, that is `PROC_DEBUG_FLAGS` is set and
+/// parses, so that work only useful for debugging can be skipped otherwise.
+///
+/// The flags are read and parsed on the first call of this or an instrumented
+/// macro in the process, and later changes of the environment are ignored.
+/// It tells nothing of the filters, which [`debug_tokens`] applies.
+///
+/// ```ignore
+/// if proc_debug::is_enabled() {
+///     proc_debug::debug_tokens("my_derive fields", &expand_fields(&input));
+/// }
+/// ```
+pub fn is_enabled() -> bool {
+    ProcDebugArgs::cached().is_some()
+}
+
+/// Whether the `label` of [`debug_tokens`] passes the filters of `args`.
+fn label_matches(args: &ProcDebugArgs, label: &str) -> bool {
+    if args.not.iter().any(|t| Entry::matches(t, label, args)) {
+        return false;
+    }
+    let found = |query: &Pattern| Entry::matches(query, label, args);
+    args.all
+        || args.selects_label(label)
+        || if args.all_queries {
+            !args.queries.is_empty() && args.queries.iter().all(found)
         } else {
-            ret
+            args.queries.iter().any(found)
+        }
+}
+
+#[test]
+fn test_show_tokens() {
+    let tokens = quote! { fn f() { g(h(1)) } };
+    let recorder = display::Recorder::default();
+    let args = test_args(&["-d", "2", "--ellipsis", "...", "fields"]);
+    assert!(label_matches(&args, "my_derive fields"));
+    assert!(!label_matches(&args, "my_derive variants"));
+    assert!(!label_matches(
+        &test_args(&["-n", "derive", "fields"]),
+        "my_derive fields"
+    ));
+    // Exactly, unlike queries
+    let args_label = test_args(&["--label", "my_derive"]);
+    assert!(!label_matches(&args_label, "my_derive fields"));
+    assert!(label_matches(&args_label, "my_derive"));
+    let args_prefix = test_args(&["--label-prefix", "codegen/"]);
+    assert!(label_matches(&args_prefix, "codegen/fields"));
+    assert!(!label_matches(&args_prefix, "my_derive codegen/fields"));
+    show_tokens(
+        &mut DisplayContext::new(recorder.clone()),
+        &args,
+        "my_derive fields",
+        "src/lib.rs:12",
+        &tokens,
+    );
+    assert_eq!(
+        recorder.text(),
+        "<fg:Black,bg:Cyan,bold>👉 tokens of my_derive fields (src/lib.rs:12)</>\n\
+         \x20 fn f () {\n\
+         \x20     g (... /* 3 more tokens */)\n\
+         \x20 }\n\n"
+    );
+}
+
+/// Shows `tokens` of [`debug_tokens`], called at `location`.
+fn show_tokens(
+    display: &mut DisplayContext,
+    args: &ProcDebugArgs,
+    label: &str,
+    location: &str,
+    tokens: &TokenStream,
+) {
+    let (depth, stmt_limit) = if args.verbose {
+        (usize::MAX, usize::MAX)
+    } else {
+        (
+            args.brace_depth.or(args.depth).unwrap_or(DEFAULT_DEPTH),
+            args.stmt_limit.or(args.depth).unwrap_or(DEFAULT_DEPTH),
+        )
+    };
+    let content = replace_ellipsis(
+        &format_tokens(
+            unreplace(simplify_and_replace(tokens.clone(), depth, stmt_limit)),
+            args.rustfmt,
+            args.show_invisible_groups,
+        ),
+        &args.ellipsis,
+    );
+    display.tokens(
+        label,
+        location,
+        &truncate_output(content, args.max_output_bytes),
+    );
+}
+
+fn item_ident(item: &Item) -> Option<&Ident> {
+    match item {
+        Item::Const(i) => Some(&i.ident),
+        Item::Enum(i) => Some(&i.ident),
+        Item::ExternCrate(i) => Some(&i.ident),
+        Item::Fn(i) => Some(&i.sig.ident),
+        Item::Macro(i) => i.ident.as_ref(),
+        Item::Mod(i) => Some(&i.ident),
+        Item::Static(i) => Some(&i.ident),
+        Item::Struct(i) => Some(&i.ident),
+        Item::Trait(i) => Some(&i.ident),
+        Item::TraitAlias(i) => Some(&i.ident),
+        Item::Type(i) => Some(&i.ident),
+        Item::Union(i) => Some(&i.ident),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_classify() {
+    let inputs = ["".to_owned(), "struct A;".to_owned()];
+    let entry = test_entry("attribute", &inputs);
+    let outcome = |ret: &str| classify(&entry, &TokenStream::from_str(ret).unwrap(), false);
+    assert_eq!(outcome("struct A;"), Outcome::Unchanged);
+    assert_eq!(outcome("#[derive(Debug)] struct A;"), Outcome::Changed);
+    assert_eq!(outcome("struct A; impl A {}"), Outcome::Changed);
+    assert_eq!(outcome(""), Outcome::Removed);
+    assert_eq!(outcome("impl B for C {}"), Outcome::Replaced);
+    assert_eq!(outcome("struct A2;"), Outcome::Replaced);
+    let derive = test_entry("derive", &inputs);
+    assert_eq!(classify(&derive, &quote!(), false), Outcome::Changed);
+}
+
+/// Classifies the output `ret` of an attribute macro against the item it was
+/// applied to. Outputs of other macros are always [`Outcome::Changed`].
+fn classify(entry: &Entry, ret: &TokenStream, strict_groups: bool) -> Outcome {
+    let item = match entry.macro_kind {
+        "attribute" => entry.macro_inputs.get(1),
+        _ => None,
+    };
+    let Some(item) = item.and_then(|item| TokenStream::from_str(item).ok()) else {
+        return Outcome::Changed;
+    };
+    if tokens_eq(item.clone(), ret.clone(), !strict_groups) {
+        return Outcome::Unchanged;
+    }
+    if ret.is_empty() {
+        return Outcome::Removed;
+    }
+    let ident = parse2::<Item>(item)
+        .ok()
+        .and_then(|item| item_ident(&item).cloned());
+    match (ident, parse2::<File>(ret.clone())) {
+        (Some(ident), Ok(file)) if !file.items.iter().any(|o| item_ident(o) == Some(&ident)) => {
+            Outcome::Replaced
+        }
+        _ => Outcome::Changed,
+    }
+}
+
+#[test]
+fn test_is_unchanged() {
+    let inputs = ["".to_owned(), "struct A;".to_owned()];
+    let ret = |s: &str| TokenStream::from_str(s).unwrap();
+    let attribute = test_entry("attribute", &inputs);
+    assert!(is_unchanged(&attribute, &ret("struct   A ;"), false));
+    assert!(!is_unchanged(
+        &attribute,
+        &ret("struct A; impl A {}"),
+        false
+    ));
+    let derive = test_entry("derive", &inputs);
+    assert!(is_unchanged(&derive, &ret(""), false));
+    assert!(!is_unchanged(&derive, &ret("impl A {}"), false));
+    let function = test_entry("function", &inputs);
+    assert!(!is_unchanged(&function, &ret(""), false));
+}
+
+/// Whether the output `ret` changes nothing, which `--only-changed` hides:
+/// an attribute macro gave back its item, or a derive macro emitted nothing.
+/// Function-like macros always change something.
+fn is_unchanged(entry: &Entry, ret: &TokenStream, strict_groups: bool) -> bool {
+    match entry.macro_kind {
+        "derive" => ret.is_empty(),
+        _ => classify(entry, ret, strict_groups) == Outcome::Unchanged,
+    }
+}
+
+fn contains_compile_error(tokens: TokenStream) -> bool {
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        match token {
+            TokenTree::Ident(ident) if ident == "compile_error" => {
+                if matches!(tokens.peek(), Some(TokenTree::Punct(p)) if p.as_char() == '!') {
+                    return true;
+                }
+            }
+            TokenTree::Group(g) if contains_compile_error(g.stream()) => return true,
+            _ => (),
+        }
+    }
+    false
+}
+
+#[test]
+fn test_is_erroneous() {
+    let entry = test_entry("function", &[]);
+    let erroneous = |ret: &str| is_erroneous(&entry, &TokenStream::from_str(ret).unwrap());
+    assert!(!erroneous("struct A; fn f() { g(1) }"));
+    assert!(!erroneous("1 + 2"));
+    assert!(erroneous(
+        "const _: () = { ::core::compile_error!(\"no\"); };"
+    ));
+    assert!(erroneous("struct A fn"));
+}
+
+#[test]
+fn test_parse_error() {
+    let entry = test_entry("attribute", &[]);
+    let error = |ret: &str| parse_error(&entry, &TokenStream::from_str(ret).unwrap());
+    assert_eq!(error("struct A<T> where T: Clone { a: T }"), None);
+    assert_eq!(
+        error("struct A;\nfn f<T>() where T: Clone struct B;").as_deref(),
+        Some(
+            "output does not parse as any known Rust item form: \
+             expected curly braces (line 2, column 31)"
+        )
+    );
+}
+
+/// Returns a description of the first error if the output of a macro does not
+/// parse.
+fn parse_error(entry: &Entry, ret: &TokenStream) -> Option<String> {
+    let kind = MacroKind::from_str(entry.macro_kind).unwrap();
+    match MacroOutput::from_tokens(ret.clone(), &kind) {
+        MacroOutput::Other(tokens)
+            if !(kind == MacroKind::Function && parse2::<Expr>(tokens.clone()).is_ok()) => {}
+        _ => return None,
+    }
+    // Parsing the printed output locates the error in it, unless the compiler
+    // lexes the string, which gives spans without a location.
+    let error = match syn::parse_file(&indent_tokens(ret.clone(), false)) {
+        Err(error) => error,
+        Ok(_) => parse2::<File>(ret.clone()).err()?,
+    };
+    let span = error.span();
+    let position = if span.file().starts_with("<parsed string") {
+        let start = span.start();
+        format!(" (line {}, column {})", start.line, start.column + 1)
+    } else {
+        String::new()
+    };
+    Some(format!(
+        "output does not parse as any known Rust item form: {error}{position}"
+    ))
+}
+
+/// Returns whether the output of a macro does not parse or invokes
+/// `compile_error!`, which `--errors-only` selects.
+fn is_erroneous(entry: &Entry, ret: &TokenStream) -> bool {
+    parse_error(entry, ret).is_some() || contains_compile_error(ret.clone())
+}
+
+#[test]
+fn test_sink() {
+    let sink = VecSink::default();
+    set_sink(Box::new(sink.clone()));
+    let inputs = ["struct A;".to_owned()];
+    let entry = Entry {
+        label: "test_sink",
+        ..test_entry("derive", &inputs)
+    };
+    let args = test_args(&["-a", "--sink-only"]);
+    let none = Destinations::default();
+    wrap(&entry, Some(&args), &none, || quote!(impl A {}));
+    wrap(&entry, None, &none, || quote!());
+    take_sink();
+    let records = sink
+        .records()
+        .into_iter()
+        .filter(|r| r.label == "test_sink")
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].inputs, inputs);
+    assert_eq!(records[0].output, "impl A {\n}");
+    assert_eq!(records[1].output, "");
+}
+
+/// Builds the record of the invocation `index` of `entry`, which output
+/// `ret` in `duration`.
+fn macro_record(
+    entry: &Entry,
+    index: usize,
+    ret: &TokenStream,
+    duration: std::time::Duration,
+    started: &Timestamp,
+) -> MacroRecord {
+    let &Entry {
+        label,
+        file,
+        line,
+        modpath,
+        macro_kind,
+        macro_name,
+        macro_inputs,
+        ..
+    } = entry;
+    MacroRecord {
+        index,
+        label: label.to_owned(),
+        kind: macro_kind.to_owned(),
+        modpath: modpath.to_owned(),
+        macro_name: macro_name.to_owned(),
+        def_file: file.to_owned(),
+        def_line: line,
+        inputs: macro_inputs.to_vec(),
+        arg_names: entry
+            .macro_arg_names
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+        input_tokens: macro_inputs
+            .iter()
+            .filter_map(|s| TokenStream::from_str(s).ok())
+            .map(count_tokens)
+            .sum(),
+        output_tokens: count_tokens(ret.clone()),
+        output: indent_tokens(ret.clone(), false),
+        duration_ms: duration.as_secs_f64() * 1000.0,
+        timestamp: started.local(),
+        offset_ms: started.offset.as_secs_f64() * 1000.0,
+    }
+}
+
+/// Whether the expansion of `entry` into `ret` passes every filter of
+/// `args`.
+fn is_matched(entry: &Entry, ret: &TokenStream, args: &ProcDebugArgs) -> bool {
+    if !entry.check_filter(args) {
+        return false;
+    }
+    // The output is only rendered for the filters on it
+    if !args.output_contains.is_empty() || !args.output_not_contains.is_empty() {
+        let output = ret.to_string();
+        if !args.output_contains.is_empty()
+            && !contains_any([&*output], &args.output_contains, args)
+            || contains_any([&*output], &args.output_not_contains, args)
+        {
+            return false;
+        }
+    }
+    if args.removed_only && classify(entry, ret, args.strict_groups) != Outcome::Removed {
+        return false;
+    }
+    if args.errors_only && !is_erroneous(entry, ret) {
+        return false;
+    }
+    if args.only_changed && is_unchanged(entry, ret, args.strict_groups) {
+        return false;
+    }
+    if let Some(kind) = args.classified_as {
+        let kind_of = MacroKind::from_str(entry.macro_kind).unwrap();
+        if MacroOutput::from_tokens(ret.clone(), &kind_of).name() != kind {
+            return false;
+        }
+    }
+    true
+}
+
+#[test]
+fn test_output_filter() {
+    let entry = test_entry("function", &[]);
+    let ret = quote! { unsafe fn generated_get(p: *const u8) -> u8 { *p } };
+    let matched = |flags: &[&str]| is_matched(&entry, &ret, &test_args(&[&["-a"], flags].concat()));
+    assert!(matched(&["--output-contains", "unsafe"]));
+    assert!(matched(&["--output-contains", "generated_get(p"]));
+    assert!(!matched(&["--output-contains", "static"]));
+    assert!(matched(&[
+        "--output-contains",
+        "static",
+        "--output-contains",
+        "*const"
+    ]));
+    assert!(!matched(&["--output-not-contains", "unsafe fn"]));
+    assert!(matched(&["--output-not-contains", "impl"]));
+}
+
+#[test]
+fn test_skipped() {
+    let matched = std::cell::Cell::new(0);
+    let skips = (0..4).map(|_| skipped(&matched, 2)).collect::<Vec<_>>();
+    assert_eq!(skips, [true, true, false, false]);
+    assert!(!skipped(&std::cell::Cell::new(0), 0));
+}
+
+/// Counts a matched expansion in `matched`, and returns whether it is one of
+/// the first `skip`.
+fn skipped(matched: &std::cell::Cell<usize>, skip: usize) -> bool {
+    let n = matched.get();
+    matched.set(n + 1);
+    n < skip
+}
+
+#[test]
+fn test_over_limit() {
+    let shown = std::cell::RefCell::new(std::collections::BTreeMap::new());
+    let over = ["a", "a", "b", "a", "b", "b"].map(|label| over_limit(&shown, label, 2));
+    assert_eq!(over, [false, false, false, true, false, true]);
+    assert!(!over_limit(&shown, "a", 0));
+    let limit = |flags: &[&str]| {
+        ProcDebugArgs::parse_flags(&flags.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+            .map(|args| args.count_per_macro)
+    };
+    assert_eq!(limit(&["--first", "3"]), Ok(3));
+    assert_eq!(limit(&["--first", "3", "--count-per-macro", "3"]), Ok(3));
+    assert_eq!(
+        limit(&["--first", "3", "--count-per-macro", "5"]),
+        Err("--first 3 contradicts --count-per-macro 5, which it is another name for".to_owned())
+    );
+}
+
+/// Counts a matched expansion of `label` in `shown`, and returns whether more
+/// than `limit` of its expansions matched, 0 being unlimited.
+fn over_limit(
+    shown: &std::cell::RefCell<std::collections::BTreeMap<String, usize>>,
+    label: &str,
+    limit: usize,
+) -> bool {
+    if limit == 0 {
+        return false;
+    }
+    let mut shown = shown.borrow_mut();
+    let count = shown.entry(label.to_owned()).or_default();
+    *count += 1;
+    *count > limit
+}
+
+#[test]
+fn test_tail() {
+    let mut tail = Tail::default();
+    for (index, label) in ["a", "a", "b", "a", "a", "b"].into_iter().enumerate() {
+        tail.hold(label, index, label, 2);
+    }
+    let held = tail.drain();
+    assert_eq!(held, [(2, "b"), (3, "a"), (4, "a"), (5, "b")]);
+    assert!(tail.drain().is_empty());
+}
+
+/// The last expansions of each macro label held back by `--last`, with their
+/// invocation index.
+struct Tail<T>(std::collections::BTreeMap<String, std::collections::VecDeque<(usize, T)>>);
+
+impl<T> Default for Tail<T> {
+    fn default() -> Self {
+        Tail(std::collections::BTreeMap::new())
+    }
+}
+
+impl<T> Tail<T> {
+    /// Holds back `item`, forgetting the oldest one of `label` beyond `last`.
+    fn hold(&mut self, label: &str, index: usize, item: T, last: usize) {
+        let held = self.0.entry(label.to_owned()).or_default();
+        held.push_back((index, item));
+        while held.len() > last {
+            held.pop_front();
+        }
+    }
+
+    /// Takes the items held back, in the order of their invocations.
+    fn drain(&mut self) -> Vec<(usize, T)> {
+        let mut held = std::mem::take(&mut self.0)
+            .into_values()
+            .flatten()
+            .collect::<Vec<_>>();
+        held.sort_by_key(|(index, _)| *index);
+        held
+    }
+}
+
+/// A matched expansion held back by `--last`, with what showing it takes once
+/// its macro returned. The output is kept as text, as the token streams of the
+/// compiler are only valid during their expansion.
+struct Deferred {
+    label: String,
+    file: String,
+    line: usize,
+    modpath: String,
+    crate_name: String,
+    crate_version: String,
+    macro_kind: String,
+    macro_name: String,
+    macro_inputs: Vec<String>,
+    macro_arg_names: Vec<String>,
+    call_site: Option<(String, usize)>,
+    ret: String,
+    duration: std::time::Duration,
+    started: Timestamp,
+    nesting: (usize, Option<String>),
+    /// What the expansions nested in it showed
+    held: Held,
+}
+
+impl Deferred {
+    fn new(
+        entry: &Entry,
+        ret: &TokenStream,
+        duration: std::time::Duration,
+        started: Timestamp,
+        nesting: (usize, Option<String>),
+        held: Held,
+    ) -> Self {
+        Deferred {
+            label: entry.label.to_owned(),
+            file: entry.file.to_owned(),
+            line: entry.line,
+            modpath: entry.modpath.to_owned(),
+            crate_name: entry.crate_name.to_owned(),
+            crate_version: entry.crate_version.to_owned(),
+            macro_kind: entry.macro_kind.to_owned(),
+            macro_name: entry.macro_name.to_owned(),
+            macro_inputs: entry.macro_inputs.to_vec(),
+            macro_arg_names: entry
+                .macro_arg_names
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            call_site: entry.call_site.clone(),
+            ret: ret.to_string(),
+            duration,
+            started,
+            nesting,
+            held,
+        }
+    }
+
+    fn show(mut self, args: &ProcDebugArgs, index: usize) {
+        let macro_arg_names = self
+            .macro_arg_names
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        let entry = Entry {
+            label: &self.label,
+            file: &self.file,
+            line: self.line,
+            modpath: &self.modpath,
+            crate_name: &self.crate_name,
+            crate_version: &self.crate_version,
+            macro_kind: &self.macro_kind,
+            macro_name: &self.macro_name,
+            macro_inputs: &self.macro_inputs,
+            macro_arg_names: &macro_arg_names,
+            call_site: self.call_site.clone(),
+        };
+        let ret = TokenStream::from_str(&self.ret).unwrap_or_default();
+        show_matched(
+            &entry,
+            args,
+            Destinations::get(),
+            index,
+            &ret,
+            self.duration,
+            &self.started,
+            false,
+            self.nesting.clone(),
+            &mut self.held,
+        );
+        release(self.held, args);
+    }
+}
+
+/// Shows the expansions held back by `--last` when its thread exits. There is
+/// no hook running after the last expansion of a rustc process, and this one
+/// does not run when the process exits before the thread.
+struct LastFlusher(Tail<Deferred>);
+
+impl Drop for LastFlusher {
+    fn drop(&mut self) {
+        let Some(args) = ProcDebugArgs::cached() else {
+            return;
+        };
+        for (index, deferred) in self.0.drain() {
+            deferred.show(args, index);
+        }
+    }
+}
+
+thread_local! {
+    static LAST: std::cell::RefCell<LastFlusher> =
+        std::cell::RefCell::new(LastFlusher(Tail::default()));
+}
+
+/// Expansions evaluated before hinting that the filters matched none.
+const HINT_AFTER: usize = 50;
+
+/// Labels of the rejected macros shown as examples in the hint.
+const HINT_EXAMPLES: usize = 5;
+
+/// What [`hint_due`] tracks of the expansions of this process.
+struct HintState {
+    evaluated: usize,
+    any_matched: bool,
+    rejected: Vec<String>,
+}
+
+static HINTS: std::sync::Mutex<HintState> = std::sync::Mutex::new(HintState {
+    evaluated: 0,
+    any_matched: false,
+    rejected: Vec::new(),
+});
+
+#[test]
+fn test_hint_due() {
+    let new = || {
+        std::sync::Mutex::new(HintState {
+            evaluated: 0,
+            any_matched: false,
+            rejected: Vec::new(),
+        })
+    };
+    let state = new();
+    let labels = ["a", "b", "a", "c", "d", "e", "f"];
+    let due = (0..HINT_AFTER + 1)
+        .map(|n| hint_due(&state, labels[n % labels.len()], false))
+        .collect::<Vec<_>>();
+    assert!(due[..HINT_AFTER - 1].iter().all(Option::is_none));
+    assert_eq!(
+        due[HINT_AFTER - 1].as_deref(),
+        Some(&["a", "b", "c", "d", "e"].map(str::to_owned)[..])
+    );
+    assert_eq!(due[HINT_AFTER], None);
+    // Nothing to hint once an expansion matched
+    let state = new();
+    hint_due(&state, "a", true);
+    assert!((0..HINT_AFTER).all(|_| hint_due(&state, "b", false).is_none()));
+}
+
+/// Counts an expansion of `label` in `state`, and returns the examples of
+/// rejected labels once [`HINT_AFTER`] expansions were evaluated without any
+/// matched, and `None` otherwise.
+fn hint_due(
+    state: &std::sync::Mutex<HintState>,
+    label: &str,
+    matched: bool,
+) -> Option<Vec<String>> {
+    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+    state.evaluated += 1;
+    state.any_matched |= matched;
+    if state.any_matched {
+        return None;
+    }
+    if state.rejected.len() < HINT_EXAMPLES && !state.rejected.iter().any(|l| l == label) {
+        state.rejected.push(label.to_owned());
+    }
+    (state.evaluated == HINT_AFTER).then(|| state.rejected.clone())
+}
+
+#[test]
+fn test_select() {
+    let entry = test_entry("function", &[]);
+    let args = test_args(&["-a", "--skip", "1"]);
+    assert_eq!(select(&entry, &quote!(), &args, 0), (true, Pick::Hide));
+    assert_eq!(select(&entry, &quote!(), &args, 1), (true, Pick::Show));
+    let args = test_args(&["--nth", "3"]);
+    assert_eq!(select(&entry, &quote!(), &args, 2), (true, Pick::Hide));
+    assert_eq!(select(&entry, &quote!(), &args, 3), (true, Pick::Show));
+    let args = test_args(&["--nth", "3", "--not", "label"]);
+    assert_eq!(select(&entry, &quote!(), &args, 3), (false, Pick::Hide));
+    let picks = |flags: &[&str]| {
+        SHOWN_PER_MACRO.with(|shown| shown.borrow_mut().clear());
+        let args = test_args(&[&["-a"], flags].concat());
+        (0..3)
+            .map(|index| select(&entry, &quote!(), &args, index).1)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        picks(&["--first", "1", "--last", "1"]),
+        [Pick::Show, Pick::Hold, Pick::Hold]
+    );
+    assert_eq!(
+        picks(&["--last", "1"]),
+        [Pick::Hold, Pick::Hold, Pick::Hold]
+    );
+    assert_eq!(
+        picks(&["--count-per-macro", "2"]),
+        [Pick::Show, Pick::Show, Pick::Hide]
+    );
+}
+
+/// What to do with an invocation, see [`select`].
+#[derive(PartialEq, Debug)]
+enum Pick {
+    Show,
+    /// Held back by `--last`, as one of the last of its macro so far
+    Hold,
+    Hide,
+}
+
+/// Returns whether invocation `index`, which returned `ret`, matches the
+/// filters, and whether it is shown. Expansions held back by `--skip`, the
+/// per-macro limits or `--nth` match without being shown, so that they do not
+/// count towards the hint that the filters matched nothing.
+fn select(entry: &Entry, ret: &TokenStream, args: &ProcDebugArgs, index: usize) -> (bool, Pick) {
+    let pick = |shown: bool| if shown { Pick::Show } else { Pick::Hide };
+    if args.explain == Some(index) {
+        return (true, Pick::Show);
+    }
+    if !args.nth.is_empty() {
+        let matched = !entry.is_excluded(args);
+        return (matched, pick(matched && args.nth.contains(&index)));
+    }
+    let matched = is_matched(entry, ret, args);
+    if !matched || MATCHED.with(|matched| skipped(matched, args.skip)) {
+        return (matched, Pick::Hide);
+    }
+    let over = SHOWN_PER_MACRO.with(|shown| over_limit(shown, entry.label, args.count_per_macro));
+    // Without --count-per-macro, --last shows only the last ones
+    if args.last > 0 && (over || args.count_per_macro == 0) {
+        return (true, Pick::Hold);
+    }
+    (true, pick(!over))
+}
+
+/// Runs the macro and shows its input and output when `args` matches, also
+/// recording them in `destinations`. The tokens returned by the macro are
+/// passed through unchanged.
+fn wrap<F: FnOnce() -> TokenStream>(
+    entry: &Entry,
+    args: Option<&ProcDebugArgs>,
+    destinations: &Destinations,
+    f: F,
+) -> TokenStream {
+    let &Entry {
+        label,
+        modpath,
+        macro_name,
+        ..
+    } = entry;
+    let index = count();
+    export::record_invocation(destinations.results.as_deref(), modpath, macro_name);
+    let started = Timestamp::now();
+    let start = std::time::Instant::now();
+    let (ret, mut held) = {
+        let _guard = CallGuard::enter(label);
+        let ret = f();
+        (ret, take_held())
+    };
+    let duration = start.elapsed();
+    let Some(args) = args else {
+        if sink::is_set() {
+            sink::emit(&macro_record(entry, index, &ret, duration, &started));
+        }
+        return ret;
+    };
+    let explained = args.explain == Some(index);
+    let (matched, pick) = select(entry, &ret, args, index);
+    if !args.no_hints && args.format == Format::Text {
+        if let Some(rejected) = hint_due(&HINTS, label, matched) {
+            DisplayContext::stdout(args.palette, args.color, false)
+                .ascii(args.ascii)
+                .no_match_hint(HINT_AFTER, &args.active(), &rejected);
+        }
+    }
+    match pick {
+        Pick::Show => show_matched(
+            entry,
+            args,
+            destinations,
+            index,
+            &ret,
+            duration,
+            &started,
+            explained,
+            nesting(),
+            &mut held,
+        ),
+        Pick::Hold => {
+            let deferred = Deferred::new(entry, &ret, duration, started, nesting(), held);
+            LAST.with(|last| last.borrow_mut().0.hold(label, index, deferred, args.last));
+            return ret;
+        }
+        Pick::Hide => {}
+    }
+    release(held, args);
+    ret
+}
+
+/// Shows the matched invocation `index`, which returned `ret`, as the flags
+/// tell: in the format, dump directory and sink they select, or on stdout.
+#[allow(clippy::too_many_arguments)]
+fn show_matched(
+    entry: &Entry,
+    args: &ProcDebugArgs,
+    destinations: &Destinations,
+    index: usize,
+    ret: &TokenStream,
+    duration: std::time::Duration,
+    started: &Timestamp,
+    explained: bool,
+    (depth, parent): (usize, Option<String>),
+    held: &mut Held,
+) {
+    // Filters match the paths as the compiler gives them
+    let file;
+    let portable;
+    let entry = if args.portable_paths {
+        let roots = paths::Roots::get();
+        file = roots.portable(entry.file);
+        portable = Entry {
+            file: &file,
+            call_site: entry
+                .call_site
+                .as_ref()
+                .map(|(call_file, line)| (roots.portable(call_file), *line)),
+            ..*entry
+        };
+        &portable
+    } else {
+        entry
+    };
+    let dump_dir = args
+        .dump_dir
+        .clone()
+        .or_else(|| args.dump.then(export::default_dump_dir));
+    let sink = sink::is_set();
+    if args.format != Format::Text || dump_dir.is_some() || sink {
+        let record = macro_record(entry, index, ret, duration, started);
+        let mapping = match args.portable_paths {
+            true => paths::Roots::get().mapping(),
+            false => Vec::new(),
+        };
+        if let Some(dir) = &dump_dir {
+            export::dump_expansion(dir, &record, &mapping);
+        }
+        sink::emit(&record);
+        if args.format != Format::Text {
+            export::write_record(args.format, &record, destinations.out.as_deref(), &mapping);
+            return;
+        }
+    }
+    if sink && args.sink_only {
+        return;
+    }
+    if args.summary {
+        let label = match entry.derive_name() {
+            Some(derive) => format!("{} ({derive})", entry.label),
+            None => entry.label.to_owned(),
+        };
+        summary::add(&label, count_tokens(ret.clone()), duration);
+        return;
+    }
+    if budget::exceeded(args.max_total_bytes) {
+        budget::suppress();
+        return;
+    }
+    // Nested in a macro which is not shown yet, its output waits for the
+    // input of that macro
+    let mut nested = Held::default();
+    let display = if depth > 0 {
+        DisplayContext::held(args.palette, args.color, args.tee.is_some(), &mut nested)
+    } else {
+        DisplayContext::stdout(args.palette, args.color, args.paging).tee(args.tee.as_deref())
+    };
+    let mut display = display
+        .group(args.group)
+        .ascii(args.ascii)
+        .heading_format(args.heading_format.as_deref())
+        .timestamp(args.timestamps.then(|| started.prefix()))
+        .nested(depth, parent.as_deref());
+    if (!args.no_banner || args.verbose_meta) && first_banner() {
+        let mut active = args.active();
+        if let Some(Ok(config)) = Config::load() {
+            active.push(format!("config file: {}", config.path.display()));
+        }
+        if args.portable_paths {
+            let mapping = paths::Roots::get().mapping();
+            active.extend(
+                mapping
+                    .iter()
+                    .map(|(name, path)| format!("{name} = {path}")),
+            );
+        }
+        display.flags_banner(
+            &std::env::var("PROC_DEBUG_FLAGS").unwrap_or_default(),
+            &active,
+        );
+    }
+    show_expansion(&mut display, entry, args, index, ret, explained, held);
+    drop(display);
+    release(nested, args);
+    if args.copy_last {
+        let dir = dump_dir.unwrap_or_else(export::default_dump_dir);
+        clipboard::copy(&format_tokens(ret.clone(), args.rustfmt, false), &dir);
+    }
+}
+
+#[test]
+fn test_show_expansion() {
+    let inputs = ["".to_owned(), "fn f() { g(h(1)) }".to_owned()];
+    let entry = test_entry("attribute", &inputs);
+    let ret = TokenStream::from_str(&inputs[1]).unwrap();
+    let recorder = display::Recorder::default();
+    let args = test_args(&["-a", "-d", "2", "--ellipsis", "..."]);
+    show_expansion(
+        &mut DisplayContext::new(recorder.clone()),
+        &entry,
+        &args,
+        0,
+        &ret,
+        false,
+        &mut Held {
+            out: b"    nested\n".to_vec(),
+            tee: Vec::new(),
+        },
+    );
+    // What nested expansions showed comes between the input and the output
+    assert_eq!(
+        recorder.text(),
+        "<fg:Black,bg:Cyan,bold>👉 input of my_crate::my_macro (lib.rs:1) #0</>\n\
+         \x20 #[my_macro()]\n\
+         \x20 fn f() { g(h(1)) }\n\n\
+         \x20   nested\n\
+         <fg:Black,bg:Cyan,bold>👉 output of my_crate::my_macro (lib.rs:1) — parsed as 1 ImplItem, unchanged</>\n\
+         \x20 fn f () {\n\
+         \x20     g (... /* 3 more tokens */)\n\
+         \x20 }\n\n"
+    );
+}
+
+/// Shows the input of the invocation `index` and its output `ret`.
+fn show_expansion(
+    display: &mut DisplayContext,
+    entry: &Entry,
+    args: &ProcDebugArgs,
+    index: usize,
+    ret: &TokenStream,
+    explained: bool,
+    held: &mut Held,
+) {
+    let side_by_side = args
+        .side_by_side
+        .then(display::terminal_width)
+        .flatten()
+        .filter(|width| *width >= args.side_by_side_min_width);
+    if side_by_side.is_none() {
+        display.macro_call(index, entry, args.verbose);
+        display.write_held(held);
+    }
+    let output =
+        MacroOutput::from_tokens(ret.clone(), &MacroKind::from_str(entry.macro_kind).unwrap());
+    let mut notes = Vec::new();
+    if args.verbose_meta {
+        let flags = ProcDebugArgs::env_flags().unwrap_or_default();
+        let config = Config::load()
+            .and_then(|config| config.as_ref().ok())
+            .map(Config::names)
+            .unwrap_or_default();
+        notes.push(meta::describe(&meta::resolve(args, flags, &config)));
+    }
+    let stmt_limit = if args.verbose {
+        usize::MAX
+    } else {
+        args.stmt_limit.or(args.depth).unwrap_or(DEFAULT_DEPTH)
+    };
+    let depth = match (args.verbose, args.brace_depth.or(args.depth)) {
+        (true, _) => usize::MAX,
+        (false, Some(depth)) => depth,
+        (false, None) => {
+            let (depth, hidden) = auto_depth(&output.emit(), DEFAULT_DEPTH);
+            if depth > DEFAULT_DEPTH {
+                notes.push(format!(
+                    "// depth auto-increased to {depth}, as most of the output was hidden"
+                ));
+            }
+            if hidden {
+                notes.push(format!(
+                    "// most of the output is hidden even at depth {depth}, use -v to show all"
+                ));
+            }
+            depth
         }
+    };
+    let rules_mode = if args.arms_only {
+        rules::RulesMode::ArmsOnly
+    } else if args.show_transcribers {
+        rules::RulesMode::Transcribers
     } else {
-        ret
+        rules::RulesMode::Folded
+    };
+    let render = |tokens| {
+        let tokens = rules::fold_rules(tokens, rules_mode);
+        let tokens = if args.fold_repeats {
+            repeats::fold_repeats(tokens)
+        } else {
+            tokens
+        };
+        let tokens = if args.show_docs {
+            tokens
+        } else {
+            docs::fold_docs(tokens)
+        };
+        let formatted = replace_ellipsis(
+            &format_tokens(
+                unreplace(simplify_and_replace(tokens, depth, stmt_limit)),
+                args.rustfmt,
+                args.show_invisible_groups,
+            ),
+            &args.ellipsis,
+        );
+        let formatted = if args.show_docs {
+            formatted
+        } else {
+            docs::replace_folded(&formatted)
+        };
+        let formatted = if args.fold_repeats {
+            repeats::replace_repeats(&formatted)
+        } else {
+            formatted
+        };
+        rules::replace_rules(&formatted)
+    };
+    let explained = explained
+        .then(|| explain::explain(ret.clone()))
+        .flatten()
+        .map(|items| {
+            items
+                .into_iter()
+                .map(|(notes, item)| {
+                    let mut lines = notes
+                        .into_iter()
+                        .map(|n| format!("// {n}"))
+                        .collect::<Vec<_>>();
+                    lines.push(render(item));
+                    lines.join("\n")
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        });
+    let mut content = truncate_output(
+        notes
+            .into_iter()
+            .chain(Some(explained.unwrap_or_else(|| render(output.emit()))))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        args.max_output_bytes,
+    );
+    if args.stats {
+        content += &format!("\n// {}", output_stats(ret, &output));
+    }
+    let outcome = classify(entry, ret, args.strict_groups);
+    display.parsed_as(output.describe());
+    if let Some(error) = parse_error(entry, ret) {
+        display.warn(error);
+    }
+    match side_by_side {
+        Some(width) => {
+            let format = |part: String| match TokenStream::from_str(&part) {
+                Ok(tokens) => format_tokens(tokens, args.rustfmt, args.show_invisible_groups),
+                Err(_) => part,
+            };
+            display.side_by_side(
+                index,
+                entry,
+                args.verbose,
+                &format,
+                &content,
+                outcome,
+                width,
+            );
+            display.write_held(held);
+        }
+        None => display.macro_output(entry, &content, outcome),
     }
 }
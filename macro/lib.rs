@@ -4,6 +4,8 @@ use proc_macro::TokenStream as TokenStream1;
 use proc_macro2::Span;
 use proc_macro2::TokenStream;
 use proc_macro_error::{abort, proc_macro_error};
+use syn::ext::IdentExt;
+use syn::parse::Parser;
 use syn::spanned::Spanned;
 use syn::*;
 use template_quote::quote;
@@ -13,7 +15,9 @@ struct Arguments {
     label: Option<String>,
 }
 
-fn check_macro_kind(attrs: &[Attribute]) -> (&'static str, Option<String>) {
+/// Returns the kind of the macro, with the derive name and the helper
+/// attributes of a derive macro.
+fn check_macro_kind(attrs: &[Attribute]) -> (&'static str, Option<(String, Vec<String>)>) {
     for attr in attrs {
         if let Meta::Path(path) = &attr.meta {
             if path.is_ident("proc_macro") {
@@ -25,19 +29,70 @@ fn check_macro_kind(attrs: &[Attribute]) -> (&'static str, Option<String>) {
             if !path.is_ident("proc_macro_derive") {
                 continue;
             }
-            if let Ok(ident) = parse2::<Ident>(tokens.clone()) {
-                return ("derive", Some(ident.to_string()));
-            }
+            let parser = punctuated::Punctuated::<Meta, Token![,]>::parse_terminated;
+            let Ok(metas) = parser.parse2(tokens.clone()) else {
+                continue;
+            };
+            let Some(Meta::Path(name)) = metas.first() else {
+                continue;
+            };
+            let Some(ident) = name.get_ident() else {
+                continue;
+            };
+            let helpers = metas
+                .iter()
+                .filter_map(|meta| match meta {
+                    Meta::List(list) if list.path.is_ident("attributes") => list
+                        .parse_args_with(
+                            punctuated::Punctuated::<Ident, Token![,]>::parse_terminated,
+                        )
+                        .ok(),
+                    _ => None,
+                })
+                .flatten()
+                .map(|ident| ident.to_string())
+                .collect();
+            return ("derive", Some((ident.to_string(), helpers)));
         }
     }
     ("unknown", None)
 }
 
+#[test]
+fn test_cfg_attrs() {
+    let output = inner(
+        Arguments { label: None },
+        parse_quote! {
+            #[proc_macro]
+            #[cfg(feature = "x")]
+            #[cfg_attr(feature = "y", proc_macro_derive(MyDerive))]
+            #[allow(unused_variables)]
+            pub fn my_macro(input: TokenStream) -> TokenStream {
+                input
+            }
+        },
+    );
+    let wrapper = parse2::<ItemFn>(output).unwrap();
+    let paths = |attrs: &[Attribute]| {
+        attrs
+            .iter()
+            .map(|attr| attr.path().get_ident().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(paths(&wrapper.attrs), ["proc_macro", "cfg", "cfg_attr"]);
+    let Some(Stmt::Item(Item::Fn(inner))) = wrapper.block.stmts.first() else {
+        panic!("the original function is not nested in the wrapper");
+    };
+    assert_eq!(paths(&inner.attrs), ["cfg", "allow"]);
+}
+
 fn inner(args: Arguments, mut input: ItemFn) -> TokenStream {
     let mut macro_inputs = Vec::new();
-    let (macro_kind, derive_ident) = check_macro_kind(&input.attrs);
-    if let Some(derive_ident) = derive_ident {
+    let mut macro_arg_names = Vec::new();
+    let (macro_kind, derive) = check_macro_kind(&input.attrs);
+    if let Some((derive_ident, _)) = &derive {
         macro_inputs.push(quote!(#derive_ident.to_string()));
+        macro_arg_names.push("derive".to_owned());
     }
     let mut inner_attrs = vec![];
     let mut outer_attrs = vec![];
@@ -50,44 +105,64 @@ fn inner(args: Arguments, mut input: ItemFn) -> TokenStream {
                     || path.is_ident("proc_macro_attribute")
                     || path.is_ident("proc_macro_derive")
                     || path.is_ident("proc_macro_error")
-                    || path.is_ident("doc") =>
+                    || path.is_ident("doc")
+                    // May register the macro, which only the wrapper can be,
+                    // and otherwise applies to the function nested in it
+                    || path.is_ident("cfg_attr") =>
             {
                 outer_attrs.push(attr.clone());
             }
-
+            // On both, so that the wrapper and the function it calls are
+            // compiled out together.
+            Meta::Path(path)
+            | Meta::List(MetaList { path, .. })
+            | Meta::NameValue(MetaNameValue { path, .. })
+                if path.is_ident("cfg") =>
+            {
+                outer_attrs.push(attr.clone());
+                inner_attrs.push(attr.clone());
+            }
             _ => inner_attrs.push(attr.clone()),
         }
     }
     input.attrs = inner_attrs;
     for (n, input) in input.sig.inputs.iter_mut().enumerate() {
-        match input {
-            FnArg::Typed(pat_type) => {
-                let ident = match pat_type.pat.as_ref() {
-                    Pat::Ident(pat_ident) if &pat_ident.ident != "_" => pat_ident.ident.clone(),
-                    _ => {
-                        let ident = Ident::new(&format!("__proc_debug_arg_{}", n), pat_type.span());
-                        pat_type.pat = Box::new(Pat::Ident(PatIdent {
-                            attrs: vec![],
-                            by_ref: None,
-                            mutability: None,
-                            ident: ident.clone(),
-                            subpat: None,
-                        }));
-                        ident
-                    }
-                };
-                macro_inputs.push(quote!(#ident.to_string()));
-            }
-            _ => (),
+        if let FnArg::Typed(pat_type) = input {
+            let ident = match pat_type.pat.as_ref() {
+                Pat::Ident(pat_ident) if &pat_ident.ident != "_" => {
+                    macro_arg_names.push(pat_ident.ident.unraw().to_string());
+                    pat_ident.ident.clone()
+                }
+                _ => {
+                    macro_arg_names.push("_".to_owned());
+                    let ident = Ident::new(&format!("__proc_debug_arg_{}", n), pat_type.span());
+                    *pat_type.pat = Pat::Ident(PatIdent {
+                        attrs: vec![],
+                        by_ref: None,
+                        mutability: None,
+                        ident: ident.clone(),
+                        subpat: None,
+                    });
+                    ident
+                }
+            };
+            macro_inputs.push(quote!(#ident.to_string()));
+        }
+    }
+    if let Some((_, helpers)) = &derive {
+        if !helpers.is_empty() {
+            let helpers = helpers.join(", ");
+            macro_inputs.push(quote!(#helpers.to_owned()));
+            macro_arg_names.push("attributes".to_owned());
         }
     }
-    let (impl_generics, _, where_clause) = input.sig.generics.split_for_impl();
+    // Generic parameters are rejected by `check_generics`.
     quote! {
         #(#outer_attrs)*
-        #{&input.vis} #{&input.sig.constness} #{&input.sig.asyncness} #{&input.sig.unsafety} #{&input.sig.abi} #{&input.sig.fn_token} #{&input.sig.ident} #impl_generics (
+        #{&input.vis} #{&input.sig.constness} #{&input.sig.asyncness} #{&input.sig.unsafety} #{&input.sig.abi} #{&input.sig.fn_token} #{&input.sig.ident} (
             #{&input.sig.inputs}
             #{&input.sig.variadic}
-        ) #{&input.sig.output} #where_clause {
+        ) #{&input.sig.output} #{&input.sig.generics.where_clause} {
             #input
             ::proc_macro::TokenStream::from(
                 ::proc_debug::proc_wrapper(
@@ -99,9 +174,12 @@ fn inner(args: Arguments, mut input: ItemFn) -> TokenStream {
                     ::std::file!(),
                     ::std::line!() as usize,
                     ::std::module_path!(),
+                    ::std::env!("CARGO_PKG_NAME"),
+                    ::std::env!("CARGO_PKG_VERSION"),
                     #macro_kind,
                     #{input.sig.ident.to_string()},
                     &[ #(for input in &macro_inputs),{#input} ],
+                    &[ #(for name in &macro_arg_names),{#name} ],
                     || {
                         ::proc_macro2::TokenStream::from(
                             #{&input.sig.ident}(
@@ -123,13 +201,40 @@ fn inner(args: Arguments, mut input: ItemFn) -> TokenStream {
     }
 }
 
+#[test]
+fn test_check_generics() {
+    assert!(check_generics(&parse_quote! {
+        #[proc_macro]
+        pub fn my_macro(input: TokenStream) -> TokenStream { input }
+    })
+    .is_ok());
+    let e = check_generics(&parse_quote! {
+        #[proc_macro]
+        pub fn my_macro<T: Into<TokenStream>>(input: T) -> TokenStream { input.into() }
+    })
+    .unwrap_err();
+    assert!(e.to_string().contains("cannot have generic parameters"));
+}
+
+/// Rejects generic parameters, which a proc-macro function cannot have.
+fn check_generics(input: &ItemFn) -> Result<()> {
+    if input.sig.generics.params.is_empty() {
+        return Ok(());
+    }
+    Err(Error::new(
+        input.sig.generics.params.span(),
+        "#[proc_debug] requires a proc-macro function, which cannot have generic parameters",
+    ))
+}
+
 #[proc_macro_error]
 #[proc_macro_attribute]
 pub fn proc_debug(attr: TokenStream1, input: TokenStream1) -> TokenStream1 {
     let args = Arguments::from_list(&NestedMeta::parse_meta_list(attr.into()).unwrap()).unwrap();
-    inner(
-        args,
-        parse(input).unwrap_or_else(|_| abort!(Span::call_site(), "Require function")),
-    )
-    .into()
+    let input: ItemFn =
+        parse(input).unwrap_or_else(|_| abort!(Span::call_site(), "Require function"));
+    if let Err(e) = check_generics(&input) {
+        abort!(e.span(), "{}", e);
+    }
+    inner(args, input).into()
 }
@@ -0,0 +1,328 @@
+//! Provenance of the flags in effect, shown by `--verbose-meta`.
+//!
+//! Flags come from `PROC_DEBUG_FLAGS`, either written by hand or generated by
+//! `cargo proc-debug`, then from `proc-debug.toml`, and filters also from
+//! `--filter-file`; anything not given there is a default.
+//! The flags are matched back to the options of [`ProcDebugArgs`] to tell the
+//! two apart.
+
+use crate::ProcDebugArgs;
+#[cfg(test)]
+use argp::FromArgs;
+
+/// Where the value of an option comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Source {
+    /// Given in `PROC_DEBUG_FLAGS`
+    Flags,
+    /// Not given in `PROC_DEBUG_FLAGS` but in `proc-debug.toml`
+    Config,
+    /// Not given in `PROC_DEBUG_FLAGS` but in the file of `--filter-file`
+    FilterFile,
+    /// Not given, so the default value of the option
+    Default,
+}
+
+impl Source {
+    fn name(&self) -> &'static str {
+        match self {
+            Source::Flags => "PROC_DEBUG_FLAGS",
+            Source::Config => "proc-debug.toml",
+            Source::FilterFile => "filter file",
+            Source::Default => "default",
+        }
+    }
+}
+
+/// Long name, short name and whether the option takes a value, for each
+/// option of [`ProcDebugArgs`].
+const OPTIONS: &[(&str, Option<char>, bool)] = &[
+    ("all", Some('a'), false),
+    ("not", Some('n'), true),
+    ("path", Some('p'), true),
+    ("not-path", None, true),
+    ("label", None, true),
+    ("label-prefix", None, true),
+    ("kind", Some('k'), true),
+    ("derive", None, true),
+    ("all-queries", None, false),
+    ("file", None, true),
+    ("not-file", None, true),
+    ("lines", None, true),
+    ("input-contains", None, true),
+    ("input-not-contains", None, true),
+    ("crate-name", None, true),
+    ("crate-version", None, true),
+    ("output-contains", None, true),
+    ("output-not-contains", None, true),
+    ("depth", Some('d'), true),
+    ("brace-depth", None, true),
+    ("stmt-limit", None, true),
+    ("verbose", Some('v'), false),
+    ("rustfmt", None, false),
+    ("format", None, true),
+    ("show-invisible-groups", None, false),
+    ("strict-groups", None, false),
+    ("summary", None, false),
+    ("stats", None, false),
+    ("explain", None, true),
+    ("ellipsis", None, true),
+    ("show-docs", None, false),
+    ("palette", None, true),
+    ("color", None, true),
+    ("max-output-bytes", None, true),
+    ("max-total-bytes", None, true),
+    ("removed-only", None, false),
+    ("errors-only", None, false),
+    ("only-changed", None, false),
+    ("skip", None, true),
+    ("count-per-macro", None, true),
+    ("first", None, true),
+    ("last", None, true),
+    ("nth", None, true),
+    ("paging", None, false),
+    ("tee", None, true),
+    ("group", None, false),
+    ("verbose-meta", None, false),
+    ("filter-file", None, true),
+    ("regex", None, false),
+    ("ignore-case", Some('i'), false),
+    ("dump-dir", None, true),
+    ("dump", None, false),
+    ("fold-repeats", None, false),
+    ("side-by-side", None, false),
+    ("side-by-side-min-width", None, true),
+    ("classified-as", None, true),
+    ("no-banner", None, false),
+    ("no-hints", None, false),
+    ("sink-only", None, false),
+    ("show-transcribers", None, false),
+    ("arms-only", None, false),
+    ("ascii", None, false),
+    ("heading-format", None, true),
+    ("portable-paths", None, false),
+    ("timestamps", None, false),
+    ("copy-last", None, false),
+];
+
+/// Returns the long names of the options given in `flags`, with `queries`
+/// for positional arguments.
+pub(crate) fn given(flags: &[String]) -> Vec<&'static str> {
+    let mut given = Vec::new();
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let option = if let Some(long) = flag.strip_prefix("--") {
+            let (long, inline) = match long.split_once('=') {
+                Some((long, _)) => (long, true),
+                None => (long, false),
+            };
+            OPTIONS
+                .iter()
+                .find(|o| o.0 == long)
+                .map(|o| (o.0, o.2 && !inline))
+        } else if let Some(short) = flag.strip_prefix('-').filter(|s| !s.is_empty()) {
+            let mut chars = short.chars();
+            let c = chars.next();
+            OPTIONS
+                .iter()
+                .find(|o| o.1 == c)
+                .map(|o| (o.0, o.2 && chars.as_str().is_empty()))
+        } else {
+            given.push("queries");
+            continue;
+        };
+        if let Some((name, takes_value)) = option {
+            given.push(name);
+            if takes_value {
+                flags.next();
+            }
+        }
+    }
+    given
+}
+
+#[test]
+fn test_resolve() {
+    let resolve = |flags: &[&str]| {
+        let flags = flags.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let args = ProcDebugArgs::from_args(&["proc-debug"], &flags).unwrap();
+        resolve(&args, &flags, &[])
+            .into_iter()
+            .filter(|(name, _, _)| {
+                ["all", "depth", "ellipsis", "palette", "queries"].contains(name)
+            })
+            .map(|(name, value, source)| format!("{name} = {value} ({})", source.name()))
+            .collect::<Vec<_>>()
+    };
+    // Nothing given
+    assert_eq!(
+        resolve(&[]),
+        [
+            "all = false (default)",
+            "queries = [] (default)",
+            "depth = None (default)",
+            "ellipsis = \"comment\" (default)",
+            "palette = Default (default)",
+        ]
+    );
+    // Long and short forms, and values equal to the default
+    assert_eq!(
+        resolve(&["-a", "--depth", "2", "--palette", "default", "my_macro"]),
+        [
+            "all = true (PROC_DEBUG_FLAGS)",
+            "queries = [\"my_macro\"] (PROC_DEBUG_FLAGS)",
+            "depth = Some(2) (PROC_DEBUG_FLAGS)",
+            "ellipsis = \"comment\" (default)",
+            "palette = Default (PROC_DEBUG_FLAGS)",
+        ]
+    );
+    // A value which looks like a flag or a query is not taken as one
+    assert_eq!(
+        resolve(&["-d", "3", "--ellipsis", "-a"]),
+        [
+            "all = false (default)",
+            "queries = [] (default)",
+            "depth = Some(3) (PROC_DEBUG_FLAGS)",
+            "ellipsis = \"-a\" (PROC_DEBUG_FLAGS)",
+            "palette = Default (default)",
+        ]
+    );
+    // Filters read from --filter-file
+    let flags = ["--filter-file".to_owned(), "filters".to_owned()];
+    let mut args = ProcDebugArgs::from_args(&["proc-debug"], &flags).unwrap();
+    args.path.push("my_crate".into());
+    let sources = self::resolve(&args, &flags, &["path", "depth"])
+        .into_iter()
+        .map(|(name, _, source)| (name, source))
+        .collect::<Vec<_>>();
+    assert!(sources.contains(&("path", Source::Config)));
+    assert!(sources.contains(&("depth", Source::Config)));
+    let sources = self::resolve(&args, &flags, &[])
+        .into_iter()
+        .map(|(name, _, source)| (name, source))
+        .collect::<Vec<_>>();
+    assert!(sources.contains(&("path", Source::FilterFile)));
+    assert!(sources.contains(&("not", Source::Default)));
+    assert!(sources.contains(&("filter-file", Source::Flags)));
+}
+
+/// Lists every option of `args` with its value and where it comes from,
+/// given the `flags` it was parsed from and the options of the `config` file.
+pub(crate) fn resolve(
+    args: &ProcDebugArgs,
+    flags: &[String],
+    config: &[&str],
+) -> Vec<(&'static str, String, Source)> {
+    let given = given(flags);
+    let filtered = |values: &[crate::Pattern]| args.filter_file.is_some() && !values.is_empty();
+    let from_file = [
+        ("not", filtered(&args.not)),
+        ("path", filtered(&args.path)),
+        ("queries", filtered(&args.queries)),
+    ];
+    let values = [
+        ("all", format!("{:?}", args.all)),
+        ("not", format!("{:?}", args.not)),
+        ("path", format!("{:?}", args.path)),
+        ("not-path", format!("{:?}", args.not_path)),
+        ("label", format!("{:?}", args.label)),
+        ("label-prefix", format!("{:?}", args.label_prefix)),
+        ("kind", format!("{:?}", args.kind)),
+        ("derive", format!("{:?}", args.derive)),
+        ("file", format!("{:?}", args.file)),
+        ("not-file", format!("{:?}", args.not_file)),
+        ("lines", format!("{:?}", args.lines)),
+        ("input-contains", format!("{:?}", args.input_contains)),
+        (
+            "input-not-contains",
+            format!("{:?}", args.input_not_contains),
+        ),
+        ("crate-name", format!("{:?}", args.crate_name)),
+        ("crate-version", format!("{:?}", args.crate_version)),
+        ("output-contains", format!("{:?}", args.output_contains)),
+        (
+            "output-not-contains",
+            format!("{:?}", args.output_not_contains),
+        ),
+        ("all-queries", format!("{:?}", args.all_queries)),
+        ("queries", format!("{:?}", args.queries)),
+        ("depth", format!("{:?}", args.depth)),
+        ("brace-depth", format!("{:?}", args.brace_depth)),
+        ("stmt-limit", format!("{:?}", args.stmt_limit)),
+        ("verbose", format!("{:?}", args.verbose)),
+        ("rustfmt", format!("{:?}", args.rustfmt)),
+        ("format", format!("{:?}", args.format)),
+        (
+            "show-invisible-groups",
+            format!("{:?}", args.show_invisible_groups),
+        ),
+        ("strict-groups", format!("{:?}", args.strict_groups)),
+        ("summary", format!("{:?}", args.summary)),
+        ("stats", format!("{:?}", args.stats)),
+        ("explain", format!("{:?}", args.explain)),
+        ("ellipsis", format!("{:?}", args.ellipsis)),
+        ("show-docs", format!("{:?}", args.show_docs)),
+        ("palette", format!("{:?}", args.palette)),
+        ("color", format!("{:?}", args.color)),
+        ("max-output-bytes", format!("{:?}", args.max_output_bytes)),
+        ("max-total-bytes", format!("{:?}", args.max_total_bytes)),
+        ("removed-only", format!("{:?}", args.removed_only)),
+        ("errors-only", format!("{:?}", args.errors_only)),
+        ("only-changed", format!("{:?}", args.only_changed)),
+        ("skip", format!("{:?}", args.skip)),
+        ("count-per-macro", format!("{:?}", args.count_per_macro)),
+        ("first", format!("{:?}", args.first)),
+        ("last", format!("{:?}", args.last)),
+        ("nth", format!("{:?}", args.nth)),
+        ("paging", format!("{:?}", args.paging)),
+        ("tee", format!("{:?}", args.tee)),
+        ("group", format!("{:?}", args.group)),
+        ("verbose-meta", format!("{:?}", args.verbose_meta)),
+        ("filter-file", format!("{:?}", args.filter_file)),
+        ("regex", format!("{:?}", args.regex)),
+        ("ignore-case", format!("{:?}", args.ignore_case)),
+        ("dump-dir", format!("{:?}", args.dump_dir)),
+        ("dump", format!("{:?}", args.dump)),
+        ("fold-repeats", format!("{:?}", args.fold_repeats)),
+        ("side-by-side", format!("{:?}", args.side_by_side)),
+        (
+            "side-by-side-min-width",
+            format!("{:?}", args.side_by_side_min_width),
+        ),
+        ("classified-as", format!("{:?}", args.classified_as)),
+        ("no-banner", format!("{:?}", args.no_banner)),
+        ("no-hints", format!("{:?}", args.no_hints)),
+        ("sink-only", format!("{:?}", args.sink_only)),
+        ("show-transcribers", format!("{:?}", args.show_transcribers)),
+        ("arms-only", format!("{:?}", args.arms_only)),
+        ("ascii", format!("{:?}", args.ascii)),
+        ("heading-format", format!("{:?}", args.heading_format)),
+        ("portable-paths", format!("{:?}", args.portable_paths)),
+        ("timestamps", format!("{:?}", args.timestamps)),
+        ("copy-last", format!("{:?}", args.copy_last)),
+    ];
+    values
+        .into_iter()
+        .map(|(name, value)| {
+            let source = if given.contains(&name) {
+                Source::Flags
+            } else if config.contains(&name) {
+                Source::Config
+            } else if from_file.contains(&(name, true)) {
+                Source::FilterFile
+            } else {
+                Source::Default
+            };
+            (name, value, source)
+        })
+        .collect()
+}
+
+/// Formats the output of [`resolve`] as comment lines.
+pub(crate) fn describe(resolved: &[(&str, String, Source)]) -> String {
+    resolved
+        .iter()
+        .map(|(name, value, source)| format!("// {name} = {value} ({})", source.name()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,165 @@
+//! Rewriting of absolute paths for `--portable-paths`.
+//!
+//! Paths under well-known roots are written relative to a placeholder, so that
+//! logs do not show the user name nor the layout of the machine, and compare
+//! equal across machines:
+//!
+//! - `{workspace}/src/main.rs` in the workspace being compiled
+//! - `{registry}/serde_derive-1.0.203/src/lib.rs` in the registry cache of
+//!   cargo, whatever the registry
+//! - `{git}/name-0123abcd/fedcba9/src/lib.rs` in git checkouts of cargo
+//! - `{home}/...` elsewhere in the home directory
+//!
+//! Both `/` and `\` separate components, and paths are written with `/`,
+//! including those under none of the roots.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Roots of the placeholders, with `/` as separator and no trailing one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Roots {
+    workspace: Option<String>,
+    cargo_home: Option<String>,
+    home: Option<String>,
+}
+
+fn normalize(path: &str) -> String {
+    path.replace('\\', "/").trim_end_matches('/').to_owned()
+}
+
+/// Returns the workspace containing the package in `manifest_dir`, which is
+/// the package itself unless an ancestor declares `[workspace]`.
+fn workspace_root(manifest_dir: &Path) -> PathBuf {
+    manifest_dir
+        .ancestors()
+        .find(|dir| {
+            std::fs::read_to_string(dir.join("Cargo.toml"))
+                .is_ok_and(|manifest| manifest.lines().any(|l| l.trim() == "[workspace]"))
+        })
+        .unwrap_or(manifest_dir)
+        .to_owned()
+}
+
+impl Roots {
+    /// Roots of this rustc process, from the environment cargo sets.
+    pub(crate) fn get() -> &'static Roots {
+        static ROOTS: OnceLock<Roots> = OnceLock::new();
+        ROOTS.get_or_init(|| {
+            let var = |name| std::env::var_os(name).map(PathBuf::from);
+            let home = var("HOME").or_else(|| var("USERPROFILE"));
+            let cargo_home = var("CARGO_HOME").or_else(|| home.as_ref().map(|h| h.join(".cargo")));
+            let workspace = var("CARGO_MANIFEST_DIR").map(|dir| workspace_root(&dir));
+            let string = |path: Option<PathBuf>| path.map(|p| normalize(&p.to_string_lossy()));
+            Roots {
+                workspace: string(workspace),
+                cargo_home: string(cargo_home),
+                home: string(home),
+            }
+        })
+    }
+
+    /// Lists each placeholder with the path it stands for.
+    pub(crate) fn mapping(&self) -> Vec<(&'static str, String)> {
+        let cargo = |sub: &str| {
+            self.cargo_home
+                .as_ref()
+                .map(|home| format!("{home}/{sub}/<one component>"))
+        };
+        [
+            ("{workspace}", self.workspace.clone()),
+            ("{registry}", cargo("registry/src")),
+            ("{git}", cargo("git/checkouts")),
+            ("{home}", self.home.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(name, path)| Some((name, path?)))
+        .collect()
+    }
+
+    /// Rewrites `path` relative to the first root containing it.
+    pub(crate) fn portable(&self, path: &str) -> String {
+        let path = normalize(path);
+        let under = |root: &Option<String>| {
+            let root = root.as_ref()?;
+            let rest = path.strip_prefix(root.as_str())?;
+            rest.strip_prefix('/').or(rest.is_empty().then_some(""))
+        };
+        let cargo = |sub: &str| {
+            let home = self.cargo_home.as_ref()?;
+            under(&Some(format!("{home}/{sub}")))
+        };
+        // Registry sources are in a directory per registry index.
+        if let Some((_, rest)) = cargo("registry/src").and_then(|rest| rest.split_once('/')) {
+            return format!("{{registry}}/{rest}");
+        }
+        if let Some(rest) = cargo("git/checkouts") {
+            return format!("{{git}}/{rest}");
+        }
+        // After the roots of cargo, since a dependency of the registry
+        // compiled on its own is a workspace of its own.
+        if let Some(rest) = under(&self.workspace) {
+            return format!("{{workspace}}/{rest}");
+        }
+        if let Some(rest) = under(&self.home) {
+            return format!("{{home}}/{rest}");
+        }
+        path
+    }
+}
+
+#[test]
+fn test_portable() {
+    let unix = Roots {
+        workspace: Some("/home/me/work/app".to_owned()),
+        cargo_home: Some("/home/me/.cargo".to_owned()),
+        home: Some("/home/me".to_owned()),
+    };
+    assert_eq!(
+        unix.portable("/home/me/work/app/src/main.rs"),
+        "{workspace}/src/main.rs"
+    );
+    assert_eq!(
+        unix.portable(
+            "/home/me/.cargo/registry/src/index.crates.io-6f17d22bba15001f/serde_derive-1.0.203/src/lib.rs"
+        ),
+        "{registry}/serde_derive-1.0.203/src/lib.rs"
+    );
+    assert_eq!(
+        unix.portable("/home/me/.cargo/git/checkouts/dep-0123abcd/fedcba9/src/lib.rs"),
+        "{git}/dep-0123abcd/fedcba9/src/lib.rs"
+    );
+    assert_eq!(
+        unix.portable("/home/me/work/application/src/lib.rs"),
+        "{home}/work/application/src/lib.rs"
+    );
+    assert_eq!(unix.portable("/opt/src/lib.rs"), "/opt/src/lib.rs");
+    assert_eq!(unix.portable("src/lib.rs"), "src/lib.rs");
+    let windows = Roots {
+        workspace: Some(normalize(r"C:\Users\me\work\app")),
+        cargo_home: Some(normalize(r"C:\Users\me\.cargo")),
+        home: Some(normalize(r"C:\Users\me")),
+    };
+    assert_eq!(
+        windows.portable(r"C:\Users\me\work\app\src\main.rs"),
+        "{workspace}/src/main.rs"
+    );
+    assert_eq!(
+        windows.portable(
+            r"C:\Users\me\.cargo\registry\src\index.crates.io-6f17d22bba15001f\serde_derive-1.0.203\src\lib.rs"
+        ),
+        "{registry}/serde_derive-1.0.203/src/lib.rs"
+    );
+    assert_eq!(
+        windows.portable(r"C:\Users\me\.cargo\git\checkouts\dep-0123abcd\fedcba9\src\lib.rs"),
+        "{git}/dep-0123abcd/fedcba9/src/lib.rs"
+    );
+    assert_eq!(windows.portable(r"D:\src\lib.rs"), "D:/src/lib.rs");
+    assert_eq!(
+        unix.mapping()[1],
+        (
+            "{registry}",
+            "/home/me/.cargo/registry/src/<one component>".to_owned()
+        )
+    );
+}
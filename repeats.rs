@@ -0,0 +1,277 @@
+//! Folding of repeated items in the displayed output, enabled by
+//! `--fold-repeats`.
+//!
+//! Macros often emit one near-identical item per field or variant. Items of
+//! the output which have the same tokens, except for a few identifiers or
+//! literals, are shown once, and the rest of the run is replaced with a marker
+//! item telling how many were folded and what varies between them. The marker
+//! is a macro invocation, which survives formatting, and is replaced with a
+//! comment afterwards.
+
+use proc_macro2::{Delimiter, Literal, Spacing, TokenStream, TokenTree};
+use quote::{quote, ToTokens};
+
+const REPEATS_MARKER: &str = "__proc_debug_repeats";
+
+/// Items of a run may differ in at most this many identifiers or literals.
+const MAX_VARYING: usize = 3;
+
+/// Runs shorter than this are shown in full.
+const MIN_RUN: usize = 3;
+
+/// Where a token stands in an item, to tell what its identifiers are.
+#[derive(Clone, Copy, PartialEq)]
+enum Context {
+    /// The top level of the item, outside any group, telling whether it is
+    /// a trait impl.
+    Header {
+        impl_trait: bool,
+    },
+    /// The braces of an enum.
+    Variants,
+    Other,
+}
+
+/// What the identifier or literal `leaf` is, from the tokens around it.
+fn role(
+    context: Context,
+    prev: Option<&str>,
+    leaf: &TokenTree,
+    next: Option<&str>,
+) -> &'static str {
+    if let TokenTree::Literal(lit) = leaf {
+        return if lit.to_string().starts_with('"') {
+            "string literal"
+        } else {
+            "literal"
+        };
+    }
+    match (prev, next) {
+        (Some("struct"), _) => "struct name",
+        (Some("enum"), _) => "enum name",
+        (Some("union"), _) => "union name",
+        (Some("trait"), _) => "trait name",
+        (Some("fn"), _) => "fn name",
+        (Some("mod"), _) => "mod name",
+        (Some("type"), _) => "type alias name",
+        (Some("const"), _) => "const name",
+        (Some("static"), _) => "static name",
+        (Some("impl"), _) if context == (Context::Header { impl_trait: true }) => {
+            "implemented trait"
+        }
+        (Some("impl"), _) if matches!(context, Context::Header { .. }) => "self type",
+        (Some("for"), _) if matches!(context, Context::Header { .. }) => "self type",
+        (Some(p), _) if p.ends_with('\'') => "lifetime",
+        (Some("::"), _) => "path segment",
+        (_, Some(":")) => "field name",
+        (Some(":" | "->" | "&"), _) => "type",
+        (None | Some(","), _) if context == Context::Variants => "variant name",
+        _ => "identifier",
+    }
+}
+
+fn kinds(item: &syn::Item) -> &'static str {
+    match item {
+        syn::Item::Const(_) => "consts",
+        syn::Item::Enum(_) => "enums",
+        syn::Item::Fn(_) => "fns",
+        syn::Item::Impl(_) => "impls",
+        syn::Item::Macro(_) => "macro invocations",
+        syn::Item::Mod(_) => "mods",
+        syn::Item::Static(_) => "statics",
+        syn::Item::Struct(_) => "structs",
+        syn::Item::Trait(_) => "traits",
+        syn::Item::Type(_) => "type aliases",
+        syn::Item::Use(_) => "uses",
+        _ => "items",
+    }
+}
+
+/// An item with its shape, which is its flattened tokens with identifiers
+/// and literals replaced with `_`, and those leaves with their roles.
+struct Shaped {
+    item: syn::Item,
+    shape: Vec<String>,
+    leaves: Vec<String>,
+    roles: Vec<&'static str>,
+}
+
+impl Shaped {
+    fn new(item: syn::Item) -> Self {
+        let context = Context::Header {
+            impl_trait: matches!(&item, syn::Item::Impl(i) if i.trait_.is_some()),
+        };
+        let mut shaped = Self {
+            shape: Vec::new(),
+            leaves: Vec::new(),
+            roles: Vec::new(),
+            item,
+        };
+        shaped.walk(shaped.item.to_token_stream(), context);
+        shaped
+    }
+
+    fn walk(&mut self, tokens: TokenStream, context: Context) {
+        // The previous token, with joint punctuation such as `::` merged
+        let mut prev: Option<String> = None;
+        let mut joint = false;
+        let mut tokens = tokens.into_iter().peekable();
+        while let Some(token) = tokens.next() {
+            match token {
+                TokenTree::Group(g) => {
+                    self.shape.push(format!("{:?}", g.delimiter()));
+                    let inner = match context {
+                        Context::Header { .. }
+                            if matches!(self.item, syn::Item::Enum(_))
+                                && g.delimiter() == Delimiter::Brace =>
+                        {
+                            Context::Variants
+                        }
+                        _ => Context::Other,
+                    };
+                    self.walk(g.stream(), inner);
+                    self.shape.push("end".to_owned());
+                    prev = None;
+                    joint = false;
+                }
+                TokenTree::Punct(p) => {
+                    self.shape.push(p.as_char().to_string());
+                    match &mut prev {
+                        Some(prev) if joint => prev.push(p.as_char()),
+                        _ => prev = Some(p.as_char().to_string()),
+                    }
+                    joint = p.spacing() == Spacing::Joint;
+                }
+                leaf => {
+                    let next = match tokens.peek() {
+                        Some(TokenTree::Punct(p)) if p.spacing() == Spacing::Alone => {
+                            Some(p.as_char().to_string())
+                        }
+                        _ => None,
+                    };
+                    self.shape.push("_".to_owned());
+                    self.roles
+                        .push(role(context, prev.as_deref(), &leaf, next.as_deref()));
+                    self.leaves.push(leaf.to_string());
+                    prev = Some(leaf.to_string());
+                    joint = false;
+                }
+            }
+        }
+    }
+
+    /// Returns the positions of the leaves in which `other` differs, or
+    /// `None` if it is not a repeat of `self`.
+    fn varying(&self, other: &Shaped) -> Option<Vec<usize>> {
+        if self.shape != other.shape {
+            return None;
+        }
+        let varying = (0..self.leaves.len())
+            .filter(|&n| self.leaves[n] != other.leaves[n])
+            .collect::<Vec<_>>();
+        (varying.len() <= MAX_VARYING).then_some(varying)
+    }
+}
+
+/// Describes what varies in the folded items `rest` at `positions`, by the
+/// roles of the leaves, omitting positions which vary alike.
+fn describe_varying(rest: &[Shaped], positions: &[usize]) -> String {
+    let mut lists: Vec<Vec<&str>> = Vec::new();
+    let mut roles = Vec::new();
+    for &n in positions {
+        let values = rest
+            .iter()
+            .map(|s| s.leaves[n].as_str())
+            .collect::<Vec<_>>();
+        if !lists.contains(&values) {
+            lists.push(values);
+            if !roles.contains(&rest[0].roles[n]) {
+                roles.push(rest[0].roles[n]);
+            }
+        }
+    }
+    roles.join(", ")
+}
+
+/// Replaces runs of repeated top-level items of `tokens` with a marker after
+/// their first item. Outputs which are not items are returned unchanged.
+pub(crate) fn fold_repeats(tokens: TokenStream) -> TokenStream {
+    let Ok(file) = syn::parse2::<syn::File>(tokens.clone()) else {
+        return tokens;
+    };
+    let items = file.items.into_iter().map(Shaped::new).collect::<Vec<_>>();
+    let mut out = TokenStream::new();
+    for attr in &file.attrs {
+        attr.to_tokens(&mut out);
+    }
+    let mut start = 0;
+    while start < items.len() {
+        let first = &items[start];
+        let mut positions = Vec::new();
+        let mut end = start + 1;
+        while let Some(varying) = items.get(end).and_then(|item| first.varying(item)) {
+            positions.extend(varying);
+            end += 1;
+        }
+        positions.sort();
+        positions.dedup();
+        first.item.to_tokens(&mut out);
+        if end - start >= MIN_RUN && positions.len() <= MAX_VARYING {
+            let rest = &items[start + 1..end];
+            let kinds = kinds(&first.item);
+            let note = if positions.is_empty() {
+                format!("… {} more identical {kinds}", rest.len())
+            } else {
+                format!(
+                    "… {} more identical {kinds} (varying: {})",
+                    rest.len(),
+                    describe_varying(rest, &positions)
+                )
+            };
+            let marker = syn::Ident::new(REPEATS_MARKER, proc_macro2::Span::call_site());
+            let note = Literal::string(&note);
+            out.extend(quote!(#marker! { #note }));
+        } else {
+            end = start + 1;
+        }
+        start = end;
+    }
+    out
+}
+
+#[test]
+fn test_fold_repeats() {
+    let tokens = quote! {
+        enum Color { Red, Green, Blue, Black }
+        impl Variant for Red { const NAME: &'static str = "Red"; }
+        impl Variant for Green { const NAME: &'static str = "Green"; }
+        impl Variant for Blue { const NAME: &'static str = "Blue"; }
+        impl Variant for Black { const NAME: &'static str = "Black"; }
+        struct A;
+        struct A;
+        type Red = u8;
+        type Green = u8;
+        type Blue = u8;
+    };
+    let formatted = crate::indent_tokens(fold_repeats(tokens), false);
+    assert_eq!(
+        replace_repeats(&formatted),
+        r#"enum Color {
+    Red, Green, Blue, Black
+}
+impl Variant for Red {
+    const NAME : & 'static str = "Red";
+}
+// … 3 more identical impls (varying: self type, string literal)
+struct A;
+struct A;
+type Red = u8;
+// … 2 more identical type aliases (varying: type alias name)"#
+    );
+}
+
+/// Replaces the markers left by [`fold_repeats`] in formatted output with
+/// comments.
+pub(crate) fn replace_repeats(formatted: &str) -> String {
+    crate::replace_markers(formatted, REPEATS_MARKER, |note| format!("// {note}"))
+}
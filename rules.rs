@@ -0,0 +1,372 @@
+//! Rendering of `macro_rules!` definitions in the displayed output.
+//!
+//! The generic indenter spaces every token, which turns `$x:expr` into
+//! `$ x : expr`, and the depth limit hides the arms of a definition. Instead,
+//! each top-level `macro_rules!` item of the output is rendered here with one
+//! arm per line and its transcriber folded, shown with `--show-transcribers`
+//! or left out with `--arms-only`. As with the other folds, the item is
+//! replaced with a marker macro invocation holding the rendered text, which
+//! survives formatting, and the marker is replaced afterwards.
+
+use proc_macro2::{Delimiter, Group, Literal, Spacing, TokenStream, TokenTree};
+use quote::{quote, ToTokens};
+
+const RULES_MARKER: &str = "__proc_debug_rules";
+
+const INDENT: &str = "    ";
+
+/// How the arms of a definition are shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RulesMode {
+    /// Matchers, with the transcribers folded
+    Folded,
+    /// Matchers and transcribers
+    Transcribers,
+    /// Matchers only
+    ArmsOnly,
+}
+
+/// Last token written by a [`Printer`], which decides the spacing before the
+/// next one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Last {
+    /// Start of a line or of a group
+    Open,
+    /// Identifier, literal or `$x`
+    Word,
+    /// End of a group
+    Close,
+    /// Punctuation, and whether the next token follows it without space
+    Punct(char, bool),
+}
+
+/// Prints tokens with the spacing of handwritten code, keeping `$x`,
+/// `$x:frag` and `$(..),*` together.
+struct Printer {
+    out: String,
+    last: Last,
+    /// Break lines after `;` and blocks, in braces
+    multiline: bool,
+    indent: usize,
+    /// Whether the innermost group is a brace group
+    in_brace: bool,
+    /// Join `$x:frag`, which only means a fragment in matchers
+    matcher: bool,
+    /// A block just ended, so the next token starts a line unless it
+    /// continues the statement
+    after_block: bool,
+}
+
+impl Printer {
+    fn new(indent: usize, multiline: bool, matcher: bool) -> Self {
+        Self {
+            out: String::new(),
+            last: Last::Open,
+            multiline,
+            indent,
+            in_brace: false,
+            matcher,
+            after_block: false,
+        }
+    }
+
+    fn newline(&mut self) {
+        self.out.truncate(self.out.trim_end_matches(' ').len());
+        self.out.push('\n');
+        self.out.push_str(&INDENT.repeat(self.indent));
+        self.last = Last::Open;
+    }
+
+    /// Writes the space or line break before a token. `tight` tells the
+    /// token attaches to a word before it, like `,` or the `(` of a call.
+    fn separate(&mut self, tight: bool, continues: bool) {
+        if std::mem::take(&mut self.after_block) && !continues {
+            self.newline();
+            return;
+        }
+        let space = match self.last {
+            Last::Open => false,
+            Last::Punct(_, true) => false,
+            Last::Word | Last::Close | Last::Punct(..) => !tight,
+        };
+        if space {
+            self.out.push(' ');
+        }
+    }
+
+    fn word(&mut self, word: &str) {
+        self.separate(false, false);
+        self.out.push_str(word);
+        self.last = Last::Word;
+    }
+
+    fn punct(&mut self, c: char, spacing: Spacing) {
+        let after_word = matches!(self.last, Last::Word | Last::Close);
+        let tight = matches!(c, ',' | ';' | '.' | '?' | ':') || (c == '!' && after_word);
+        self.separate(tight, matches!(c, ',' | ';' | '.'));
+        self.out.push(c);
+        let joined = spacing == Spacing::Joint
+            || matches!(c, '#' | '.' | '!' | '&' | '$')
+            || (c == ':' && self.out.ends_with("::"));
+        self.last = Last::Punct(c, joined);
+        if c == ';' && self.multiline && self.in_brace {
+            self.newline();
+        }
+    }
+
+    fn group(&mut self, group: &Group, prefix: &str) {
+        let (open, close) = match group.delimiter() {
+            Delimiter::Parenthesis => ("(", ")"),
+            Delimiter::Bracket => ("[", "]"),
+            Delimiter::Brace => ("{", "}"),
+            Delimiter::None => ("", ""),
+        };
+        let brace = group.delimiter() == Delimiter::Brace;
+        let tight = !brace && prefix.is_empty() && matches!(self.last, Last::Word | Last::Close);
+        self.separate(tight, false);
+        self.out.push_str(prefix);
+        self.out.push_str(open);
+        self.last = Last::Open;
+        let in_brace = std::mem::replace(&mut self.in_brace, brace);
+        let empty = group.stream().is_empty();
+        if brace && self.multiline && !empty {
+            self.indent += 1;
+            self.newline();
+            self.tokens(group.stream());
+            self.after_block = false;
+            self.indent -= 1;
+            self.newline();
+        } else if brace && !empty {
+            self.out.push(' ');
+            self.tokens(group.stream());
+            self.after_block = false;
+            self.out.push(' ');
+        } else {
+            self.tokens(group.stream());
+            self.after_block = false;
+        }
+        self.in_brace = in_brace;
+        self.out.push_str(close);
+        self.last = Last::Close;
+        self.after_block = brace && self.multiline && self.in_brace;
+    }
+
+    fn tokens(&mut self, tokens: TokenStream) {
+        let tokens = tokens.into_iter().collect::<Vec<_>>();
+        let is_op = |t: Option<&TokenTree>| matches!(t, Some(TokenTree::Punct(p)) if matches!(p.as_char(), '*' | '+' | '?'));
+        let mut n = 0;
+        while n < tokens.len() {
+            match (&tokens[n], tokens.get(n + 1)) {
+                (TokenTree::Punct(p), Some(TokenTree::Ident(ident))) if p.as_char() == '$' => {
+                    n += 2;
+                    let fragment = match (tokens.get(n), tokens.get(n + 1)) {
+                        (Some(TokenTree::Punct(p)), Some(TokenTree::Ident(frag)))
+                            if self.matcher && p.as_char() == ':' =>
+                        {
+                            n += 2;
+                            format!(":{frag}")
+                        }
+                        _ => String::new(),
+                    };
+                    self.word(&format!("${ident}{fragment}"));
+                }
+                (TokenTree::Punct(p), Some(TokenTree::Group(g)))
+                    if p.as_char() == '$' && g.delimiter() == Delimiter::Parenthesis =>
+                {
+                    self.group(g, "$");
+                    n += 2;
+                    // The separator, if any, and the repetition operator
+                    if is_op(tokens.get(n)) {
+                        self.out.push_str(&tokens[n].to_string());
+                        n += 1;
+                    } else if tokens.get(n).is_some() && is_op(tokens.get(n + 1)) {
+                        self.out.push_str(&tokens[n].to_string());
+                        self.out.push_str(&tokens[n + 1].to_string());
+                        n += 2;
+                    }
+                }
+                (TokenTree::Group(g), _) => {
+                    self.group(g, "");
+                    n += 1;
+                }
+                (TokenTree::Punct(p), _) => {
+                    self.punct(p.as_char(), p.spacing());
+                    n += 1;
+                }
+                (token, _) => {
+                    self.word(&token.to_string());
+                    n += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Prints `tokens` on a single line.
+fn print_line(tokens: TokenStream, matcher: bool) -> String {
+    let mut printer = Printer::new(0, false, matcher);
+    printer.tokens(tokens);
+    printer.out
+}
+
+/// Splits the body of a `macro_rules!` into its arms, or returns `None` if it
+/// is not a list of `(matcher) => {transcriber}`.
+fn arms(body: TokenStream) -> Option<Vec<(Group, Group)>> {
+    let tokens = body.into_iter().collect::<Vec<_>>();
+    let mut arms = Vec::new();
+    let mut rest = tokens.as_slice();
+    while !rest.is_empty() {
+        let [TokenTree::Group(matcher), TokenTree::Punct(eq), TokenTree::Punct(gt), TokenTree::Group(transcriber), tail @ ..] =
+            rest
+        else {
+            return None;
+        };
+        if eq.as_char() != '=' || gt.as_char() != '>' {
+            return None;
+        }
+        arms.push((matcher.clone(), transcriber.clone()));
+        rest = match tail {
+            [TokenTree::Punct(semi), tail @ ..] if semi.as_char() == ';' => tail,
+            [] => tail,
+            _ => return None,
+        };
+    }
+    Some(arms)
+}
+
+/// Renders a `macro_rules!` definition, or returns `None` if `item` is not
+/// one.
+fn render(item: &syn::ItemMacro, mode: RulesMode) -> Option<String> {
+    if !item.mac.path.is_ident("macro_rules") {
+        return None;
+    }
+    let name = item.ident.as_ref()?;
+    let arms = arms(item.mac.tokens.clone())?;
+    let mut lines = item
+        .attrs
+        .iter()
+        .map(|attr| print_line(attr.to_token_stream(), false))
+        .collect::<Vec<_>>();
+    lines.push(format!("macro_rules! {name} {{"));
+    for (matcher, transcriber) in arms {
+        let matcher = print_line(TokenTree::Group(matcher).into(), true);
+        let line = match mode {
+            RulesMode::ArmsOnly => matcher,
+            RulesMode::Folded => {
+                let folded = match transcriber.delimiter() {
+                    Delimiter::Parenthesis => "( … )",
+                    Delimiter::Bracket => "[ … ]",
+                    _ => "{ … }",
+                };
+                format!("{matcher} => {folded};")
+            }
+            RulesMode::Transcribers => {
+                let mut printer = Printer::new(1, true, false);
+                printer.group(&transcriber, "");
+                format!("{matcher} => {};", printer.out)
+            }
+        };
+        lines.push(format!("{INDENT}{line}"));
+    }
+    lines.push("}".to_owned());
+    Some(lines.join("\n"))
+}
+
+#[test]
+fn test_fold_rules() {
+    let tokens = quote! {
+        #[macro_export]
+        macro_rules! my_vec {
+            () => { Vec::new() };
+            ($($x:expr),+ $(,)?) => {{ let mut v = Vec::new(); $(v.push($x);)+ v }};
+            ($($k:ident => [$($v:expr),*]);*) => { $crate::build!($($k, $($v),*);*) }
+        }
+        struct A;
+    };
+    let show = |mode| {
+        replace_rules(&crate::indent_tokens(
+            fold_rules(tokens.clone(), mode),
+            false,
+        ))
+    };
+    assert_eq!(
+        show(RulesMode::Folded),
+        "#[macro_export]
+macro_rules! my_vec {
+    () => { … };
+    ($($x:expr),+ $(,)?) => { … };
+    ($($k:ident => [$($v:expr),*]);*) => { … };
+}
+struct A;"
+    );
+    assert_eq!(
+        show(RulesMode::ArmsOnly),
+        "#[macro_export]
+macro_rules! my_vec {
+    ()
+    ($($x:expr),+ $(,)?)
+    ($($k:ident => [$($v:expr),*]);*)
+}
+struct A;"
+    );
+    assert_eq!(
+        show(RulesMode::Transcribers),
+        "#[macro_export]
+macro_rules! my_vec {
+    () => {
+        Vec::new()
+    };
+    ($($x:expr),+ $(,)?) => {
+        {
+            let mut v = Vec::new();
+            $(v.push($x);)+ v
+        }
+    };
+    ($($k:ident => [$($v:expr),*]);*) => {
+        $crate::build!($($k, $($v),*);*)
+    };
+}
+struct A;"
+    );
+}
+
+/// Replaces the `macro_rules!` definitions at the top level of `tokens` with
+/// markers holding their rendering. Outputs which are not items are returned
+/// unchanged.
+pub(crate) fn fold_rules(tokens: TokenStream, mode: RulesMode) -> TokenStream {
+    let Ok(file) = syn::parse2::<syn::File>(tokens.clone()) else {
+        return tokens;
+    };
+    let rendered = file
+        .items
+        .iter()
+        .map(|item| match item {
+            syn::Item::Macro(item) => render(item, mode),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if rendered.iter().all(Option::is_none) {
+        return tokens;
+    }
+    let mut out = TokenStream::new();
+    for attr in &file.attrs {
+        attr.to_tokens(&mut out);
+    }
+    let marker = syn::Ident::new(RULES_MARKER, proc_macro2::Span::call_site());
+    for (item, rendered) in file.items.iter().zip(rendered) {
+        match rendered {
+            Some(text) => {
+                let text = Literal::string(&text);
+                out.extend(quote!(#marker! { #text }));
+            }
+            None => item.to_tokens(&mut out),
+        }
+    }
+    out
+}
+
+/// Replaces the markers left by [`fold_rules`] in formatted output with the
+/// rendered definitions.
+pub(crate) fn replace_rules(formatted: &str) -> String {
+    crate::replace_markers(formatted, RULES_MARKER, |text| text.to_owned())
+}
@@ -0,0 +1,71 @@
+//! Custom handling of expansions, registered with [`set_sink`].
+
+use crate::MacroRecord;
+use std::sync::{Arc, Mutex};
+
+/// Receives each expansion matching the filters of `PROC_DEBUG_FLAGS`, or
+/// every expansion when it is not set.
+///
+/// Expansions are passed in addition to the usual output, or instead of it
+/// with `--sink-only`.
+pub trait ExpansionSink {
+    /// Called after the expansion of `record`. Calling [`set_sink`] from here
+    /// deadlocks.
+    fn on_expansion(&self, record: &MacroRecord);
+}
+
+type BoxedSink = Box<dyn ExpansionSink + Send + Sync>;
+
+static SINK: Mutex<Option<BoxedSink>> = Mutex::new(None);
+
+/// Registers `sink` for the expansions of this process, replacing the one
+/// registered before.
+pub fn set_sink(sink: BoxedSink) {
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = Some(sink);
+}
+
+/// Unregisters the sink, returning it.
+pub fn take_sink() -> Option<BoxedSink> {
+    SINK.lock().unwrap_or_else(|e| e.into_inner()).take()
+}
+
+pub(crate) fn is_set() -> bool {
+    SINK.lock().unwrap_or_else(|e| e.into_inner()).is_some()
+}
+
+/// Passes `record` to the registered sink, if any.
+pub(crate) fn emit(record: &MacroRecord) {
+    if let Some(sink) = &*SINK.lock().unwrap_or_else(|e| e.into_inner()) {
+        sink.on_expansion(record);
+    }
+}
+
+/// Sink collecting the records, such as for checking the expansions of a
+/// proc-macro crate in its own tests.
+///
+/// ```
+/// let sink = proc_debug::VecSink::default();
+/// proc_debug::set_sink(Box::new(sink.clone()));
+/// // Expand the macros...
+/// for record in sink.records() {
+///     assert!(!record.output.contains("compile_error"));
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct VecSink(Arc<Mutex<Vec<MacroRecord>>>);
+
+impl VecSink {
+    /// Returns the records collected so far.
+    pub fn records(&self) -> Vec<MacroRecord> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl ExpansionSink for VecSink {
+    fn on_expansion(&self, record: &MacroRecord) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(record.clone());
+    }
+}
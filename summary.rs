@@ -0,0 +1,87 @@
+//! Per-macro totals shown by `--summary`.
+
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+struct Totals {
+    count: usize,
+    tokens: usize,
+    time: Duration,
+}
+
+static SUMMARY: Mutex<BTreeMap<String, Totals>> = Mutex::new(BTreeMap::new());
+static LINES: Mutex<usize> = Mutex::new(0);
+
+/// Pads `s` to `width` terminal columns; wide characters take two columns.
+fn pad(s: &str, width: usize) -> String {
+    let w = unicode_width::UnicodeWidthStr::width(s);
+    format!("{s}{}", " ".repeat(width.saturating_sub(w)))
+}
+
+fn render_row(label: &str, totals: &Totals) -> String {
+    format!(
+        "{} {:>7} {:>10} {:>10.3}",
+        pad(label, 40),
+        totals.count,
+        totals.tokens,
+        totals.time.as_secs_f64() * 1000.0
+    )
+}
+
+fn render(summary: &BTreeMap<String, Totals>) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{:<40} {:>7} {:>10} {:>10}",
+        "macro", "count", "tokens", "time (ms)"
+    )];
+    lines.extend(summary.iter().map(|(label, t)| render_row(label, t)));
+    lines
+}
+
+#[test]
+fn test_render() {
+    let mut summary = BTreeMap::new();
+    summary.insert(
+        "a::b".to_owned(),
+        Totals {
+            count: 2,
+            tokens: 30,
+            time: Duration::from_micros(1500),
+        },
+    );
+    assert_eq!(
+        render(&summary)[1],
+        format!("{:<40}       2         30      1.500", "a::b")
+    );
+    assert_eq!(pad("型::名前", 10), "型::名前  ");
+}
+
+/// Adds an expansion to the totals and prints them.
+///
+/// There is no hook running after the last expansion of a rustc process, so
+/// the table is printed after every expansion: on a terminal it is redrawn in
+/// place, otherwise only the updated row is printed.
+pub(crate) fn add(label: &str, tokens: usize, time: Duration) {
+    let mut summary = SUMMARY.lock().unwrap_or_else(|e| e.into_inner());
+    let totals = summary.entry(label.to_owned()).or_default();
+    totals.count += 1;
+    totals.tokens += tokens;
+    totals.time += time;
+    let totals = *totals;
+    let mut stdout = std::io::stdout().lock();
+    if stdout.is_terminal() {
+        let mut lines = LINES.lock().unwrap_or_else(|e| e.into_inner());
+        if *lines > 0 {
+            let _ = write!(stdout, "\x1b[{}A\r", *lines);
+        }
+        let table = render(&summary);
+        *lines = table.len();
+        for line in table {
+            let _ = writeln!(stdout, "{line}\x1b[K");
+        }
+    } else {
+        let _ = writeln!(stdout, "{}", render_row(label, &totals));
+    }
+}